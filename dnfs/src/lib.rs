@@ -0,0 +1,739 @@
+//! dnfs: a tiny filesystem for VM disk images
+//!
+//! layout (all multi-byte fields are big endian, same as the vm):
+//!
+//! block 0                  superblock
+//! blocks 1..               allocation bitmap, 1 bit per block (msb first)
+//! after the bitmap         directory, fixed 32-byte entries
+//! after the directory      file data, each file is one contiguous run of blocks
+//!
+//! blocks are 256 bytes so a block number is just the high byte of an offset
+//! into a 64 KB window, which keeps the kernel side cheap.
+
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"DNFS";
+pub const VERSION: u8 = 1;
+
+pub const BLOCK_SIZE: usize = 256;
+pub const ENTRY_SIZE: usize = 32;
+pub const ENTRIES_PER_BLOCK: usize = BLOCK_SIZE / ENTRY_SIZE;
+pub const NAME_LEN: usize = 16;
+pub const LABEL_LEN: usize = 12;
+pub const MAX_FILE_SIZE: usize = 0xFFFF;
+
+pub const DEFAULT_BLOCKS: u16 = 256; // 64 KB image
+pub const DEFAULT_DIR_BLOCKS: u16 = 4; // 32 entries
+
+// superblock field offsets
+pub const SB_MAGIC: usize = 0;
+pub const SB_VERSION: usize = 4;
+pub const SB_BLOCK_SIZE: usize = 6;
+pub const SB_TOTAL_BLOCKS: usize = 8;
+pub const SB_BITMAP_START: usize = 10;
+pub const SB_BITMAP_BLOCKS: usize = 12;
+pub const SB_DIR_START: usize = 14;
+pub const SB_DIR_BLOCKS: usize = 16;
+pub const SB_DATA_START: usize = 18;
+pub const SB_DIR_ENTRIES: usize = 20;
+pub const SB_LABEL: usize = 22;
+
+// directory entry field offsets
+pub const DE_NAME: usize = 0;
+pub const DE_FLAGS: usize = 16;
+pub const DE_START: usize = 18;
+pub const DE_BLOCKS: usize = 20;
+pub const DE_SIZE: usize = 22;
+pub const DE_LOAD: usize = 24;
+pub const DE_CHECKSUM: usize = 26;
+
+// directory entry flags
+pub const FLAG_USED: u8 = 0b0000_0001;
+pub const FLAG_EXEC: u8 = 0b0000_0010;
+
+#[derive(Debug)]
+pub struct FsError {
+    pub message: String,
+}
+
+impl FsError {
+    fn new(message: impl Into<String>) -> Self {
+        Self { message: message.into() }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+fn get_u16(bytes: &[u8], at: usize) -> u16 {
+    (bytes[at] as u16) << 8 | bytes[at + 1] as u16
+}
+
+fn put_u16(bytes: &mut [u8], at: usize, val: u16) {
+    bytes[at] = (val >> 8) as u8;
+    bytes[at + 1] = val as u8;
+}
+
+// 16-bit fletcher sum; cheap enough for the guest to recompute
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut a: u8 = 0;
+    let mut b: u8 = 0;
+    for byte in data {
+        a = a.wrapping_add(*byte);
+        b = b.wrapping_add(a);
+    }
+    (b as u16) << 8 | a as u16
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Superblock {
+    pub version: u8,
+    pub total_blocks: u16,
+    pub bitmap_start: u16,
+    pub bitmap_blocks: u16,
+    pub dir_start: u16,
+    pub dir_blocks: u16,
+    pub data_start: u16,
+    pub dir_entries: u16,
+    pub label: String,
+}
+
+impl Superblock {
+    pub fn new(total_blocks: u16, dir_blocks: u16, label: &str) -> Result<Self, FsError> {
+        let bitmap_bits = BLOCK_SIZE as u32 * 8;
+        let bitmap_blocks = (total_blocks as u32).div_ceil(bitmap_bits) as u16;
+        let dir_start = 1 + bitmap_blocks;
+        let data_start = dir_start as u32 + dir_blocks as u32;
+
+        if dir_blocks == 0 {
+            return Err(FsError::new("directory needs at least one block"));
+        }
+        if data_start >= total_blocks as u32 {
+            return Err(FsError::new(format!("{} blocks leaves no room for data", total_blocks)));
+        }
+        if label.len() > LABEL_LEN || !label.is_ascii() {
+            return Err(FsError::new(format!("label must be at most {} ascii chars", LABEL_LEN)));
+        }
+        let dir_entries = dir_blocks.checked_mul(ENTRIES_PER_BLOCK as u16)
+            .ok_or_else(|| FsError::new(format!("{} directory blocks is more entries than fit in 16 bits", dir_blocks)))?;
+
+        Ok(Self {
+            version: VERSION,
+            total_blocks,
+            bitmap_start: 1,
+            bitmap_blocks,
+            dir_start,
+            dir_blocks,
+            data_start: data_start as u16,
+            dir_entries,
+            label: label.to_string(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+        block[SB_MAGIC..SB_MAGIC + 4].copy_from_slice(&MAGIC);
+        block[SB_VERSION] = self.version;
+        put_u16(&mut block, SB_BLOCK_SIZE, BLOCK_SIZE as u16);
+        put_u16(&mut block, SB_TOTAL_BLOCKS, self.total_blocks);
+        put_u16(&mut block, SB_BITMAP_START, self.bitmap_start);
+        put_u16(&mut block, SB_BITMAP_BLOCKS, self.bitmap_blocks);
+        put_u16(&mut block, SB_DIR_START, self.dir_start);
+        put_u16(&mut block, SB_DIR_BLOCKS, self.dir_blocks);
+        put_u16(&mut block, SB_DATA_START, self.data_start);
+        put_u16(&mut block, SB_DIR_ENTRIES, self.dir_entries);
+        block[SB_LABEL..SB_LABEL + self.label.len()].copy_from_slice(self.label.as_bytes());
+        block
+    }
+
+    pub fn from_bytes(block: &[u8]) -> Result<Self, FsError> {
+        if block.len() < BLOCK_SIZE {
+            return Err(FsError::new("image is smaller than one block"));
+        }
+        if block[SB_MAGIC..SB_MAGIC + 4] != MAGIC {
+            return Err(FsError::new("bad magic, not a dnfs image"));
+        }
+        if block[SB_VERSION] != VERSION {
+            return Err(FsError::new(format!("unsupported dnfs version {}", block[SB_VERSION])));
+        }
+        if get_u16(block, SB_BLOCK_SIZE) as usize != BLOCK_SIZE {
+            return Err(FsError::new(format!("unsupported block size {}", get_u16(block, SB_BLOCK_SIZE))));
+        }
+
+        let label_bytes = &block[SB_LABEL..SB_LABEL + LABEL_LEN];
+        let label_len = label_bytes.iter().position(|b| *b == 0).unwrap_or(LABEL_LEN);
+
+        Ok(Self {
+            version: block[SB_VERSION],
+            total_blocks: get_u16(block, SB_TOTAL_BLOCKS),
+            bitmap_start: get_u16(block, SB_BITMAP_START),
+            bitmap_blocks: get_u16(block, SB_BITMAP_BLOCKS),
+            dir_start: get_u16(block, SB_DIR_START),
+            dir_blocks: get_u16(block, SB_DIR_BLOCKS),
+            data_start: get_u16(block, SB_DATA_START),
+            dir_entries: get_u16(block, SB_DIR_ENTRIES),
+            label: String::from_utf8_lossy(&label_bytes[..label_len]).to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub flags: u8,
+    pub start: u16,
+    pub blocks: u16,
+    pub size: u16,
+    pub load_addr: u16,
+    pub checksum: u16,
+}
+
+impl DirEntry {
+    pub fn is_used(&self) -> bool {
+        self.flags & FLAG_USED != 0
+    }
+
+    pub fn is_exec(&self) -> bool {
+        self.flags & FLAG_EXEC != 0
+    }
+
+    pub fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut entry = [0; ENTRY_SIZE];
+        entry[DE_NAME..DE_NAME + self.name.len()].copy_from_slice(self.name.as_bytes());
+        entry[DE_FLAGS] = self.flags;
+        put_u16(&mut entry, DE_START, self.start);
+        put_u16(&mut entry, DE_BLOCKS, self.blocks);
+        put_u16(&mut entry, DE_SIZE, self.size);
+        put_u16(&mut entry, DE_LOAD, self.load_addr);
+        put_u16(&mut entry, DE_CHECKSUM, self.checksum);
+        entry
+    }
+
+    pub fn from_bytes(entry: &[u8]) -> Self {
+        let name_bytes = &entry[DE_NAME..DE_NAME + NAME_LEN];
+        let name_len = name_bytes.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+
+        Self {
+            name: String::from_utf8_lossy(&name_bytes[..name_len]).to_string(),
+            flags: entry[DE_FLAGS],
+            start: get_u16(entry, DE_START),
+            blocks: get_u16(entry, DE_BLOCKS),
+            size: get_u16(entry, DE_SIZE),
+            load_addr: get_u16(entry, DE_LOAD),
+            checksum: get_u16(entry, DE_CHECKSUM),
+        }
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name.len() > NAME_LEN {
+        return Err(FsError::new(format!("file name must be 1-{} chars, got {:?}", NAME_LEN, name)));
+    }
+    if !name.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(FsError::new(format!("file name {:?} must be printable ascii without spaces", name)));
+    }
+    Ok(())
+}
+
+pub struct Image {
+    data: Vec<u8>,
+    sb: Superblock,
+}
+
+impl Image {
+    pub fn format(total_blocks: u16, dir_blocks: u16, label: &str) -> Result<Self, FsError> {
+        let sb = Superblock::new(total_blocks, dir_blocks, label)?;
+        let mut image = Self {
+            data: vec![0; total_blocks as usize * BLOCK_SIZE],
+            sb,
+        };
+        image.data[..BLOCK_SIZE].copy_from_slice(&image.sb.to_bytes());
+
+        // metadata blocks are always allocated
+        for block in 0..image.sb.data_start {
+            image.set_allocated(block, true);
+        }
+        Ok(image)
+    }
+
+    pub fn open(data: Vec<u8>) -> Result<Self, FsError> {
+        let sb = Superblock::from_bytes(&data)?;
+        // the layout fields all follow from the block counts, anything else would send
+        // bitmap and directory reads outside the image
+        if Superblock::new(sb.total_blocks, sb.dir_blocks, &sb.label)? != sb {
+            return Err(FsError::new("superblock layout fields don't match the block counts"));
+        }
+        if data.len() < sb.total_blocks as usize * BLOCK_SIZE {
+            return Err(FsError::new(format!(
+                "image is {} bytes but superblock claims {} blocks",
+                data.len(), sb.total_blocks
+            )));
+        }
+        Ok(Self { data, sb })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.sb
+    }
+
+    fn block_offset(block: u16) -> usize {
+        block as usize * BLOCK_SIZE
+    }
+
+    pub fn is_allocated(&self, block: u16) -> bool {
+        let byte = Self::block_offset(self.sb.bitmap_start) + block as usize / 8;
+        self.data[byte] & (0b1000_0000 >> (block % 8)) != 0
+    }
+
+    fn set_allocated(&mut self, block: u16, allocated: bool) {
+        let byte = Self::block_offset(self.sb.bitmap_start) + block as usize / 8;
+        let mask = 0b1000_0000 >> (block % 8);
+        if allocated {
+            self.data[byte] |= mask;
+        }
+        else {
+            self.data[byte] &= !mask;
+        }
+    }
+
+    fn entry_offset(&self, idx: u16) -> usize {
+        Self::block_offset(self.sb.dir_start) + idx as usize * ENTRY_SIZE
+    }
+
+    pub fn entry(&self, idx: u16) -> DirEntry {
+        let at = self.entry_offset(idx);
+        DirEntry::from_bytes(&self.data[at..at + ENTRY_SIZE])
+    }
+
+    fn write_entry(&mut self, idx: u16, entry: &DirEntry) {
+        let at = self.entry_offset(idx);
+        self.data[at..at + ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+    }
+
+    pub fn list(&self) -> Vec<DirEntry> {
+        (0..self.sb.dir_entries)
+            .map(|i| self.entry(i))
+            .filter(|e| e.is_used())
+            .collect()
+    }
+
+    fn find(&self, name: &str) -> Option<u16> {
+        (0..self.sb.dir_entries).find(|i| {
+            let entry = self.entry(*i);
+            entry.is_used() && entry.name == name
+        })
+    }
+
+    // first fit over the bitmap
+    fn allocate(&self, blocks: u16) -> Option<u16> {
+        if blocks == 0 {
+            return Some(0);
+        }
+        let mut run_start = self.sb.data_start;
+        let mut run_len = 0;
+        for block in self.sb.data_start..self.sb.total_blocks {
+            if self.is_allocated(block) {
+                run_start = block + 1;
+                run_len = 0;
+            }
+            else {
+                run_len += 1;
+                if run_len == blocks {
+                    return Some(run_start);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn free_blocks(&self) -> u16 {
+        (self.sb.data_start..self.sb.total_blocks)
+            .filter(|b| !self.is_allocated(*b))
+            .count() as u16
+    }
+
+    pub fn put(&mut self, name: &str, contents: &[u8], load_addr: u16, exec: bool) -> Result<DirEntry, FsError> {
+        validate_name(name)?;
+        if contents.len() > MAX_FILE_SIZE {
+            return Err(FsError::new(format!("{} is {} bytes, limit is {}", name, contents.len(), MAX_FILE_SIZE)));
+        }
+
+        // a file being replaced keeps its slot, and its blocks count as free for the
+        // new copy. it's only gone once the new copy has somewhere to go
+        let old = self.find(name).map(|idx| (idx, self.entry(idx)));
+        let slot = match &old {
+            Some((idx, _)) => *idx,
+            None => (0..self.sb.dir_entries)
+                .find(|i| !self.entry(*i).is_used())
+                .ok_or_else(|| FsError::new("directory is full"))?,
+        };
+        if let Some((_, entry)) = &old {
+            self.mark_file(entry, false);
+        }
+
+        let blocks = contents.len().div_ceil(BLOCK_SIZE) as u16;
+        let Some(start) = self.allocate(blocks) else {
+            if let Some((_, entry)) = &old {
+                self.mark_file(entry, true);
+            }
+            return Err(FsError::new(format!("no run of {} free blocks for {}", blocks, name)));
+        };
+
+        for block in start..start + blocks {
+            self.set_allocated(block, true);
+        }
+        let at = Self::block_offset(start);
+        self.data[at..at + blocks as usize * BLOCK_SIZE].fill(0);
+        self.data[at..at + contents.len()].copy_from_slice(contents);
+
+        let entry = DirEntry {
+            name: name.to_string(),
+            flags: FLAG_USED | if exec { FLAG_EXEC } else { 0 },
+            start,
+            blocks,
+            size: contents.len() as u16,
+            load_addr,
+            checksum: checksum(contents),
+        };
+        self.write_entry(slot, &entry);
+        Ok(entry)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Vec<u8>, FsError> {
+        let idx = self.find(name).ok_or_else(|| FsError::new(format!("no such file {:?}", name)))?;
+        let entry = self.entry(idx);
+        let bytes = self.file_bytes(&entry).map_err(|e| FsError::new(format!("{}: {}", name, e)))?;
+        Ok(self.data[bytes].to_vec())
+    }
+
+    // where an entry's contents are in the image, if the entry makes sense
+    fn file_bytes(&self, entry: &DirEntry) -> Result<std::ops::Range<usize>, FsError> {
+        let needed = (entry.size as usize).div_ceil(BLOCK_SIZE);
+        if entry.blocks as usize != needed {
+            return Err(FsError::new(format!("{} bytes should take {} blocks, entry says {}", entry.size, needed, entry.blocks)));
+        }
+        let end = entry.start as u32 + entry.blocks as u32;
+        if entry.blocks > 0 && (entry.start < self.sb.data_start || end > self.sb.total_blocks as u32) {
+            return Err(FsError::new(format!("blocks {}..{} are outside the data area", entry.start, end)));
+        }
+        let at = Self::block_offset(entry.start);
+        if at + entry.size as usize > self.data.len() {
+            return Err(FsError::new(format!("{} bytes from block {} run past the end of the image", entry.size, entry.start)));
+        }
+        Ok(at..at + entry.size as usize)
+    }
+
+    // clamped to the data area, a corrupt entry can point into the metadata or past the image
+    fn mark_file(&mut self, entry: &DirEntry, allocated: bool) {
+        let start = entry.start.max(self.sb.data_start) as u32;
+        let end = (entry.start as u32 + entry.blocks as u32).min(self.sb.total_blocks as u32);
+        for block in start..end {
+            self.set_allocated(block as u16, allocated);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), FsError> {
+        let idx = self.find(name).ok_or_else(|| FsError::new(format!("no such file {:?}", name)))?;
+        let entry = self.entry(idx);
+        self.mark_file(&entry, false);
+        self.write_entry(idx, &DirEntry::from_bytes(&[0; ENTRY_SIZE]));
+        Ok(())
+    }
+
+    // returns a list of problems; empty means the image is consistent
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        // open already checked the superblock layout
+        let sb = &self.sb;
+
+        let mut owner: Vec<Option<String>> = vec![None; sb.total_blocks as usize];
+        for block in 0..sb.data_start {
+            owner[block as usize] = Some("<metadata>".to_string());
+            if !self.is_allocated(block) {
+                problems.push(format!("block {} is metadata but not marked allocated", block));
+            }
+        }
+
+        let mut names: Vec<String> = vec![];
+        for idx in 0..sb.dir_entries {
+            let entry = self.entry(idx);
+            if !entry.is_used() {
+                continue;
+            }
+            let who = format!("entry {} ({:?})", idx, entry.name);
+
+            if let Err(e) = validate_name(&entry.name) {
+                problems.push(format!("{}: {}", who, e));
+            }
+            if names.contains(&entry.name) {
+                problems.push(format!("{}: duplicate name", who));
+            }
+            names.push(entry.name.clone());
+
+            // a size, block count or start that doesn't add up can't be followed any further
+            let bytes = match self.file_bytes(&entry) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    problems.push(format!("{}: {}", who, e));
+                    continue;
+                },
+            };
+            if entry.blocks == 0 {
+                continue;
+            }
+
+            let end = entry.start as u32 + entry.blocks as u32;
+
+            for block in entry.start..end as u16 {
+                if let Some(other) = &owner[block as usize] {
+                    problems.push(format!("{}: block {} is also used by {}", who, block, other));
+                }
+                owner[block as usize] = Some(who.clone());
+                if !self.is_allocated(block) {
+                    problems.push(format!("{}: block {} is not marked allocated", who, block));
+                }
+            }
+
+            if checksum(&self.data[bytes]) != entry.checksum {
+                problems.push(format!("{}: checksum mismatch", who));
+            }
+        }
+
+        for block in sb.data_start..sb.total_blocks {
+            if self.is_allocated(block) && owner[block as usize].is_none() {
+                problems.push(format!("block {} is marked allocated but no file owns it", block));
+            }
+        }
+
+        problems
+    }
+}
+
+// .const only takes bytes, so 16-bit values are split into _hi/_lo pairs
+pub fn dnasm_constants() -> String {
+    let mut out = String::new();
+    out.push_str("; generated by `dnfs consts`, do not edit by hand\n");
+    out.push_str("; mirrors the on-disk layout in dnfs/src/lib.rs\n\n");
+
+    let wide: [(&str, u16); 3] = [
+        ("dnfs_block_size", BLOCK_SIZE as u16),
+        ("dnfs_default_blocks", DEFAULT_BLOCKS),
+        ("dnfs_max_file_size", MAX_FILE_SIZE as u16),
+    ];
+    for (name, val) in wide {
+        out.push_str(&format!(".const \"{}_hi\", 0x{:02X}\n", name, val >> 8));
+        out.push_str(&format!(".const \"{}_lo\", 0x{:02X}\n", name, val & 0xFF));
+    }
+    out.push('\n');
+
+    let bytes: [(&str, usize); 26] = [
+        ("dnfs_version", VERSION as usize),
+        ("dnfs_entry_size", ENTRY_SIZE),
+        ("dnfs_entries_per_block", ENTRIES_PER_BLOCK),
+        ("dnfs_name_len", NAME_LEN),
+        ("dnfs_label_len", LABEL_LEN),
+        ("dnfs_magic_0", MAGIC[0] as usize),
+        ("dnfs_magic_1", MAGIC[1] as usize),
+        ("dnfs_magic_2", MAGIC[2] as usize),
+        ("dnfs_magic_3", MAGIC[3] as usize),
+        ("dnfs_sb_version", SB_VERSION),
+        ("dnfs_sb_block_size", SB_BLOCK_SIZE),
+        ("dnfs_sb_total_blocks", SB_TOTAL_BLOCKS),
+        ("dnfs_sb_bitmap_start", SB_BITMAP_START),
+        ("dnfs_sb_bitmap_blocks", SB_BITMAP_BLOCKS),
+        ("dnfs_sb_dir_start", SB_DIR_START),
+        ("dnfs_sb_dir_blocks", SB_DIR_BLOCKS),
+        ("dnfs_sb_data_start", SB_DATA_START),
+        ("dnfs_sb_dir_entries", SB_DIR_ENTRIES),
+        ("dnfs_sb_label", SB_LABEL),
+        ("dnfs_de_name", DE_NAME),
+        ("dnfs_de_flags", DE_FLAGS),
+        ("dnfs_de_start", DE_START),
+        ("dnfs_de_blocks", DE_BLOCKS),
+        ("dnfs_de_size", DE_SIZE),
+        ("dnfs_de_load", DE_LOAD),
+        ("dnfs_de_checksum", DE_CHECKSUM),
+    ];
+    for (name, val) in bytes {
+        out.push_str(&format!(".const \"{}\", {}\n", name, val));
+    }
+    out.push('\n');
+
+    out.push_str(&format!(".const \"dnfs_flag_used\", 0b{:08b}\n", FLAG_USED));
+    out.push_str(&format!(".const \"dnfs_flag_exec\", 0b{:08b}\n", FLAG_EXEC));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_with(files: &[(&str, &[u8])]) -> Image {
+        let mut img = Image::format(16, 1, "TEST").unwrap();
+        for (name, contents) in files {
+            img.put(name, contents, 0x1000, false).unwrap();
+        }
+        img
+    }
+
+    // overwrite one directory entry in place, the way a bad write would
+    fn corrupt(img: &Image, idx: u16, edit: impl Fn(&mut DirEntry)) -> Image {
+        let mut entry = img.entry(idx);
+        edit(&mut entry);
+        let mut data = img.bytes().to_vec();
+        let at = img.entry_offset(idx);
+        data[at..at + ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        Image::open(data).unwrap()
+    }
+
+    #[test]
+    fn format_is_consistent() {
+        let img = Image::format(DEFAULT_BLOCKS, DEFAULT_DIR_BLOCKS, "DISK").unwrap();
+        assert_eq!(img.bytes().len(), DEFAULT_BLOCKS as usize * BLOCK_SIZE);
+        assert_eq!(img.superblock().dir_entries, DEFAULT_DIR_BLOCKS * ENTRIES_PER_BLOCK as u16);
+        assert!(img.check().is_empty());
+        assert!(img.list().is_empty());
+    }
+
+    #[test]
+    fn format_rejects_bad_layouts() {
+        assert!(Image::format(16, 0, "").is_err());
+        assert!(Image::format(4, 4, "").is_err());
+        assert!(Image::format(16, 1, "A LABEL TOO LONG").is_err());
+        // 10000 directory blocks is 80000 entries
+        assert!(Image::format(65535, 10000, "").is_err());
+    }
+
+    #[test]
+    fn put_get_round_trip() {
+        let big: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let img = image_with(&[("a.bin", b"hello"), ("big.bin", &big), ("empty", b"")]);
+        assert_eq!(img.get("a.bin").unwrap(), b"hello");
+        assert_eq!(img.get("big.bin").unwrap(), big);
+        assert_eq!(img.get("empty").unwrap(), b"");
+        assert!(img.get("missing").is_err());
+        assert!(img.check().is_empty());
+
+        let reopened = Image::open(img.bytes().to_vec()).unwrap();
+        assert_eq!(reopened.list(), img.list());
+    }
+
+    #[test]
+    fn put_replaces_and_remove_frees() {
+        let mut img = image_with(&[("a.bin", &[1; 300])]);
+        let free = img.free_blocks();
+        img.put("a.bin", &[2; 10], 0x2000, true).unwrap();
+        assert_eq!(img.get("a.bin").unwrap(), [2; 10]);
+        assert_eq!(img.list().len(), 1);
+        assert_eq!(img.free_blocks(), free + 1);
+        img.remove("a.bin").unwrap();
+        assert!(img.list().is_empty());
+        assert!(img.check().is_empty());
+    }
+
+    #[test]
+    fn replace_on_a_full_disk_keeps_the_original() {
+        let mut img = image_with(&[("a.bin", &[1; 256])]);
+        let room = img.free_blocks() as usize * BLOCK_SIZE;
+        img.put("fill.bin", &vec![0; room], 0, false).unwrap();
+        assert_eq!(img.free_blocks(), 0);
+
+        // only a.bin's own block is free for it, two won't fit
+        assert!(img.put("a.bin", &[3; 512], 0, false).is_err());
+        assert_eq!(img.get("a.bin").unwrap(), [1; 256]);
+        assert!(img.check().is_empty());
+
+        // but the same size fits in place
+        img.put("a.bin", &[4; 256], 0, false).unwrap();
+        assert_eq!(img.get("a.bin").unwrap(), [4; 256]);
+        assert!(img.check().is_empty());
+    }
+
+    #[test]
+    fn check_finds_bad_checksums() {
+        let img = image_with(&[("a.bin", b"hello")]);
+        let mut data = img.bytes().to_vec();
+        data[Image::block_offset(img.entry(0).start)] ^= 0xFF;
+        let img = Image::open(data).unwrap();
+        assert_eq!(img.check().len(), 1);
+    }
+
+    #[test]
+    fn corrupt_sizes_are_reported_not_followed() {
+        let img = image_with(&[("a.bin", b"hello")]);
+        let last = img.superblock().total_blocks - 1;
+
+        // size says 256 blocks, entry says 1, and it starts at the last block
+        let bad = corrupt(&img, 0, |e| {
+            e.size = 0xFFFF;
+            e.blocks = 1;
+            e.start = last;
+        });
+        assert_eq!(bad.check().len(), 2); // the entry, and a.bin's block now belongs to nothing
+        assert!(bad.get("a.bin").is_err());
+
+        // sizes agree but run off the end of the image
+        let bad = corrupt(&img, 0, |e| {
+            e.size = 0xFFFF;
+            e.blocks = 256;
+            e.start = last;
+        });
+        assert!(!bad.check().is_empty());
+        assert!(bad.get("a.bin").is_err());
+
+        // in the metadata
+        let bad = corrupt(&img, 0, |e| e.start = 0);
+        assert!(!bad.check().is_empty());
+        assert!(bad.get("a.bin").is_err());
+    }
+
+    #[test]
+    fn removing_a_corrupt_entry_leaves_the_metadata_allocated() {
+        let img = image_with(&[("a.bin", b"hello")]);
+        let data_start = img.superblock().data_start;
+        // starts in the superblock and runs over the whole of the metadata
+        let mut bad = corrupt(&img, 0, |e| {
+            e.start = 0;
+            e.blocks = data_start + 1;
+        });
+        bad.remove("a.bin").unwrap();
+        for block in 0..data_start {
+            assert!(bad.is_allocated(block), "block {} was freed", block);
+        }
+    }
+
+    #[test]
+    fn open_rejects_bad_layouts() {
+        let img = image_with(&[("a.bin", b"hello")]);
+        let edit = |at: usize, val: u16| {
+            let mut data = img.bytes().to_vec();
+            put_u16(&mut data, at, val);
+            Image::open(data)
+        };
+        assert!(edit(SB_DIR_ENTRIES, 0xFFFF).is_err());
+        assert!(edit(SB_BITMAP_START, 0x40).is_err());
+        assert!(edit(SB_DIR_START, 0xFFFF).is_err());
+        assert!(edit(SB_DIR_BLOCKS, 0).is_err());
+        assert!(edit(SB_DATA_START, 1).is_err());
+        assert!(edit(SB_TOTAL_BLOCKS, 0xFFFF).is_err());
+    }
+
+    #[test]
+    fn corrupt_entries_can_still_be_replaced() {
+        let img = image_with(&[("a.bin", b"hello")]);
+        let mut bad = corrupt(&img, 0, |e| {
+            e.size = 0xFFFF;
+            e.blocks = 0xFFFF;
+            e.start = 0xFFFF;
+        });
+        bad.put("a.bin", b"again", 0, false).unwrap();
+        assert_eq!(bad.get("a.bin").unwrap(), b"again");
+    }
+}
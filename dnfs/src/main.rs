@@ -0,0 +1,181 @@
+//! dnfs command line tool
+//!
+//! dnfs format <image> [--blocks N] [--dir-blocks N] [--label L]
+//! dnfs put    <image> <host file> [--name N] [--load ADDR] [--exec]
+//! dnfs get    <image> <name> <host file>
+//! dnfs rm     <image> <name>
+//! dnfs ls     <image>
+//! dnfs check  <image>
+//! dnfs consts [out.dnasm]
+
+use std::{env, fs, path::Path, process::ExitCode};
+
+use dnfs::{DEFAULT_BLOCKS, DEFAULT_DIR_BLOCKS, FsError, Image};
+
+const USAGE: &str = "usage:
+  dnfs format <image> [--blocks N] [--dir-blocks N] [--label L]
+  dnfs put    <image> <host file> [--name N] [--load ADDR] [--exec]
+  dnfs get    <image> <name> <host file>
+  dnfs rm     <image> <name>
+  dnfs ls     <image>
+  dnfs check  <image>
+  dnfs consts [out.dnasm]";
+
+fn parse_num(s: &str) -> Result<u16, FsError> {
+    let parsed = if let Some(hex) = s.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    }
+    else {
+        s.parse::<u16>()
+    };
+    parsed.map_err(|e| FsError { message: format!("bad number {:?}: {}", s, e) })
+}
+
+// splits `--flag value` pairs out of the positional args
+fn take_opt(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let idx = args.iter().position(|a| a == flag)?;
+    if idx + 1 >= args.len() {
+        return None;
+    }
+    let val = args.remove(idx + 1);
+    args.remove(idx);
+    Some(val)
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|a| a == flag) {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        },
+        None => false,
+    }
+}
+
+fn load(path: &str) -> Result<Image, FsError> {
+    let data = fs::read(path).map_err(|e| FsError { message: format!("couldn't read {}: {}", path, e) })?;
+    Image::open(data)
+}
+
+fn save(path: &str, image: &Image) -> Result<(), FsError> {
+    fs::write(path, image.bytes()).map_err(|e| FsError { message: format!("couldn't write {}: {}", path, e) })
+}
+
+fn positional(args: &[String], idx: usize, what: &str) -> Result<String, FsError> {
+    args.get(idx).cloned().ok_or_else(|| FsError { message: format!("missing {}\n{}", what, USAGE) })
+}
+
+fn run(mut args: Vec<String>) -> Result<(), FsError> {
+    if args.is_empty() {
+        return Err(FsError { message: USAGE.to_string() });
+    }
+    let command = args.remove(0);
+
+    match command.as_str() {
+        "format" => {
+            let blocks = match take_opt(&mut args, "--blocks") {
+                Some(b) => parse_num(&b)?,
+                None => DEFAULT_BLOCKS,
+            };
+            let dir_blocks = match take_opt(&mut args, "--dir-blocks") {
+                Some(b) => parse_num(&b)?,
+                None => DEFAULT_DIR_BLOCKS,
+            };
+            let label = take_opt(&mut args, "--label").unwrap_or_default();
+            let path = positional(&args, 0, "image path")?;
+
+            let image = Image::format(blocks, dir_blocks, &label)?;
+            save(&path, &image)?;
+            let sb = image.superblock();
+            println!("formatted {}: {} blocks, {} directory entries, {} data blocks",
+                path, sb.total_blocks, sb.dir_entries, sb.total_blocks - sb.data_start);
+        },
+        "put" => {
+            let name = take_opt(&mut args, "--name");
+            let load_addr = match take_opt(&mut args, "--load") {
+                Some(a) => parse_num(&a)?,
+                None => 0,
+            };
+            let exec = take_flag(&mut args, "--exec");
+            let path = positional(&args, 0, "image path")?;
+            let host = positional(&args, 1, "host file")?;
+
+            // default to the host file name, e.g. build/kernel.bin -> kernel.bin
+            let name = match name {
+                Some(n) => n,
+                None => Path::new(&host)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or(host.clone()),
+            };
+
+            let contents = fs::read(&host).map_err(|e| FsError { message: format!("couldn't read {}: {}", host, e) })?;
+            let mut image = load(&path)?;
+            let entry = image.put(&name, &contents, load_addr, exec)?;
+            save(&path, &image)?;
+            println!("{}: {} bytes in blocks {}..{}", entry.name, entry.size, entry.start, entry.start + entry.blocks);
+        },
+        "get" => {
+            let path = positional(&args, 0, "image path")?;
+            let name = positional(&args, 1, "file name")?;
+            let host = positional(&args, 2, "host file")?;
+
+            let contents = load(&path)?.get(&name)?;
+            fs::write(&host, contents).map_err(|e| FsError { message: format!("couldn't write {}: {}", host, e) })?;
+        },
+        "rm" => {
+            let path = positional(&args, 0, "image path")?;
+            let name = positional(&args, 1, "file name")?;
+
+            let mut image = load(&path)?;
+            image.remove(&name)?;
+            save(&path, &image)?;
+        },
+        "ls" => {
+            let path = positional(&args, 0, "image path")?;
+            let image = load(&path)?;
+            let sb = image.superblock();
+
+            println!("{} ({:?}): {} of {} data blocks free",
+                path, sb.label, image.free_blocks(), sb.total_blocks - sb.data_start);
+            for entry in image.list() {
+                println!("{:<16} {:>5} bytes  blocks {:>4}..{:<4}  load 0x{:04X}{}",
+                    entry.name, entry.size, entry.start, entry.start + entry.blocks, entry.load_addr,
+                    if entry.is_exec() { "  exec" } else { "" });
+            }
+        },
+        "check" => {
+            let path = positional(&args, 0, "image path")?;
+            let problems = load(&path)?.check();
+            if problems.is_empty() {
+                println!("{}: ok", path);
+            }
+            else {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                return Err(FsError { message: format!("{}: {} problem(s)", path, problems.len()) });
+            }
+        },
+        "consts" => {
+            let consts = dnfs::dnasm_constants();
+            match args.first() {
+                Some(out) => fs::write(out, consts).map_err(|e| FsError { message: format!("couldn't write {}: {}", out, e) })?,
+                None => print!("{}", consts),
+            }
+        },
+        _ => return Err(FsError { message: format!("unknown command {:?}\n{}", command, USAGE) }),
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -1,8 +1,10 @@
 use std::num::ParseIntError;
-use std::{fs, string};
+use std::fs;
+use std::ops::Range;
 use std::collections::HashMap;
 
 use crate::binary::{get_bits_lsb, get_bits_msb};
+use crate::charset;
 
 
 /*
@@ -60,6 +62,8 @@ pub enum Token {
 pub struct Lexer {
     src: Vec<char>,
     pos: usize,
+    line: usize, // 1-based line of pos
+    token_line: usize, // line the last token started on
 }
 
 impl Lexer {
@@ -67,10 +71,15 @@ impl Lexer {
         Self {
             src: input.chars().collect(),
             pos: 0,
+            line: 1,
+            token_line: 1,
         }
     }
 
     fn advance(&mut self) {
+        if self.peek() == Some('\n') {
+            self.line += 1;
+        }
         self.pos += 1;
     }
 
@@ -266,6 +275,7 @@ impl Lexer {
     pub fn next_token(&mut self) -> Result<Token,LexerError> {
 
         // self.skip_whitespace();
+        self.token_line = self.line;
 
         if self.is_eof() {
             return Ok(Token::EOF);
@@ -344,9 +354,12 @@ impl Lexer {
 
     pub fn peek_next_token(&mut self) -> Result<Token, LexerError> {
         let current_pos: usize = self.pos;
+        let (line, token_line) = (self.line, self.token_line);
         let token= self.next_token();
         
         self.pos = current_pos;
+        self.line = line;
+        self.token_line = token_line;
         return token;
 
     }
@@ -483,8 +496,8 @@ pub enum Stmt {
 
 #[derive(Debug, PartialEq)]
 pub struct ParserError {
-    message: String,
-    filename: String,
+    pub message: String,
+    pub filename: String,
 }
 
 #[allow(dead_code)]
@@ -496,11 +509,14 @@ pub struct Parser {
     double_modes: HashMap<String, DoubleMode>,
     ops: HashMap<String, (OpKind, OperandLength)>,
     filename: String,
+    current_line: usize, // source line current_token is on
+    lines: Vec<usize>, // source line of every stmt parse returned
 }
 
 impl Parser {
     pub fn new(mut passed_lex: Lexer, filename: String) -> Self {
         let first = passed_lex.next_token().unwrap();
+        let first_line = passed_lex.token_line;
         let mut single_modes: HashMap<char, SingleMode> = HashMap::new();
         single_modes.insert('r', SingleMode::R);
         single_modes.insert('m', SingleMode::M);
@@ -591,6 +607,8 @@ impl Parser {
             double_modes,
             ops,
             filename,
+            current_line: first_line,
+            lines: Vec::new(),
         }
     }
 
@@ -843,6 +861,7 @@ impl Parser {
                 message: format!("LexerError {:?}", e), filename: self.filename.clone()
             })?;
         self.current_token = tok;
+        self.current_line = self.parser_lexer.token_line;
         Ok(())
     }
 
@@ -956,14 +975,21 @@ impl Parser {
         let mut program: Vec<Stmt> = vec![];
 
         loop {
+            let line = self.current_line;
             let stmt = self.next_stmt()?;
             if stmt == Stmt::End {
                 break;
             };
             program.push(stmt);
+            self.lines.push(line);
         }
         Ok(program)
     }
+
+    // source line each stmt from parse started on, for the assembler's line map
+    pub fn lines(&self) -> Vec<usize> {
+        self.lines.clone()
+    }
 }
 
 
@@ -980,6 +1006,126 @@ pub struct AssemblerError {
     pub message: String,
 }
 
+const LISTING_BYTES_PER_ROW: usize = 4;
+
+// what assemble hands back: the bytes, and what debugging tools need to make sense of them
+#[derive(Debug)]
+pub struct Assembled {
+    pub segments: HashMap<u16, Vec<u8>>, // start address -> bytes
+    pub labels: HashMap<String, u16>,
+    pub consts: HashMap<String, u8>,
+    pub lines: Vec<SourceLine>, // every line that produced bytes, in source order
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceLine {
+    pub line: usize,
+    pub addr: u16,
+    pub len: u16,
+}
+
+impl Assembled {
+    /*
+     * sidecar symbol file for other tools, one entry per line, sorted by address:
+     *
+     * label <name> <addr>
+     * const <name> <value>
+     * line <source line> <addr> <byte count>
+     */
+    pub fn write_symbols(&self, path: &str) -> Result<(), AssemblerError> {
+        let mut labels: Vec<(&String, &u16)> = self.labels.iter().collect();
+        labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+        let mut consts: Vec<(&String, &u8)> = self.consts.iter().collect();
+        consts.sort();
+
+        let mut out = String::new();
+        for (name, addr) in labels {
+            out.push_str(&format!("label {} 0x{:04X}\n", name, addr));
+        }
+        for (name, value) in consts {
+            out.push_str(&format!("const {} 0x{:02X}\n", name, value));
+        }
+        for line in &self.lines {
+            out.push_str(&format!("line {} 0x{:04X} {}\n", line.line, line.addr, line.len));
+        }
+
+        fs::write(path, out).map_err(|e| AssemblerError { message: format!("couldn't write {}: {}", path, e) })
+    }
+
+    /*
+     * classic listing: every source line with the address and bytes it assembled to
+     * (long runs like .str wrap onto extra rows), then each segment with how much
+     * of its memory region it fills, then the labels and consts.
+     * regions are (name, range) of the machine's memory map
+     */
+    pub fn write_listing(&self, path: &str, source: &str, regions: &[(String, Range<u32>)]) -> Result<(), AssemblerError> {
+        let mut out = String::new();
+        out.push_str(" line  addr  bytes         source\n");
+
+        let mut emitted = self.lines.iter().peekable();
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            let mut text = Some(text.trim_end());
+            // several stmts can share a line ("!" separates them), each gets rows of its own
+            while let Some(stmt) = emitted.next_if(|l| l.line <= line) {
+                let bytes = self.bytes_at(stmt.addr, stmt.len);
+                for (row, chunk) in bytes.chunks(LISTING_BYTES_PER_ROW).enumerate() {
+                    let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+                    let addr = stmt.addr.wrapping_add((row * LISTING_BYTES_PER_ROW) as u16);
+                    out.push_str(&format!("{:5}  {:04X}  {:<12}  {}\n", line, addr, hex.join(" "), text.take().unwrap_or("")));
+                }
+            }
+            if let Some(text) = text {
+                out.push_str(&format!("{:5}  {:4}  {:<12}  {}\n", line, "", "", text));
+            }
+        }
+
+        out.push_str("\nsegments\n");
+        let mut segments: Vec<(&u16, &Vec<u8>)> = self.segments.iter().collect();
+        segments.sort();
+        for (start, bytes) in segments {
+            let end = *start as u32 + bytes.len() as u32;
+            out.push_str(&format!("  {:04X}-{:04X}  {:5} bytes", start, end.saturating_sub(1), bytes.len()));
+            match regions.iter().find(|(_, r)| r.contains(&(*start as u32))) {
+                Some((name, r)) => {
+                    let size = r.end - r.start;
+                    let used = end.min(r.end) - *start as u32;
+                    out.push_str(&format!("  in {} {:04X}-{:04X}, {} of {} bytes ({}%)", name, r.start, r.end - 1, used, size, used * 100 / size));
+                    if end > r.end {
+                        out.push_str(&format!(", {} bytes past its end", end - r.end));
+                    }
+                    out.push('\n');
+                },
+                None => out.push_str("  outside the memory map\n"),
+            }
+        }
+
+        out.push_str("\nlabels\n");
+        let mut labels: Vec<(&String, &u16)> = self.labels.iter().collect();
+        labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+        for (name, addr) in labels {
+            out.push_str(&format!("  {:04X}  {}\n", addr, name));
+        }
+
+        out.push_str("\nconsts\n");
+        let mut consts: Vec<(&String, &u8)> = self.consts.iter().collect();
+        consts.sort();
+        for (name, value) in consts {
+            out.push_str(&format!("  {:02X}    {}\n", value, name));
+        }
+
+        fs::write(path, out).map_err(|e| AssemblerError { message: format!("couldn't write {}: {}", path, e) })
+    }
+
+    // len bytes assembled at addr
+    fn bytes_at(&self, addr: u16, len: u16) -> Vec<u8> {
+        self.segments.iter()
+            .find(|(start, bytes)| addr >= **start && (addr - **start) as usize + len as usize <= bytes.len())
+            .map(|(start, bytes)| bytes[(addr - start) as usize..(addr - start) as usize + len as usize].to_vec())
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Assembler {
     pc: u16,
@@ -989,42 +1135,16 @@ pub struct Assembler {
     consts: HashMap<String, u8>,
     symbols: HashMap<char, u8>,
     program: Vec<Stmt>,
+    stmt_lines: Vec<usize>, // source line of each stmt in program
+    lines: Vec<SourceLine>,
     mode: AssembleMode,
 
     start: u16, // given to assembler to know where the program starts so .rel (jumps to location in mem relative to start) will work
 }
 
 impl Assembler {
-    pub fn new(program: Vec<Stmt>, start: Option<u16>) -> Self {
-        let symbols: HashMap<char, u8> = HashMap::from([
-            (' ', 0),
-            ('a', 1),
-            ('b', 2),
-            ('c', 3),
-            ('d', 4),
-            ('e', 5),
-            ('f', 6),
-            ('g', 7),
-            ('h', 8),
-            ('i', 9),
-            ('j', 10),
-            ('k', 11),
-            ('l', 12),
-            ('m', 13),
-            ('n', 14),
-            ('o', 15),
-            ('p', 16),
-            ('q', 17),
-            ('r', 18),
-            ('s', 19),
-            ('t', 20),
-            ('u', 21),
-            ('v', 22),
-            ('w', 23),
-            ('x', 24),
-            ('y', 25),
-            ('z', 26),
-        ]);
+    pub fn new(program: Vec<Stmt>, stmt_lines: Vec<usize>, start: Option<u16>) -> Self {
+        let symbols: HashMap<char, u8> = charset::symbols();
         
         Self {
             pc: 0_u16,
@@ -1034,6 +1154,8 @@ impl Assembler {
             consts: HashMap::new(),
             symbols,
             program,
+            stmt_lines,
+            lines: vec![],
             mode: AssembleMode::CountBytes,
             start: match start {
                 Some(s) => s,
@@ -1728,7 +1850,7 @@ impl Assembler {
 
 
 
-        for stmt in program {
+        for (i, stmt) in program.into_iter().enumerate() {
             // println!("Stmt: {:?}", stmt);
             let next_instructions: Vec<u8> = match stmt {
                 Stmt::DoubleOperation { opid, mode, dest, src, operand_length } => self.assemble_double_op(Stmt::DoubleOperation { opid, mode, dest, src, operand_length })?,
@@ -1742,11 +1864,10 @@ impl Assembler {
                 _ => return Err(AssemblerError { message: "Unexpected stmt".to_string() }),
             };
             if !next_instructions.is_empty() {
-                // println!("Next instructions:");
-                for inst in next_instructions.clone() {
-                    // print!("(0x{:x}): 0b{:08b},\n", self.current_pos, inst);
+                if self.mode == AssembleMode::Assemble {
+                    let line = self.stmt_lines.get(i).copied().unwrap_or(0);
+                    self.lines.push(SourceLine { line, addr: self.pc, len: next_instructions.len() as u16 });
                 }
-                // print!("\n");
                 self.inc_pc(next_instructions.len() as u16);
                 if self.mode == AssembleMode::Assemble {
                     byte_segments.entry(self.current_pos).or_insert_with(||Vec::new()).extend(next_instructions);
@@ -1758,25 +1879,13 @@ impl Assembler {
             }
         }
 
-        print!("\nLabels:");
-        for (labels, labelu) in self.labels.clone() {
-            print!("\n[\"{}\": 0x{:0x}]", labels, labelu);
-        }
-        print!("\n");
-        print!("\nConsts:");
-        for (labels, labelu) in self.consts.clone() {
-            print!("\n[\"{}\": 0b{:08b}]", labels, labelu);
-        }
-        print!("\n");
-        
-
         return Ok(match self.mode {
             AssembleMode::CountBytes => None,
             AssembleMode::Assemble => Some(byte_segments),
         });
     }
 
-    pub fn assemble(&mut self) -> Result<HashMap<u16, Vec<u8>>, AssemblerError> {
+    pub fn assemble(&mut self) -> Result<Assembled, AssemblerError> {
         // save current state
         let orig_pc = self.pc;
         let orig_pos = self.current_pos;
@@ -1794,11 +1903,18 @@ impl Assembler {
         self.pc = orig_pc;
         self.current_pos = orig_pos;
         self.labels = lbls;
+        self.lines.clear();
 
-        return Ok(match self.walk()? {
+        let segments = match self.walk()? {
             Some(s) => s,
             None => return Err(AssemblerError { message: "Assembling returned none".to_string() })
-        });
+        };
+        Ok(Assembled {
+            segments,
+            labels: self.labels.clone(),
+            consts: self.consts.clone(),
+            lines: self.lines.clone(),
+        })
     }
 }
 
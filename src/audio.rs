@@ -0,0 +1,314 @@
+use std::{fs, io};
+
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+use crate::vm::CLOCK_HZ;
+
+
+/*
+ * psg-style tone generator, mapped at mmio + AUDIO_OFFSET
+ *
+ * 4 channels, 4 registers each, channel n starts at n * 4:
+ * 0x0: frequency high byte (Hz)
+ * 0x1: frequency low byte
+ * 0x2: volume (0-15)
+ * 0x3: control
+ *      bit 0: enable
+ *      bit 1: short noise (noise channel only, gives a buzzier periodic noise)
+ * channels 0-2 are square waves, channel 3 is noise
+ *
+ * 0x10: master volume (0-15, starts at 15)
+ */
+
+pub const AUDIO_OFFSET: u16 = 0x20;
+pub const AUDIO_SIZE: u16 = 0x20;
+
+pub const SAMPLE_RATE: u32 = 22_050;
+
+const CHANNELS: usize = 4;
+const NOISE_CHANNEL: usize = 3;
+const MASTER_VOLUME: u16 = 0x10;
+
+const CTRL_ENABLE: u8 = 0b0000_0001;
+const CTRL_SHORT_NOISE: u8 = 0b0000_0010;
+
+// loudest a single channel gets, 4 of these still fit in an i16
+const CHANNEL_AMPLITUDE: i32 = 6000;
+
+struct Channel {
+    freq: u16,
+    volume: u8,
+    control: u8,
+    phase: u32,
+    lfsr: u16, // noise channel only
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            freq: 0,
+            volume: 0,
+            control: 0,
+            phase: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    // how far the phase moves per output sample, 2^32 is a full period
+    fn phase_step(&self) -> u32 {
+        ((self.freq as u64) << 32).wrapping_div(SAMPLE_RATE as u64) as u32
+    }
+
+    // -1 or 1, advances the channel by one sample
+    fn square(&mut self) -> i32 {
+        self.phase = self.phase.wrapping_add(self.phase_step());
+        if self.phase < 0x8000_0000 { 1 } else { -1 }
+    }
+
+    fn noise(&mut self) -> i32 {
+        let (phase, wrapped) = self.phase.overflowing_add(self.phase_step());
+        self.phase = phase;
+        if wrapped {
+            // 15-bit lfsr, short mode also feeds bit 6 so it repeats every 127 clocks
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.control & CTRL_SHORT_NOISE != 0 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+        if self.lfsr & 1 == 0 { 1 } else { -1 }
+    }
+}
+
+pub struct Audio {
+    channels: [Channel; CHANNELS],
+    master: u8,
+    samples_made: u64,
+    buffer: Vec<i16>, // mono samples at SAMPLE_RATE, waiting for drain()
+    clock_hz: u64,
+}
+
+impl Audio {
+    pub fn new() -> Self {
+        Self {
+            channels: [Channel::new(), Channel::new(), Channel::new(), Channel::new()],
+            master: 15,
+            samples_made: 0,
+            buffer: Vec::new(),
+            clock_hz: CLOCK_HZ,
+        }
+    }
+
+    pub fn set_clock_hz(&mut self, hz: u64) {
+        self.clock_hz = hz;
+    }
+
+    pub fn read(&self, offset: u16) -> u8 {
+        if offset == MASTER_VOLUME {
+            return self.master;
+        }
+        match self.channels.get(offset as usize / 4) {
+            Some(channel) => match offset % 4 {
+                0 => (channel.freq >> 8) as u8,
+                1 => channel.freq as u8,
+                2 => channel.volume,
+                _ => channel.control,
+            },
+            None => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u16, val: u8) {
+        if offset == MASTER_VOLUME {
+            self.master = val & 0xF;
+            return;
+        }
+        if let Some(channel) = self.channels.get_mut(offset as usize / 4) {
+            match offset % 4 {
+                0 => channel.freq = (channel.freq & 0x00FF) | ((val as u16) << 8),
+                1 => channel.freq = (channel.freq & 0xFF00) | val as u16,
+                2 => channel.volume = val & 0xF,
+                _ => channel.control = val,
+            }
+        }
+    }
+
+    fn sample(&mut self) -> i16 {
+        let mut mix = 0;
+        for (idx, channel) in self.channels.iter_mut().enumerate() {
+            if channel.control & CTRL_ENABLE == 0 {
+                continue;
+            }
+            let level = if idx == NOISE_CHANNEL { channel.noise() } else { channel.square() };
+            mix += level * channel.volume as i32 * CHANNEL_AMPLITUDE / 15;
+        }
+        (mix * self.master as i32 / 15) as i16
+    }
+
+    // catch the output up to the cpu's cycle count
+    pub fn tick(&mut self, cycles: u64) {
+        let due = cycles * SAMPLE_RATE as u64 / self.clock_hz;
+        while self.samples_made < due {
+            let sample = self.sample();
+            self.buffer.push(sample);
+            self.samples_made += 1;
+        }
+    }
+
+    // moves everything generated so far into out
+    pub fn drain(&mut self, out: &mut Vec<i16>) {
+        out.append(&mut self.buffer);
+    }
+
+    // samples not drained yet are left out, the host only ever has a frame's worth waiting
+    pub fn save_state(&self, out: &mut StateWriter) {
+        for channel in &self.channels {
+            out.u16(channel.freq);
+            out.u8(channel.volume);
+            out.u8(channel.control);
+            out.u32(channel.phase);
+            out.u16(channel.lfsr);
+        }
+        out.u8(self.master);
+        out.u64(self.samples_made);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        for channel in &mut self.channels {
+            channel.freq = input.u16()?;
+            channel.volume = input.u8()?;
+            channel.control = input.u8()?;
+            channel.phase = input.u32()?;
+            channel.lfsr = input.u16()?;
+        }
+        self.master = input.u8()?;
+        self.samples_made = input.u64()?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+// 16-bit mono pcm wav at SAMPLE_RATE
+pub fn write_wav(path: &str, samples: &[i16]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut out: Vec<u8> = Vec::with_capacity(44 + data_len as usize);
+
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+    out.extend_from_slice(&1u16.to_le_bytes()); // pcm
+    out.extend_from_slice(&1u16.to_le_bytes()); // mono
+    out.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    out.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+    out.extend_from_slice(&2u16.to_le_bytes()); // block align
+    out.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    fs::write(path, out)
+}
+
+// playback through the host's default output device (cargo feature "host-audio")
+#[cfg(feature = "host-audio")]
+pub mod host {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+
+    use super::SAMPLE_RATE;
+
+    // don't let latency build up if the vm outruns the sound card
+    const MAX_QUEUED: usize = SAMPLE_RATE as usize / 4;
+
+    pub struct HostAudio {
+        queue: Arc<Mutex<VecDeque<i16>>>,
+        _stream: Stream, // playback stops when this is dropped
+    }
+
+    impl HostAudio {
+        // None if there's no usable output device, the vm just runs silent then
+        pub fn open() -> Option<Self> {
+            let device = cpal::default_host().default_output_device()?;
+            let supported = match device.default_output_config() {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("No audio output config: {}", e);
+                    return None;
+                },
+            };
+            let config = supported.config();
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+
+            let stream = match supported.sample_format() {
+                SampleFormat::F32 => build::<f32>(&device, &config, queue.clone()),
+                SampleFormat::I16 => build::<i16>(&device, &config, queue.clone()),
+                SampleFormat::U16 => build::<u16>(&device, &config, queue.clone()),
+                other => {
+                    eprintln!("Unsupported audio sample format {:?}", other);
+                    return None;
+                },
+            }?;
+
+            if let Err(e) = stream.play() {
+                eprintln!("Couldn't start audio: {}", e);
+                return None;
+            }
+
+            Some(Self { queue, _stream: stream })
+        }
+
+        pub fn push(&self, samples: &[i16]) {
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(samples);
+            let excess = queue.len().saturating_sub(MAX_QUEUED);
+            queue.drain(..excess);
+        }
+    }
+
+    // resamples SAMPLE_RATE mono up/down to whatever the device wants
+    fn build<T>(device: &cpal::Device, config: &StreamConfig, queue: Arc<Mutex<VecDeque<i16>>>) -> Option<Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let channels = config.channels as usize;
+        let step = SAMPLE_RATE as f32 / config.sample_rate.0 as f32;
+        let mut pos = 0.0;
+        let mut current: i16 = 0;
+
+        let stream = device.build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                let mut queue = queue.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    pos += step;
+                    while pos >= 1.0 {
+                        current = queue.pop_front().unwrap_or(0); // silence on underrun
+                        pos -= 1.0;
+                    }
+                    let value = T::from_sample(current as f32 / i16::MAX as f32);
+                    for out in frame.iter_mut() {
+                        *out = value;
+                    }
+                }
+            },
+            |e| eprintln!("Audio stream error: {}", e),
+            None,
+        );
+
+        match stream {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("Couldn't open audio stream: {}", e);
+                None
+            },
+        }
+    }
+}
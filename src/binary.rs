@@ -20,4 +20,11 @@ pub fn get_bits_lsb(number: u16, idx1: u8, idx2: u8) -> u16 {
     let width = lsb_high - lsb_low + 1;
 
     (number >> lsb_low) & ((1 << width) - 1)
-}
\ No newline at end of file
+}
+// decimal or 0x hex, as typed in scenarios and debugger commands
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
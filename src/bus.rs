@@ -1,103 +1,46 @@
 
 use crate::{Keyboard, Mouse, cpu::{Access, CPUExit, CPUMode, Fault}};
-use std::ops::Range;
-
-#[derive(Debug, PartialEq)]
-pub enum MemRange {
-    Bootloader ( Range<u16> ), // kernel can read & execute
-    KernelCore ( Range<u16> ), // kernel can read & execute
-    KernelTraps ( Range<u16> ), // kernel can 
-    KernelData ( Range<u16> ),
-    KernelHeap ( Range<u16> ),
-    KernelStack ( Range<u16> ),
-    Vram ( Range<u16> ),
-    Mmio ( Range<u16> ),
-
-    UserCode ( Range<u16>, u8 ),
-    UserData ( Range<u16>, u8 ),
-    UserHeap ( Range<u16>, u8 ),
-    UserStack ( Range<u16>, u8 ),
-    UserVram ( Range<u16>, u8 ),
-
-    SharedData ( Range<u16> ),
+use crate::memmap::{MemRange, RegionKind};
+use crate::device::{Interrupts, IRQ_DMA, IRQ_ENABLE_OFFSET, IRQ_STATUS_OFFSET};
+use crate::rtc::{ClockSample, Rtc, RTC_OFFSET, RTC_SIZE};
+use crate::audio::{Audio, AUDIO_OFFSET, AUDIO_SIZE};
+use crate::rng::{Rng, RNG_OFFSET, RNG_SIZE};
+use crate::dma::{Dma, DmaStep, DMA_OFFSET, DMA_SIZE};
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+use crate::vc::is_video_register;
+use std::ops::RangeInclusive;
+
+// the kernel keeps the running task's index here, user memory permissions follow it
+pub const CURRENT_TASK: u16 = 0x12C8;
+
+// where the devices the bus dispatches to sit, as offsets into mmio. the keyboard,
+// the irq registers and the video registers don't move
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceSlots {
+    pub rtc: u16,
+    pub audio: u16,
+    pub rng: u16,
+    pub dma: u16,
 }
 
-impl MemRange {
-
-    pub fn check_access(&self, mode: CPUMode, access: Access, task_index: u8) -> Result<(), CPUExit> {
-        match self.allows(mode, access, task_index) {
-            true => Ok(()),
-            false => Err(CPUExit::Fault(Fault::IllegalMemAccess)),
-        }
-    }
-
-    pub fn contains(&self, addr: u16) -> bool {
-        match self {
-            MemRange::Bootloader(r)
-            | MemRange::KernelCore(r)
-            | MemRange::KernelTraps(r)
-            | MemRange::KernelData(r)
-            | MemRange::KernelHeap(r)
-            | MemRange::KernelStack(r)
-            | MemRange::Vram(r)
-            | MemRange::Mmio(r)
-            | MemRange::UserCode(r, _)
-            | MemRange::UserData(r, _)
-            | MemRange::UserHeap(r, _)
-            | MemRange::UserStack(r, _)
-            | MemRange::UserVram(r, _)
-            | MemRange::SharedData (r)
-            => r.contains(&addr),
+impl DeviceSlots {
+    pub fn new() -> Self {
+        Self {
+            rtc: RTC_OFFSET,
+            audio: AUDIO_OFFSET,
+            rng: RNG_OFFSET,
+            dma: DMA_OFFSET,
         }
     }
+}
 
-    pub fn allows(&self, mode: CPUMode, access: Access, current_task: u8) -> bool {
-        return match self {
-            Self::Bootloader(_) => mode == CPUMode::K && matches!(access, Access::X | Access::R),
-            Self::KernelCore(_) => mode == CPUMode::K && matches!(access, Access::R | Access::X),
-            Self::KernelTraps(_) => mode == CPUMode::K && matches!(access, Access::X | Access::R),
-            Self::KernelData(_) => mode == CPUMode::K && matches!(access, Access::R | Access::W),
-            Self::KernelHeap(_) => mode == CPUMode::K && matches!(access, Access::R | Access::W),
-            Self::KernelStack(_) => mode == CPUMode::K && matches!(access, Access::R | Access::W),
-            Self::Vram(_) => mode == CPUMode::K && matches!(access, Access::R | Access::W),
-            Self::Mmio(_) => mode == CPUMode::K && matches!(access, Access::R | Access::W),
-            
-            Self::UserCode(_, t) => {
-                // println!("t (code): {}", t);
-                (mode == CPUMode::K && matches!(access, Access::X | Access::R)) || 
-                (mode == CPUMode::U && matches!(access, Access::X | Access::R) && (*t == current_task))
-            },
-            
-            Self::UserData(_, t) =>  
-                (mode == CPUMode::K && matches!(access, Access::R | Access::W)) || 
-                (mode == CPUMode::U && matches!(access, Access::R | Access::W) && (*t == current_task)),
-            
-            Self::UserHeap(_, t) => 
-                (mode == CPUMode::K && matches!(access, Access::R | Access::W)) || 
-                (mode == CPUMode::U && matches!(access, Access::R | Access::W) && (*t == current_task)),
-
-            Self::UserStack(_, t) => 
-                {
-                    // println!("t (stack): {}", t);
-                    (mode == CPUMode::K && matches!(access, Access::R | Access::W)) || 
-                    (mode == CPUMode::U && matches!(access, Access::R | Access::W) && (*t == current_task))},
-
-            Self::UserVram(_, t) => 
-                {
-                    // println!("t (stack): {}", t);
-                    (mode == CPUMode::K && matches!(access, Access::R | Access::W)) || 
-                    (mode == CPUMode::U && matches!(access, Access::R | Access::W) && (*t == current_task))},
-
-            Self::SharedData (_) => {
-                // println!("t (code): {}", t);
-                (mode == CPUMode::K && matches!(access, Access::R | Access::W)) || 
-                (mode == CPUMode::U && matches!(access, Access::R | Access::X))
-            },
-
-            _ => panic!("Couldn't identify memory range."),
-            
-        }
-    }
+// how an instruction uses a memory destination operand: cmp only reads it, mov and
+// not only write it, everything else reads it and writes the result back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dest {
+    Read,
+    Write,
+    Modify,
 }
 
 // memory bus
@@ -107,182 +50,52 @@ pub struct Bus {
 
     mouse: Mouse,
     keyboard: Keyboard,
-
-    // memory ranges
-    // bootloader: MemRange,
-    // kernel_core: MemRange,
-    // kernel_traps: MemRange,
-    // kernel_data: MemRange,
-    // kernel_heap: MemRange,
-    // kernel_stack: MemRange,
-    // mmio: MemRange,
-
-    // user_code: MemRange,
-    // user_data: MemRange,
-    // user_heap: MemRange,
-    // user_stack: MemRange,
-    ranges: Vec<MemRange>,
-    mmio_range: Range<u16>,
+    rtc: Rtc,
+    audio: Audio,
+    rng: Rng,
+    dma: Dma,
+    irq: Interrupts,
+
+    ranges: Vec<MemRange>, // the memory map, sorted by start
+    mmio_range: RangeInclusive<u16>,
+    slots: DeviceSlots,
+    mmio_pending: Option<(u16, CPUMode, u8, bool)>, // mmio operand behind a mutable ref: address, mode, its ram byte before, whether the op writes it
+
+    // debugger watchpoints: (first, last, access), and the first one the cpu tripped since it was last taken
+    watches: Vec<(u16, u16, Access)>,
+    watch_hit: Option<(u16, Access)>,
+    // data reads/writes since the last take_accesses, only kept while tracing
+    accesses: Option<Vec<(u16, Access, u8)>>,
 }
 
 
 
 impl Bus {
-    pub fn new(
-        mouse: Mouse,
-        keyboard: Keyboard,
-
-        bootloader: Range<u16>,
-        kernel_core: Range<u16>,
-        kernel_traps: Range<u16>,
-        kernel_data: Range<u16>,
-        kernel_heap: Range<u16>,
-        kernel_stack: Range<u16>,
-        vram: Range<u16>,
-        mmio: Range<u16>,
-
-
-        // later: make this way more concise. probably use a vec or array
-        user_code_0:  Range<u16>,
-        user_data_0:  Range<u16>,
-        user_heap_0:  Range<u16>,
-        user_vram_0:  Range<u16>,
-        user_stack_0: Range<u16>,
-
-        user_code_1:  Range<u16>,
-        user_data_1:  Range<u16>,
-        user_heap_1:  Range<u16>,
-        user_vram_1:  Range<u16>,
-        user_stack_1: Range<u16>,
-
-        user_code_2:  Range<u16>,
-        user_data_2:  Range<u16>,
-        user_heap_2:  Range<u16>,
-        user_vram_2:  Range<u16>,
-        user_stack_2: Range<u16>,
-
-        user_code_3:  Range<u16>,
-        user_data_3:  Range<u16>,
-        user_heap_3:  Range<u16>,
-        user_vram_3:  Range<u16>,
-        user_stack_3: Range<u16>,
-
-        // user_code_4:  Range<u16>,
-        // user_data_4:  Range<u16>,
-        // user_heap_4:  Range<u16>,
-        // user_vram_4:  Range<u16>,
-        // user_stack_4: Range<u16>,
-
-        // user_code_5:  Range<u16>,
-        // user_data_5:  Range<u16>,
-        // user_heap_5:  Range<u16>,
-        // user_vram_5:  Range<u16>,
-        // user_stack_5: Range<u16>,
-
-        // user_code_6:  Range<u16>,
-        // user_data_6:  Range<u16>,
-        // user_heap_6:  Range<u16>,
-        // user_vram_6:  Range<u16>,
-        // user_stack_6: Range<u16>,
-
-        shared_data: Range<u16>,
-
-        // user_code_7:  Range<u16>,
-        // user_data_7:  Range<u16>,
-        // user_heap_7:  Range<u16>,
-        // user_vram_7:  Range<u16>,
-        // user_stack_7: Range<u16>,
-
-    ) -> Self {
-        let mmio_range = mmio.clone();
-        let bootloader= MemRange::Bootloader (bootloader);
-        let kernel_core= MemRange::KernelCore (kernel_core);
-        let kernel_traps= MemRange::KernelTraps (kernel_traps);
-        let kernel_data= MemRange::KernelData (kernel_data);
-        let kernel_heap= MemRange::KernelHeap (kernel_heap);
-        let kernel_stack= MemRange::KernelStack (kernel_stack);
-        let vram = MemRange::Vram (vram);
-        let mmio= MemRange::Mmio (mmio);
-
-        let user_code_0  = MemRange::UserCode ( user_code_0, 0);
-        let user_data_0  = MemRange::UserData ( user_data_0, 0);
-        let user_heap_0  = MemRange::UserHeap ( user_heap_0, 0);
-        let user_vram_0  = MemRange::UserVram ( user_vram_0, 0);
-        let user_stack_0 = MemRange::UserStack ( user_stack_0, 0);
-
-        let user_code_1  = MemRange::UserCode ( user_code_1, 1);
-        let user_data_1  = MemRange::UserData ( user_data_1, 1);
-        let user_heap_1  = MemRange::UserHeap ( user_heap_1, 1);
-        let user_vram_1  = MemRange::UserVram ( user_vram_1, 1);
-        let user_stack_1 = MemRange::UserStack ( user_stack_1, 1);
-
-        let user_code_2  = MemRange::UserCode ( user_code_2, 2);
-        let user_data_2  = MemRange::UserData ( user_data_2, 2);
-        let user_heap_2  = MemRange::UserHeap ( user_heap_2, 2);
-        let user_vram_2  = MemRange::UserVram ( user_vram_2, 2);
-        let user_stack_2 = MemRange::UserStack ( user_stack_2, 2);
-
-        let user_code_3  = MemRange::UserCode ( user_code_3, 3);
-        let user_data_3  = MemRange::UserData ( user_data_3, 3);
-        let user_heap_3  = MemRange::UserHeap ( user_heap_3, 3);
-        let user_vram_3  = MemRange::UserVram ( user_vram_3, 3);
-        let user_stack_3 = MemRange::UserStack ( user_stack_3, 3);
-
-        // let user_code_4  = MemRange::UserCode ( user_code_4, 4);
-        // let user_data_4  = MemRange::UserData ( user_data_4, 4);
-        // let user_heap_4  = MemRange::UserHeap ( user_heap_4, 4);
-        // let user_vram_4  = MemRange::UserVram ( user_vram_4, 4);
-        // let user_stack_4 = MemRange::UserStack ( user_stack_4, 4);
-
-        // let user_code_5  = MemRange::UserCode ( user_code_5, 5);
-        // let user_data_5  = MemRange::UserData ( user_data_5, 5);
-        // let user_heap_5  = MemRange::UserHeap ( user_heap_5, 5);
-        // let user_vram_5  = MemRange::UserVram ( user_vram_5, 5);
-        // let user_stack_5 = MemRange::UserStack ( user_stack_5, 5);
-
-        // let user_code_6  = MemRange::UserCode ( user_code_6, 6);
-        // let user_data_6  = MemRange::UserData ( user_data_6, 6);
-        // let user_heap_6  = MemRange::UserHeap ( user_heap_6, 6);
-        // let user_vram_6  = MemRange::UserVram ( user_vram_6, 6);
-        // let user_stack_6 = MemRange::UserStack ( user_stack_6, 6);
-
-        let shared_data = MemRange::SharedData( shared_data );
-
-        // let user_code_7  = MemRange::UserCode ( user_code_7, 7);
-        // let user_data_7  = MemRange::UserData ( user_data_7, 7);
-        // let user_heap_7  = MemRange::UserHeap ( user_heap_7, 7);
-        // let user_stack_7 = MemRange::UserStack ( user_stack_7, 7);
-
-
-        let ranges = vec![
-            bootloader,
-            kernel_core,
-            kernel_traps,
-            kernel_data,
-            kernel_heap,
-            kernel_stack,
-            vram,
-            mmio,
-
-            user_code_0,  user_data_0,  user_heap_0,  user_stack_0,
-            user_code_1,  user_data_1,  user_heap_1,  user_stack_1,
-            user_code_2,  user_data_2,  user_heap_2,  user_stack_2,
-            user_code_3,  user_data_3,  user_heap_3,  user_stack_3,
-            // user_code_4,  user_data_4,  user_heap_4,  user_stack_4,
-            // user_code_5,  user_data_5,  user_heap_5,  user_stack_5,
-            // user_code_6,  user_data_6,  user_heap_6,  user_stack_6,
-            shared_data,
-            // user_code_7,  user_data_7,  user_heap_7,  user_stack_7,
-        ];
-
-        
+    // regions is a whole memory map (see memmap::check) with exactly one mmio region
+    pub fn new(mouse: Mouse, keyboard: Keyboard, rtc: Rtc, audio: Audio, rng: Rng, mut regions: Vec<MemRange>) -> Self {
+        regions.sort_by_key(|r| r.range.start);
+        let mmio_range = regions.iter()
+            .find(|r| r.kind == RegionKind::Mmio)
+            .map(|r| r.range.start as u16..=(r.range.end - 1) as u16)
+            .expect("memory map without an mmio region");
+
         Self {
             ram: [0; 65536],
 
             mouse,
             keyboard,
-            ranges,
+            rtc,
+            audio,
+            rng,
+            dma: Dma::new(),
+            irq: Interrupts::new(),
+            ranges: regions,
             mmio_range,
+            slots: DeviceSlots::new(),
+            mmio_pending: None,
+            watches: Vec::new(),
+            watch_hit: None,
+            accesses: None,
         }
     }
 
@@ -290,66 +103,193 @@ impl Bus {
         return (self.ram.len() - 1) as u16;
     }
     pub fn check_access(&mut self, address: u16, mode: CPUMode, access: Access) -> Result<(), CPUExit> {
+        let task = self.ram[CURRENT_TASK as usize]; // dynamically grab current task
+        self.check_access_as(address, mode, access, task)
+    }
 
-        for range in &self.ranges {
-            let actual_range = match range {
-                MemRange::Bootloader(r)
-                | MemRange::KernelCore(r)
-                | MemRange::KernelTraps(r)
-                | MemRange::KernelData(r)
-                | MemRange::KernelHeap(r)
-                | MemRange::KernelStack(r)
-                | MemRange::Vram(r)
-                | MemRange::Mmio(r)
-                | MemRange::UserCode(r, _)
-                | MemRange::UserData(r, _)
-                | MemRange::UserHeap(r, _)
-                | MemRange::UserVram(r, _)
-                | MemRange::UserStack(r, _)
-                | MemRange::SharedData(r) => r,
-            };
-            if actual_range.contains(&address) {
-                // println!("Range: {:?}", range);
-                let result = range.check_access(mode, access, self.ram[0x12C8]); // dynamically grab current task
-                match &result {
-                    Ok(()) => (),
-                    Err(e) => {
-                        println!("Got CPUExit {:?} at range {:?} (0x{:0x}..0x{:0x})", e, range, actual_range.start, actual_range.end);
-                        println!("Mode: {:?}\nAccess: {:?}", mode, access);
-                        println!("Attempted to access address 0x{:0x}\n", address);
-                    }
-                }
-                return result;
-            }
+    // same as check_access for a given task, the dma checks against the one that started it
+    fn check_access_as(&self, address: u16, mode: CPUMode, access: Access, task: u8) -> Result<(), CPUExit> {
+        let Some(range) = self.region(address) else {
+            return Err(CPUExit::Fault(Fault::IllegalMemAccess));
+        };
+        // println!("Range: {:?}", range);
+        let result = range.check_access(mode, access, task);
+        if let Err(e) = &result {
+            println!("Got CPUExit {:?} at {} (0x{:0x}..0x{:0x})", e, range.name(), range.range.start, range.range.end);
+            println!("Mode: {:?}\nAccess: {:?}", mode, access);
+            println!("Attempted to access address 0x{:0x}\n", address);
         }
-        
-        Err(CPUExit::Fault(Fault::IllegalMemAccess))
-
+        result
     }
 
     pub fn get(&mut self, address: u16, mode: CPUMode, access: Access) -> Result<u8, CPUExit> {
 
         self.check_access(address, mode, access)?;
 
-        if self.mmio_range.contains(&address) {
-            return Ok(self.mmio_get(address)?);
+        let value = if self.mmio_range.contains(&address) {
+            self.mmio_get(address)?
+        }
+        else {
+            self.ram[address as usize]
+        };
+        if access == Access::R {
+            self.observe(address, Access::R, value);
         }
-        return Ok(self.ram[address as usize]);
+        return Ok(value);
 
     }
 
     pub fn mmio_get(&mut self, address: u16) -> Result<u8, CPUExit> {
         // println!("Getting from MMIO...");
-        let keyboard_status: u16 = self.mmio_range.start;
-        if address == keyboard_status {
-            return Ok(self.keyboard.status());
+        let keyboard_status: u16 = *self.mmio_range.start();
+        let offset = address - keyboard_status;
+        let value = if address == keyboard_status {
+            self.keyboard.status()
         }
         else if address == keyboard_status + 1 {
-            return Ok(self.keyboard.pop_key());
+            self.keyboard.pop_key()
+        }
+        else if offset == IRQ_STATUS_OFFSET {
+            self.irq.status
+        }
+        else if offset == IRQ_ENABLE_OFFSET {
+            self.irq.enable
+        }
+        else if (self.slots.rtc..self.slots.rtc + RTC_SIZE).contains(&offset) {
+            self.rtc.read(offset - self.slots.rtc)
+        }
+        else if (self.slots.audio..self.slots.audio + AUDIO_SIZE).contains(&offset) {
+            self.audio.read(offset - self.slots.audio)
+        }
+        else if (self.slots.rng..self.slots.rng + RNG_SIZE).contains(&offset) {
+            self.rng.read(offset - self.slots.rng)
+        }
+        else if (self.slots.dma..self.slots.dma + DMA_SIZE).contains(&offset) {
+            self.dma.read(offset - self.slots.dma)
+        }
+        else if is_video_register(offset) {
+            // plain registers, the video controller reads them straight out of ram
+            self.ram[address as usize]
+        }
+        else {
+            return Err(CPUExit::Fault(Fault::IllegalMemAccess));
+        };
+        Ok(value)
+    }
+
+    pub fn mmio_set(&mut self, address: u16, src: u8, mode: CPUMode) -> Result<(), CPUExit> {
+        let offset = address - self.mmio_range.start();
+
+        if offset == IRQ_STATUS_OFFSET {
+            self.irq.acknowledge(src);
+        }
+        else if offset == IRQ_ENABLE_OFFSET {
+            self.irq.enable = src;
+        }
+        else if (self.slots.rtc..self.slots.rtc + RTC_SIZE).contains(&offset) {
+            self.rtc.write(offset - self.slots.rtc, src);
+        }
+        else if (self.slots.audio..self.slots.audio + AUDIO_SIZE).contains(&offset) {
+            self.audio.write(offset - self.slots.audio, src);
+        }
+        else if (self.slots.rng..self.slots.rng + RNG_SIZE).contains(&offset) {
+            self.rng.write(offset - self.slots.rng, src);
+        }
+        else if (self.slots.dma..self.slots.dma + DMA_SIZE).contains(&offset) {
+            self.dma.write(offset - self.slots.dma, src, mode, self.ram[CURRENT_TASK as usize]);
+        }
+        else if is_video_register(offset) {
+            // plain registers, the video controller reads them straight out of ram
         }
         else {
             return Err(CPUExit::Fault(Fault::IllegalMemAccess));
         }
+        // only once a device took it, the last value written sits in ram behind the register
+        self.ram[address as usize] = src;
+        Ok(())
+    }
+
+    // ops like `mov mr` go through a &mut u8; for mmio that's the ram byte behind the
+    // register, loaded from the device first if the op reads it. once the op is done the
+    // byte goes back to what it was and whatever the op wrote is sent to the device
+    pub fn flush_mmio(&mut self) -> Result<(), CPUExit> {
+        if let Some((address, mode, before, write)) = self.mmio_pending.take() {
+            let val = std::mem::replace(&mut self.ram[address as usize], before);
+            if write {
+                self.mmio_set(address, val, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn map_devices(&mut self, slots: DeviceSlots) {
+        self.slots = slots;
+    }
+
+    pub fn set_clock_hz(&mut self, hz: u64) {
+        self.rtc.set_clock_hz(hz);
+        self.audio.set_clock_hz(hz);
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.rtc.tick(cycles, &mut self.irq);
+        self.audio.tick(cycles);
+        self.run_dma(cycles);
+    }
+
+    // moves as many bytes as the dma has had cycles for. every byte is checked against
+    // the requester's permissions (as whichever task started it, not the one running
+    // now), and devices can't be a source or destination
+    fn run_dma(&mut self, cycles: u64) {
+        let mut budget = self.dma.budget(cycles);
+        let (mode, task) = (self.dma.mode(), self.dma.task());
+
+        while self.dma.busy() && budget > 0 {
+            budget -= 1;
+            let result = match self.dma.next_step() {
+                Some(DmaStep::Copy { src, dst }) => self.dma_get(src, mode, task)
+                    .and_then(|val| self.dma_set(dst, val, mode, task)),
+                Some(DmaStep::Fill { dst, val }) => self.dma_set(dst, val, mode, task),
+                None => {
+                    self.dma.finish(false);
+                    self.irq.raise(IRQ_DMA);
+                    break;
+                },
+            };
+            if result.is_err() {
+                self.dma.finish(true);
+                self.irq.raise(IRQ_DMA);
+            }
+        }
+    }
+
+    fn dma_get(&mut self, address: u16, mode: CPUMode, task: u8) -> Result<u8, CPUExit> {
+        if self.mmio_range.contains(&address) {
+            return Err(CPUExit::Fault(Fault::IllegalMemAccess));
+        }
+        self.check_access_as(address, mode, Access::R, task)?;
+        Ok(self.ram[address as usize])
+    }
+
+    fn dma_set(&mut self, address: u16, val: u8, mode: CPUMode, task: u8) -> Result<(), CPUExit> {
+        if self.mmio_range.contains(&address) {
+            return Err(CPUExit::Fault(Fault::IllegalMemAccess));
+        }
+        self.check_access_as(address, mode, Access::W, task)?;
+        self.ram[address as usize] = val;
+        Ok(())
+    }
+
+    pub fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        self.audio.drain(out);
+    }
+
+    pub fn raise_irq(&mut self, line: u8) {
+        self.irq.raise(line);
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.irq.pending()
     }
 
     pub fn force_set(&mut self, dest: u16, src: u8) {
@@ -362,20 +302,29 @@ impl Bus {
 
     pub fn set(&mut self, dest: u16, src: u8, mode: CPUMode, access: Access) -> Result<(), CPUExit> {
         self.check_access(dest, mode, access)?;
+        self.observe(dest, Access::W, src);
+        if self.mmio_range.contains(&dest) {
+            return self.mmio_set(dest, src, mode);
+        }
         self.ram[dest as usize] = src;
 
         Ok(())
     }
 
-    pub fn get_mutable_ref(&mut self, address: u16, mode: CPUMode, access: Access) -> Result<&mut u8, CPUExit> {
-
+    pub fn get_mutable_ref(&mut self, address: u16, mode: CPUMode, dest: Dest) -> Result<&mut u8, CPUExit> {
+        // cmp only needs to be able to read it. the value written isn't known yet
+        let access = if dest == Dest::Read { Access::R } else { Access::W };
         self.check_access(address, mode, access)?;
 
         if self.mmio_range.contains(&address) {
-            return Err(CPUExit::Fault(Fault::IllegalMemAccess))
+            let before = self.ram[address as usize];
+            if dest != Dest::Write {
+                self.ram[address as usize] = self.mmio_get(address)?;
+            }
+            self.mmio_pending = Some((address, mode, before, dest != Dest::Read));
         }
-        return Ok(&mut self.ram[address as usize]);
-
+        self.observe(address, access, self.ram[address as usize]);
+        Ok(&mut self.ram[address as usize])
     }
 
     pub fn get_range(&mut self, a: u16, b: u16) -> &[u8] { // ONLY EXPOSED TO VM ONLY EXPOSED TO VM ONLY EXPOSED TO VM
@@ -383,11 +332,117 @@ impl Bus {
         return &self.ram[a as usize..b as usize];
     }
 
+    pub fn ram(&self) -> &[u8] { // also only for the vm, the video controller reads patterns from anywhere
+        &self.ram
+    }
+
+    pub fn regions(&self) -> &[MemRange] {
+        &self.ranges
+    }
+
+    // the memory range an address falls in
+    pub fn region(&self, address: u16) -> Option<&MemRange> {
+        let idx = self.ranges.partition_point(|r| r.range.start <= address as u32);
+        self.ranges[..idx].last().filter(|r| r.contains(address))
+    }
+
+    pub fn set_watches(&mut self, watches: Vec<(u16, u16, Access)>) {
+        self.watches = watches;
+        self.watch_hit = None;
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<(u16, Access)> {
+        self.watch_hit.take()
+    }
+
+    // starts or stops keeping the access log
+    pub fn log_accesses(&mut self, on: bool) {
+        self.accesses = on.then(Vec::new);
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<(u16, Access, u8)> {
+        self.accesses.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // every data read/write by the cpu, for watchpoints and the trace
+    fn observe(&mut self, address: u16, access: Access, value: u8) {
+        if let Some(log) = &mut self.accesses {
+            log.push((address, access, value));
+        }
+        if self.watch_hit.is_none() && self.watches.iter().any(|(first, last, a)| *a == access && (*first..=*last).contains(&address)) {
+            self.watch_hit = Some((address, access));
+        }
+    }
+
     pub fn key_inject(&mut self, key: u8) {
         self.keyboard.inject_key(key);
     }
 
+    pub fn key_pending(&self) -> bool {
+        self.keyboard.pending()
+    }
+
+    // the rtc's end of record/replay and history, see replay.rs and history.rs
+    pub fn host_clock(&self) -> bool {
+        self.rtc.is_host()
+    }
+
+    pub fn record_clock(&mut self) {
+        self.rtc.record();
+    }
+
+    pub fn stop_clock_log(&mut self) {
+        self.rtc.stop_recording();
+    }
+
+    pub fn clock_log(&self) -> &[(u64, ClockSample)] {
+        self.rtc.log()
+    }
+
+    pub fn trim_clock_log(&mut self, cycle: u64) {
+        self.rtc.trim_log(cycle);
+    }
+
+    pub fn forget_clock_after(&mut self, cycle: u64) {
+        self.rtc.forget_after(cycle);
+    }
+
+    pub fn replay_clock(&mut self, host: Option<Vec<(u64, ClockSample)>>) {
+        self.rtc.replay(host);
+    }
+
+    pub fn rewind_clock(&mut self, samples: &[(u64, ClockSample)], cycle: u64, live_after: Option<u64>) {
+        self.rtc.rewind(samples, cycle, live_after);
+    }
+
     pub fn status(&mut self) {
         self.keyboard.debug();
     }
+
+    // ram and every device. pending mmio writes are always flushed by the end of an
+    // instruction, and watches belong to whoever's debugging, so neither is saved
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.ram);
+        self.mouse.save_state(out);
+        self.keyboard.save_state(out);
+        self.rtc.save_state(out);
+        self.audio.save_state(out);
+        self.rng.save_state(out);
+        self.dma.save_state(out);
+        self.irq.save_state(out);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.ram = input.array()?;
+        self.mouse.load_state(input)?;
+        self.keyboard.load_state(input)?;
+        self.rtc.load_state(input)?;
+        self.audio.load_state(input)?;
+        self.rng.load_state(input)?;
+        self.dma.load_state(input)?;
+        self.irq.load_state(input)?;
+        self.mmio_pending = None;
+        self.watch_hit = None;
+        Ok(())
+    }
 }
\ No newline at end of file
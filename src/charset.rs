@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+
+/*
+ * the machine's character set, used by the assembler's .str and the text mode font
+ *
+ * 0: space
+ * 1-26: a-z (upper case letters encode the same)
+ * 27, 28: caret, end caret (no source character, use the codes)
+ * 29-38: 0-9
+ * 39-62: punctuation, see CHARS
+ * 63: solid block (no source character)
+ *
+ * anything from GLYPHS up is blank in the built-in font
+ */
+
+pub const GLYPHS: usize = 64;
+
+// character for each code, '\0' where the code has no character
+const CHARS: [char; GLYPHS] = [
+    ' ', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o',
+    'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '\0', '\0', '0', '1', '2',
+    '3', '4', '5', '6', '7', '8', '9', '.', ',', '!', '?', ':', ';', '-', '+', '=',
+    '/', '(', ')', '\'', '"', '*', '#', '_', '<', '>', '[', ']', '%', '&', '@', '\0',
+];
+
+// char -> code, for .str
+pub fn symbols() -> HashMap<char, u8> {
+    let mut symbols = HashMap::new();
+    for (code, c) in CHARS.iter().enumerate() {
+        if *c == '\0' {
+            continue;
+        }
+        symbols.insert(*c, code as u8);
+        if c.is_ascii_lowercase() {
+            symbols.insert(c.to_ascii_uppercase(), code as u8);
+        }
+    }
+    symbols
+}
+
+// code -> char, None for codes without one
+pub fn char_of(code: u8) -> Option<char> {
+    CHARS.get(code as usize).copied().filter(|c| *c != '\0')
+}
+
+// built-in 8x8 font, one byte per row, msb is the leftmost pixel.
+// letters are the same 5x7 glyphs the shell draws from shared.dnasm
+pub const FONT: [[u8; 8]; GLYPHS] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x38, 0x44, 0x44, 0x7C, 0x44, 0x44, 0x44, 0x00], // a
+    [0x78, 0x44, 0x44, 0x78, 0x44, 0x44, 0x78, 0x00], // b
+    [0x38, 0x44, 0x40, 0x40, 0x40, 0x44, 0x38, 0x00], // c
+    [0x78, 0x44, 0x44, 0x44, 0x44, 0x44, 0x78, 0x00], // d
+    [0x7C, 0x40, 0x40, 0x7C, 0x40, 0x40, 0x7C, 0x00], // e
+    [0x7C, 0x40, 0x40, 0x7C, 0x40, 0x40, 0x40, 0x00], // f
+    [0x7C, 0x44, 0x44, 0x40, 0x5C, 0x44, 0x7C, 0x00], // g
+    [0x44, 0x44, 0x44, 0x7C, 0x44, 0x44, 0x44, 0x00], // h
+    [0x7C, 0x10, 0x10, 0x10, 0x10, 0x10, 0x7C, 0x00], // i
+    [0x7C, 0x10, 0x10, 0x10, 0x50, 0x50, 0x70, 0x00], // j
+    [0x44, 0x48, 0x50, 0x60, 0x50, 0x48, 0x44, 0x00], // k
+    [0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x7C, 0x00], // l
+    [0x44, 0x6C, 0x54, 0x44, 0x44, 0x44, 0x44, 0x00], // m
+    [0x64, 0x64, 0x64, 0x54, 0x4C, 0x4C, 0x4C, 0x00], // n
+    [0x38, 0x44, 0x44, 0x44, 0x44, 0x44, 0x38, 0x00], // o
+    [0x7C, 0x44, 0x44, 0x7C, 0x40, 0x40, 0x40, 0x00], // p
+    [0x78, 0x48, 0x48, 0x48, 0x48, 0x78, 0x04, 0x00], // q
+    [0x7C, 0x44, 0x44, 0x7C, 0x60, 0x50, 0x48, 0x00], // r
+    [0x7C, 0x44, 0x40, 0x7C, 0x04, 0x44, 0x7C, 0x00], // s
+    [0x7C, 0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x00], // t
+    [0x44, 0x44, 0x44, 0x44, 0x44, 0x44, 0x7C, 0x00], // u
+    [0x44, 0x44, 0x44, 0x44, 0x28, 0x28, 0x10, 0x00], // v
+    [0x44, 0x44, 0x44, 0x54, 0x54, 0x54, 0x7C, 0x00], // w
+    [0x44, 0x6C, 0x28, 0x10, 0x38, 0x6C, 0x44, 0x00], // x
+    [0x44, 0x44, 0x28, 0x10, 0x10, 0x10, 0x10, 0x00], // y
+    [0x7C, 0x04, 0x08, 0x10, 0x20, 0x40, 0x7C, 0x00], // z
+    [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80], // caret
+    [0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01], // end-caret
+    [0x38, 0x4C, 0x54, 0x54, 0x64, 0x44, 0x38, 0x00], // 0
+    [0x10, 0x30, 0x10, 0x10, 0x10, 0x10, 0x38, 0x00], // 1
+    [0x38, 0x44, 0x04, 0x18, 0x20, 0x40, 0x7C, 0x00], // 2
+    [0x78, 0x04, 0x04, 0x38, 0x04, 0x04, 0x78, 0x00], // 3
+    [0x08, 0x18, 0x28, 0x48, 0x7C, 0x08, 0x08, 0x00], // 4
+    [0x7C, 0x40, 0x78, 0x04, 0x04, 0x44, 0x38, 0x00], // 5
+    [0x18, 0x20, 0x40, 0x78, 0x44, 0x44, 0x38, 0x00], // 6
+    [0x7C, 0x04, 0x08, 0x10, 0x20, 0x20, 0x20, 0x00], // 7
+    [0x38, 0x44, 0x44, 0x38, 0x44, 0x44, 0x38, 0x00], // 8
+    [0x38, 0x44, 0x44, 0x3C, 0x04, 0x08, 0x30, 0x00], // 9
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x30, 0x00], // .
+    [0x00, 0x00, 0x00, 0x00, 0x30, 0x10, 0x20, 0x00], // ,
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x00, 0x10, 0x00], // !
+    [0x38, 0x44, 0x04, 0x08, 0x10, 0x00, 0x10, 0x00], // ?
+    [0x00, 0x30, 0x30, 0x00, 0x30, 0x30, 0x00, 0x00], // :
+    [0x00, 0x30, 0x30, 0x00, 0x30, 0x10, 0x20, 0x00], // ;
+    [0x00, 0x00, 0x00, 0x7C, 0x00, 0x00, 0x00, 0x00], // -
+    [0x00, 0x10, 0x10, 0x7C, 0x10, 0x10, 0x00, 0x00], // +
+    [0x00, 0x00, 0x7C, 0x00, 0x7C, 0x00, 0x00, 0x00], // =
+    [0x04, 0x08, 0x08, 0x10, 0x20, 0x20, 0x40, 0x00], // /
+    [0x08, 0x10, 0x20, 0x20, 0x20, 0x10, 0x08, 0x00], // (
+    [0x20, 0x10, 0x08, 0x08, 0x08, 0x10, 0x20, 0x00], // )
+    [0x10, 0x10, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00], // '
+    [0x28, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // "
+    [0x00, 0x54, 0x38, 0x7C, 0x38, 0x54, 0x00, 0x00], // *
+    [0x28, 0x28, 0x7C, 0x28, 0x7C, 0x28, 0x28, 0x00], // #
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7C, 0x00], // _
+    [0x08, 0x10, 0x20, 0x40, 0x20, 0x10, 0x08, 0x00], // <
+    [0x20, 0x10, 0x08, 0x04, 0x08, 0x10, 0x20, 0x00], // >
+    [0x38, 0x20, 0x20, 0x20, 0x20, 0x20, 0x38, 0x00], // [
+    [0x38, 0x08, 0x08, 0x08, 0x08, 0x08, 0x38, 0x00], // ]
+    [0x60, 0x64, 0x08, 0x10, 0x20, 0x4C, 0x0C, 0x00], // %
+    [0x30, 0x48, 0x50, 0x20, 0x54, 0x48, 0x34, 0x00], // &
+    [0x38, 0x44, 0x5C, 0x54, 0x5C, 0x40, 0x38, 0x00], // @
+    [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF], // block
+];
@@ -0,0 +1,146 @@
+use crate::binary::parse_number;
+
+
+/*
+ * command line: os [COMMAND] [ARGS] [--config FILE]. with no command (or just
+ * options) it's run. every command only takes the options listed for it in
+ * COMMANDS, anything else is an error rather than being ignored
+ */
+
+pub const USAGE: &str = "\
+usage: os [COMMAND] [OPTIONS] [--config MACHINE.toml]
+
+commands:
+  run (default)           boot the machine in a window
+      --headless          no window, run on the virtual clock until it halts or --cycles runs out
+      --scale N --fit --fullscreen --crt
+      --debug             debugger on stdin (with --headless, on its own)
+      --gdb PORT|HOST:PORT|SOCKET
+                          wait for a gdb remote protocol client instead
+      --cycles N --wav FILE --screenshot FILE --dump-frames DIR [--every N]
+                          headless output
+      --seed N --font FILE
+      --load-snapshot FILE --save-snapshot FILE
+      --record FILE --replay FILE
+      --trace FILE [--trace-range FIRST-LAST] [--trace-task N] [--trace-mode kernel|user]
+      --profile FILE --profile-stacks FILE
+  debug                   same as run --headless --debug, takes the same options
+  assemble FILE.dnasm [--at ADDR] [-o FILE.bin]
+                          assemble without booting. FILE.bin gets everything from the
+                          lowest assembled address to the highest, plus FILE.sym and FILE.lst
+  disassemble FILE.dnasm|FILE.bin [--at ADDR] [--count N]
+                          list instructions from ADDR (labels too, for source files)
+  test SCENARIO... [--bless]
+                          run scenario files, see src/harness.rs
+  bench [--cycles N]      run src/bench_vram.dnasm and report instructions per second
+  render-trace FILE       print a --trace file as text
+  help                    this
+
+--config picks the machine (memory map, programs, devices, clock, window); the
+built-in one is machines/default.toml";
+
+const RUN_SWITCHES: &[&str] = &["--headless", "--debug", "--fit", "--fullscreen", "--crt"];
+const RUN_VALUES: &[&str] = &[
+    "--scale", "--gdb", "--cycles", "--wav", "--screenshot", "--dump-frames", "--every", "--seed", "--font",
+    "--load-snapshot", "--save-snapshot", "--record", "--replay",
+    "--trace", "--trace-range", "--trace-task", "--trace-mode", "--profile", "--profile-stacks",
+];
+
+struct Command {
+    name: &'static str,
+    switches: &'static [&'static str],
+    values: &'static [&'static str], // options that take a value
+    files: (usize, usize), // min, max plain arguments
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "run", switches: RUN_SWITCHES, values: RUN_VALUES, files: (0, 0) },
+    Command { name: "debug", switches: RUN_SWITCHES, values: RUN_VALUES, files: (0, 0) },
+    Command { name: "assemble", switches: &[], values: &["--at", "-o"], files: (1, 1) },
+    Command { name: "disassemble", switches: &[], values: &["--at", "--count"], files: (1, 1) },
+    Command { name: "test", switches: &["--bless"], values: &[], files: (1, usize::MAX) },
+    Command { name: "bench", switches: &[], values: &["--cycles"], files: (0, 0) },
+    Command { name: "render-trace", switches: &[], values: &[], files: (1, 1) },
+    Command { name: "help", switches: &[], values: &[], files: (0, 0) },
+];
+
+#[derive(Debug)]
+pub struct CliError {
+    pub message: String,
+}
+
+pub struct Args {
+    pub command: String,
+    pub positional: Vec<String>,
+    switches: Vec<String>,
+    values: Vec<(String, String)>,
+}
+
+impl Args {
+    // args without the program name
+    pub fn parse(args: &[String]) -> Result<Self, CliError> {
+        let err = |message: String| Err(CliError { message });
+        let (command, rest) = match args.first() {
+            Some(first) if first == "-h" || first == "--help" => ("help".to_string(), &args[1..]),
+            Some(first) if !first.starts_with('-') => (first.clone(), &args[1..]),
+            _ => ("run".to_string(), args),
+        };
+        let Some(spec) = COMMANDS.iter().find(|c| c.name == command) else {
+            return err(format!("unknown command {}", command));
+        };
+
+        let mut parsed = Self {
+            command: command.clone(),
+            positional: Vec::new(),
+            switches: Vec::new(),
+            values: Vec::new(),
+        };
+        let mut rest = rest.iter();
+        while let Some(arg) = rest.next() {
+            if spec.switches.contains(&arg.as_str()) {
+                parsed.switches.push(arg.clone());
+            }
+            else if spec.values.contains(&arg.as_str()) || arg == "--config" {
+                match rest.next() {
+                    Some(value) => parsed.values.push((arg.clone(), value.clone())),
+                    None => return err(format!("{} needs a value", arg)),
+                }
+            }
+            else if arg.starts_with('-') && arg.len() > 1 {
+                return err(format!("{} doesn't take {}", command, arg));
+            }
+            else {
+                parsed.positional.push(arg.clone());
+            }
+        }
+
+        let (min, max) = spec.files;
+        if parsed.positional.len() < min {
+            return err(format!("{} needs {}", command, if max == 1 { "a file" } else { "at least one file" }));
+        }
+        if parsed.positional.len() > max {
+            return err(format!("{} doesn't take {}", command, parsed.positional[max]));
+        }
+        Ok(parsed)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.switches.iter().any(|s| s == name)
+    }
+
+    // the last one wins if it's given twice
+    pub fn value(&self, name: &str) -> Option<String> {
+        self.values.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v.clone())
+    }
+
+    // decimal or 0x hex, no bigger than max
+    pub fn number(&self, name: &str, max: u64) -> Result<Option<u64>, CliError> {
+        match self.value(name) {
+            Some(v) => parse_number(&v)
+                .filter(|n| *n <= max)
+                .map(Some)
+                .ok_or(CliError { message: format!("bad number for {}: {}", name, v) }),
+            None => Ok(None),
+        }
+    }
+}
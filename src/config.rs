@@ -0,0 +1,401 @@
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::bus::DeviceSlots;
+use crate::memmap::{self, MemRange, Perms, RegionKind};
+use crate::device::IRQ_ENABLE_OFFSET;
+use crate::rtc::{RTC_OFFSET, RTC_SIZE};
+use crate::audio::{AUDIO_OFFSET, AUDIO_SIZE};
+use crate::rng::{RNG_OFFSET, RNG_SIZE};
+use crate::dma::{DMA_OFFSET, DMA_SIZE};
+use crate::vc::{is_video_register, PALETTE_OFFSET, PALETTE_SIZE, VRAM_SIZE};
+use crate::vm::{CLOCK_HZ, FRAME_HZ};
+
+
+/*
+ * machine config: everything boot_machine used to hard-code. a toml file with
+ *
+ * [machine]    clock_hz, timeslice (instructions per task before the timer traps),
+ *              seed and font (both optional)
+ * [frontend]   headless, scale, fit, fullscreen, crt; the command line wins
+ * [devices]    mmio slot of each device that can move, as an offset into mmio
+ * [[region]]   kind, task (user regions only), start, end (exclusive), and
+ *              optionally kernel/user permissions; see memmap.rs
+ * [[image]]    path, at: assembled into memory at boot, in file order
+ *
+ * machines/default.toml is the os's own machine and is built in, so running
+ * without --config needs no files besides the programs. image and font paths are
+ * relative to the config file; the built-in one is read as if it was loaded from
+ * machines/, so it and --config machines/default.toml are the same machine
+ */
+
+pub const DEFAULT: &str = include_str!("../machines/default.toml");
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub machine: Machine,
+    #[serde(default)]
+    pub frontend: Frontend,
+    #[serde(default)]
+    pub devices: Devices,
+    #[serde(rename = "region", default)]
+    pub regions: Vec<Region>,
+    #[serde(rename = "image", default)]
+    pub images: Vec<Image>,
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Machine {
+    pub clock_hz: u64,
+    pub timeslice: u16,
+    pub seed: Option<u32>,
+    pub font: Option<String>,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self {
+            clock_hz: CLOCK_HZ,
+            timeslice: 100,
+            seed: None,
+            font: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Frontend {
+    pub headless: bool,
+    pub scale: u32,
+    pub fit: bool,
+    pub fullscreen: bool,
+    pub crt: bool,
+}
+
+impl Default for Frontend {
+    fn default() -> Self {
+        Self {
+            headless: false,
+            scale: 4,
+            fit: false,
+            fullscreen: false,
+            crt: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Devices {
+    pub rtc: u16,
+    pub audio: u16,
+    pub rng: u16,
+    pub dma: u16,
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Self {
+            rtc: RTC_OFFSET,
+            audio: AUDIO_OFFSET,
+            rng: RNG_OFFSET,
+            dma: DMA_OFFSET,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub task: Option<u8>,
+    pub start: u16,
+    pub end: u32, // exclusive, up to 0x10000
+    pub kernel: Option<String>, // permissions, e.g. "rw", instead of the kind's
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Image {
+    pub path: String,
+    pub at: Option<u16>,
+}
+
+impl Region {
+    // the permissions were checked by validate
+    pub fn mem_range(&self) -> MemRange {
+        let mut range = MemRange::new(self.kind, self.start as u32..self.end, self.task);
+        if let Some(perms) = self.kernel.as_deref().and_then(Perms::parse) {
+            range.kernel = perms;
+        }
+        if let Some(perms) = self.user.as_deref().and_then(Perms::parse) {
+            range.user = perms;
+        }
+        range
+    }
+
+    // user_code_1, kernel_data...
+    pub fn name(&self) -> String {
+        self.mem_range().name()
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError {
+            message: format!("couldn't read {}: {}", path.display(), e),
+        })?;
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Self::parse(&text, dir).map_err(|e| ConfigError { message: format!("{}: {}", path.display(), e.message) })
+    }
+
+    pub fn builtin() -> Self {
+        Self::parse(DEFAULT, PathBuf::from("machines")).expect("machines/default.toml is broken")
+    }
+
+    fn parse(text: &str, dir: PathBuf) -> Result<Self, ConfigError> {
+        let mut config: Config = toml::from_str(text).map_err(|e| ConfigError { message: e.to_string() })?;
+        config.dir = dir;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let err = |message: String| Err(ConfigError { message });
+
+        if self.machine.clock_hz < FRAME_HZ {
+            return err(format!("clock_hz has to be at least {} (one instruction a frame)", FRAME_HZ));
+        }
+        if self.machine.timeslice == 0 {
+            return err("timeslice has to be at least 1".to_string());
+        }
+
+        self.validate_regions()?;
+        self.validate_devices()
+    }
+
+    fn validate_regions(&self) -> Result<(), ConfigError> {
+        let err = |message: String| Err(ConfigError { message });
+
+        for region in &self.regions {
+            let name = region.name();
+            if region.end <= region.start as u32 || region.end > 0x10000 {
+                return err(format!("region {} is 0x{:04X}..0x{:04X}, the end has to be past the start and at most 0x10000", name, region.start, region.end));
+            }
+            match (region.kind.is_user(), region.task) {
+                (true, None) => return err(format!("region {} needs a task", name)),
+                (false, Some(_)) => return err(format!("region {} isn't per task, leave out its task", name)),
+                _ => (),
+            }
+            for perms in [&region.kernel, &region.user].into_iter().flatten() {
+                if region.kind == RegionKind::Unmapped {
+                    return err("unmapped regions can't have permissions".to_string());
+                }
+                if Perms::parse(perms).is_none() {
+                    return err(format!("region {} has permissions \"{}\", expected some of r, w and x", name, perms));
+                }
+            }
+            if region.kind != RegionKind::Unmapped && self.regions.iter().filter(|r| r.name() == name).count() > 1 {
+                return err(format!("region {} is given more than once", name));
+            }
+        }
+
+        for name in ["kernel_traps", "vram", "mmio"] {
+            if self.range(name).is_none() {
+                return err(format!("no {} region", name));
+            }
+        }
+
+        // tasks are numbered from 0 with no gaps, each with the full set of regions
+        let tasks = self.tasks();
+        for task in 0..tasks {
+            for kind in [RegionKind::UserCode, RegionKind::UserData, RegionKind::UserHeap, RegionKind::UserVram, RegionKind::UserStack] {
+                let name = format!("{}_{}", kind.name(), task);
+                if self.range(&name).is_none() {
+                    return err(format!("no {} region (tasks go from 0 to {})", name, tasks - 1));
+                }
+            }
+        }
+        for name in std::iter::once("vram".to_string()).chain((0..tasks).map(|t| format!("user_vram_{}", t))) {
+            let range = self.range(&name).unwrap();
+            if ((range.end - range.start) as usize) < VRAM_SIZE {
+                return err(format!("region {} is smaller than a screen (0x{:X} bytes)", name, VRAM_SIZE));
+            }
+        }
+
+        memmap::check(&self.memory_map()).map_err(|e| ConfigError { message: e.message })
+    }
+
+    // every device has to fit in mmio without landing on another one, the
+    // keyboard/irq registers or the video registers
+    fn validate_devices(&self) -> Result<(), ConfigError> {
+        let mmio = self.range("mmio").unwrap();
+        let mmio_len = mmio.end - mmio.start;
+        let needed = PALETTE_OFFSET as u32 + PALETTE_SIZE as u32;
+        if mmio_len < needed {
+            return Err(ConfigError { message: format!("mmio is 0x{:X} bytes, the video registers need 0x{:X}", mmio_len, needed) });
+        }
+        let slots = [
+            ("rtc", self.devices.rtc, RTC_SIZE),
+            ("audio", self.devices.audio, AUDIO_SIZE),
+            ("rng", self.devices.rng, RNG_SIZE),
+            ("dma", self.devices.dma, DMA_SIZE),
+        ];
+        for (i, (name, offset, size)) in slots.iter().enumerate() {
+            let end = *offset as u32 + *size as u32;
+            if end > mmio_len {
+                return Err(ConfigError { message: format!("device {} at 0x{:02X} runs past the end of mmio (0x{:X} bytes)", name, offset, mmio_len) });
+            }
+            let slot = *offset..end as u16;
+            if slot.start <= IRQ_ENABLE_OFFSET || slot.clone().any(is_video_register) {
+                return Err(ConfigError { message: format!("device {} at 0x{:02X} overlaps the keyboard, irq or video registers", name, offset) });
+            }
+            if let Some((other, _, _)) = slots[..i].iter().find(|(_, o, s)| *o < slot.end && slot.start < *o + *s) {
+                return Err(ConfigError { message: format!("devices {} and {} overlap", other, name) });
+            }
+        }
+        Ok(())
+    }
+
+    // every region, sorted by address
+    pub fn memory_map(&self) -> Vec<MemRange> {
+        let mut regions: Vec<MemRange> = self.regions.iter().map(|r| r.mem_range()).collect();
+        regions.sort_by_key(|r| r.range.start);
+        regions
+    }
+
+    // one past the highest task with a region
+    pub fn tasks(&self) -> usize {
+        self.regions.iter().filter_map(|r| r.task).map(|t| t as usize + 1).max().unwrap_or(0)
+    }
+
+    // where each task's vram starts, by task
+    pub fn task_vram(&self) -> Vec<u16> {
+        (0..self.tasks()).map(|t| self.range(&format!("user_vram_{}", t)).unwrap().start as u16).collect()
+    }
+
+    pub fn range(&self, name: &str) -> Option<Range<u32>> {
+        self.regions.iter().find(|r| r.name() == name).map(|r| r.start as u32..r.end)
+    }
+
+    pub fn device_slots(&self) -> DeviceSlots {
+        DeviceSlots {
+            rtc: self.devices.rtc,
+            audio: self.devices.audio,
+            rng: self.devices.rng,
+            dma: self.devices.dma,
+        }
+    }
+
+    // (path without .dnasm, load address), the way boot_machine takes programs
+    pub fn programs(&self) -> Vec<(String, Option<u16>)> {
+        self.images.iter().map(|image| {
+            let path = self.dir.join(&image.path).to_string_lossy().to_string();
+            let path = path.strip_suffix(".dnasm").unwrap_or(&path).to_string();
+            (path, image.at)
+        }).collect()
+    }
+
+    pub fn font(&self) -> Option<String> {
+        self.machine.font.as_ref().map(|font| self.dir.join(font).to_string_lossy().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shipped() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("machines/default.toml")
+    }
+
+    #[test]
+    fn shipped_config_finds_its_programs() {
+        let config = Config::load(&shipped()).unwrap();
+        assert!(!config.programs().is_empty());
+        for (path, _) in config.programs() {
+            let path = format!("{}.dnasm", path);
+            assert!(Path::new(&path).is_file(), "{} doesn't exist", path);
+        }
+    }
+
+    #[test]
+    fn builtin_is_the_shipped_config() {
+        let builtin = Config::builtin();
+        let loaded = Config::load(Path::new("machines/default.toml")).unwrap();
+        assert_eq!(builtin.programs(), loaded.programs());
+        assert_eq!(builtin.memory_map(), loaded.memory_map());
+    }
+
+    #[test]
+    fn map_reaches_the_last_address() {
+        let map = Config::builtin().memory_map();
+        assert_eq!(map.last().unwrap().range.end, 0x10000);
+        assert!(map.last().unwrap().contains(0xFFFF));
+
+        let short = DEFAULT.replace("end = 0x10000", "end = 0xFFFF");
+        assert!(Config::parse(&short, PathBuf::new()).is_err());
+        let long = DEFAULT.replace("end = 0x10000", "end = 0x10001");
+        assert!(Config::parse(&long, PathBuf::new()).is_err());
+    }
+
+    // shared data and the unmapped top of memory make room for a fifth task, past
+    // the end of the window table
+    #[test]
+    fn more_tasks_than_windows_boot() {
+        let tail = DEFAULT.find("[[region]]\nkind = \"shared_data\"").unwrap();
+        let images = DEFAULT.find("# programs assembled").unwrap();
+        let mut text = DEFAULT[..tail].to_string();
+        let mut start = 0xD800;
+        for (kind, size) in [("user_code", 0x800), ("user_data", 0x200), ("user_heap", 0x600), ("user_vram", 0x1000), ("user_stack", 0x800)] {
+            text.push_str(&format!("[[region]]\nkind = \"{}\"\ntask = 4\nstart = 0x{:X}\nend = 0x{:X}\n\n", kind, start, start + size));
+            start += size;
+        }
+        text.push_str(&DEFAULT[images..].replace("[[image]]\npath = \"../src/shared.dnasm\"\nat = 0xD800\n", ""));
+
+        let config = Config::parse(&text, PathBuf::from("machines")).unwrap();
+        assert_eq!(config.tasks(), 5);
+        let boot = crate::MachineConfig { headless: true, seed: None, font: None, programs: None };
+        let mut vm = crate::boot_machine(&boot, &config).unwrap();
+        for _ in 0..100_000 {
+            vm.step();
+        }
+        vm.render_frame();
+    }
+
+    #[test]
+    fn vram_can_end_at_the_top_of_memory() {
+        let text = DEFAULT
+            .replace("kind = \"vram\"\nstart = 0x2400", "kind = \"unmapped\"\nstart = 0x2400")
+            .replace("end = 0xF800\n\n[[region]]\nkind = \"unmapped\"\nstart = 0xF800", "end = 0xF000\n\n[[region]]\nkind = \"vram\"\nstart = 0xF000");
+        let config = Config::parse(&text, PathBuf::from("machines")).unwrap();
+        assert_eq!(config.range("vram").unwrap(), 0xF000..0x10000);
+        let boot = crate::MachineConfig { headless: true, seed: None, font: None, programs: None };
+        let mut vm = crate::boot_machine(&boot, &config).unwrap();
+        vm.render_frame();
+    }
+
+    #[test]
+    fn mmio_has_to_fit_the_palette() {
+        let small = DEFAULT.replace("start = 0x3400\nend = 0x3800", "start = 0x3400\nend = 0x3600");
+        assert_ne!(small, DEFAULT);
+        let unmapped = small.replace("kind = \"user_code\"\ntask = 0", "kind = \"unmapped\"\nstart = 0x3600\nend = 0x3800\n\n[[region]]\nkind = \"user_code\"\ntask = 0");
+        let err = Config::parse(&unmapped, PathBuf::new()).err().unwrap();
+        assert!(err.message.contains("video registers"), "{}", err.message);
+    }
+}
@@ -1,5 +1,6 @@
 
-use crate::bus::Bus;
+use crate::bus::{Bus, Dest};
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
 
 use crate::binary::{get_bits_lsb, get_bits_msb};
 
@@ -13,10 +14,24 @@ struct DoubleVal<'a> {
 
 #[allow(dead_code)]
 pub struct Flags {
-    carry: bool,
-    sign: bool,
-    zero: bool,
-    overflow: bool,
+    pub carry: bool,
+    pub sign: bool,
+    pub zero: bool,
+    pub overflow: bool,
+}
+
+impl Flags {
+    // carry, sign, zero, overflow from bit 0 up, the same order gfls stores them in
+    pub fn bits(&self) -> u8 {
+        self.carry as u8 | (self.sign as u8) << 1 | (self.zero as u8) << 2 | (self.overflow as u8) << 3
+    }
+
+    pub fn set_bits(&mut self, bits: u8) {
+        self.carry = bits & 1 != 0;
+        self.sign = bits & 2 != 0;
+        self.zero = bits & 4 != 0;
+        self.overflow = bits & 8 != 0;
+    }
 }
 
 #[derive(PartialEq, Debug, Copy, Clone)]
@@ -46,6 +61,25 @@ pub enum CPUExit {
     Halt, // Kill current program; 
     Syscall, // R0-R3 give information re which syscall;
     Fault ( Fault ), // Something went wrong, probably w/ permissions; 
+    Interrupt, // a device raised an enabled irq line; kernel reads mmio irq status to see which
+}
+
+impl CPUExit {
+    // what the kernel finds at 0x12C7 after the trap
+    pub fn id(&self) -> u8 {
+        match self {
+            CPUExit::None => 0b0000,
+            CPUExit::Timer => 0b0001,
+            CPUExit::Halt => 0b0010,
+            CPUExit::Syscall => 0b0011,
+            CPUExit::Fault(f) => match f {
+                Fault::IllegalInstruction => 0b0100,
+                Fault::IllegalMemAccess => 0b0101,
+                Fault::UnknownAction => 0b0110,
+            },
+            CPUExit::Interrupt => 0b0111,
+        }
+    }
 }
 
 
@@ -60,9 +94,10 @@ pub struct Cpu {
     pub access: Access,
     pub instruction_ctr: u16,
     pub instruction_lim: u16,
+    pub cycles: u64, // total instructions executed, drives device timing
 
     pub kernel_trap_address: u16,
-
+    pub last_exit: Option<CPUExit>, // trap into the kernel by the last step, if any (Vm::step clears it)
 
 
 
@@ -82,7 +117,9 @@ impl Cpu {
             access: Access::X,
             instruction_ctr: 0,
             instruction_lim: 100, // allow 50 instructions before returning control
+            cycles: 0,
             kernel_trap_address: trap_addr,
+            last_exit: None,
         }
     }
 
@@ -238,6 +275,11 @@ impl Cpu {
     }
 
     fn double_val<'a>(&'a mut self, mode: u16, reg: u16, mem: &'a mut Bus) -> Result<DoubleVal<'a>, CPUExit> {
+        self.double_val_as(mode, reg, mem, Dest::Modify)
+    }
+
+    // dest is how the op uses a memory destination, see bus::Dest
+    fn double_val_as<'a>(&'a mut self, mode: u16, reg: u16, mem: &'a mut Bus, dest: Dest) -> Result<DoubleVal<'a>, CPUExit> {
         return Ok(match mode {
             0_u16 => {
                 // r2 to r1
@@ -280,7 +322,7 @@ impl Cpu {
 
                 self.increment_pc(2);
 
-                DoubleVal { a: self.memgetmutable(m1, mem)?, b: r2 }
+                DoubleVal { a: self.memgetmutable(m1, mem, dest)?, b: r2 }
 
             },
             0b0011_u16 => {
@@ -329,7 +371,7 @@ impl Cpu {
 
                 self.increment_pc(2); // uses operand -> 3 bytes
 
-                DoubleVal { a: self.memgetmutable(i, mem)?, b: r2 }
+                DoubleVal { a: self.memgetmutable(i, mem, dest)?, b: r2 }
             },
             _ => {println!("Not accounted-for mode"); println!("pc: {:0x}", self.pc); return Err(CPUExit::Fault(Fault::UnknownAction))},
         });
@@ -339,8 +381,7 @@ impl Cpu {
 
 
     fn op_mov(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> {
-        // println!("{}", reg);
-        let DoubleVal { a, b} = self.double_val(mode, reg, mem)?;
+        let DoubleVal { a, b} = self.double_val_as(mode, reg, mem, Dest::Write)?;
         *a = b;
         
         Ok(())
@@ -466,7 +507,7 @@ impl Cpu {
     }
 
     fn op_not(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> {
-        let DoubleVal { a, b} = self.double_val(mode, reg, mem)?;
+        let DoubleVal { a, b} = self.double_val_as(mode, reg, mem, Dest::Write)?;
 
         *a = !b;
 
@@ -475,7 +516,6 @@ impl Cpu {
 
 
     fn op_j(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> {
-        // println!("Jumping from: {:016b}", self.pc);
         self.pc = self.single_val_addr(mode, reg, mem)?;
         Ok(())
     }
@@ -535,7 +575,7 @@ impl Cpu {
     }
 
     fn op_cmp(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> {
-        let DoubleVal { a, b} = self.double_val(mode, reg, mem)?;
+        let DoubleVal { a, b} = self.double_val_as(mode, reg, mem, Dest::Read)?;
         let (result, borrow) = (*a).overflowing_sub(b);
         // *a = result;
         let aclone = (*a).clone();
@@ -562,7 +602,6 @@ impl Cpu {
         };
 
 
-        // println!("SP: {:?}", self.sp);
         let p1 = (pos >> 8) as u8;
         let p2 = pos as u8;
 
@@ -571,10 +610,8 @@ impl Cpu {
 
         self.push(p1, mem)?;
         self.push(p2, mem)?;
-        // println!("SP (from call): 0x{:0x}", self.sp);
 
 
-        // print stack
 
         Ok(self.op_j(mode, reg, mem)?)
     }
@@ -584,11 +621,9 @@ impl Cpu {
         let m2 = self.pop(mem)?;
         let m1 = self.pop(mem)?;
 
-        // println!("m{}", (m1 as u16) << 8 | (m2 as u16));
 
 
         self.pc = (m1 as u16) << 8 | (m2 as u16);
-        // println!("sp{}", self.pc);
         // panic!();
 
         Ok(())
@@ -601,7 +636,6 @@ impl Cpu {
         // 0b0000_0001: get key
         self.increment_pc(1);
 
-        // println!("-----------------------------------------------------------------------------------------------------------------------");
 
         return Err(CPUExit::Syscall);
     }
@@ -610,21 +644,16 @@ impl Cpu {
 
         // self.access = Access::X;
 
-        // self.status();
         let pc2 = self.pop(mem)?;
         let pc1 = self.pop(mem)?;
-        // println!("pc2: {:0x}", pc2);
-        // println!("pc1: {:0x}", pc1);
         
         // panic!();
         let new_pc = (pc1 as u16) << 8 | (pc2 as u16);
-        // println!("new pc: 0x{:x}", new_pc);
         self.pc = new_pc;
 
         self.mode = CPUMode::U;
 
         self.access = Access::X;
-        // println!("-----------------------------------------------------------------------------------------------------------------------");
         Ok(())
 
     }
@@ -816,17 +845,14 @@ impl Cpu {
 
     fn op_ssp(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> {
         self.sp = self.single_val_addr(mode, reg, mem)?;
-        // self.debug(mem);
         // panic!();
 
-        // self.debug(mem);
         Ok(())
 
 
     }
 
     fn op_skip(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> { // skip n byte instructions
-        // println!("Jumping from: {:016b}", self.pc);
         match mode {
             0b0000_u16 => {
                 // r
@@ -860,7 +886,6 @@ impl Cpu {
     }
 
     fn op_gsp(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> {
-        // println!("Jumping from: {:016b}", self.pc);
         let s1 = get_bits_msb(self.sp, 0, 7) as u8;
         let s2 = get_bits_msb(self.sp, 8, 15) as u8;
         match mode {
@@ -957,7 +982,6 @@ impl Cpu {
 
     fn op_dbg(&mut self, mode: u16, reg: u16, mem: &mut Bus) -> Result<(), CPUExit> {
         println!("DEBUG:");
-        // self.status();
         self.debug(mem);
         let val = self.single_val(mode, reg, mem)?;
         println!("Debug num: {}", val);
@@ -983,7 +1007,6 @@ impl Cpu {
         println!("Access: {:?}", self.access);
         println!("Instruction: {:08b}", mem.force_get(self.pc));
         let size = mem.get_size() as i64;
-        // println!("size: {}", size);
         let range = 0;
         let mut start = self.pc as i64 - range;
         if start < 0 {
@@ -1014,23 +1037,11 @@ impl Cpu {
                 self.instruction_ctr -= 2;
             }
 
-            let exit_id: u8 = match exit {
-                CPUExit::None => 0b0000,
-                CPUExit::Timer => {/*println!("Timer exit"); self.status(); */0b0001},
-                CPUExit::Halt => 0b0010,
-                CPUExit::Syscall => 0b0011,
-                CPUExit::Fault(ref f) => match f {
-                    Fault::IllegalInstruction => 0b0100,
-                    Fault::IllegalMemAccess => 0b0101,
-                    Fault::UnknownAction => 0b0110,
-                }
-            };
+            let exit_id: u8 = exit.id();
 
             let pc1: u8 = get_bits_msb(self.pc, 0, 7) as u8;
             let pc2: u8 = get_bits_msb(self.pc, 8, 15) as u8;
 
-            // println!("pc: {:0x}", self.pc);
-            // println!("SP: {:0x}", self.sp);
 
 
             // save exit pc
@@ -1040,7 +1051,6 @@ impl Cpu {
                 Ok(()) => (),
                 Err(e) => {
                     println!("push failed w/ exit {:?}", e);
-                    // self.debug(mem);
                     panic!();
                 }
             };
@@ -1056,11 +1066,9 @@ impl Cpu {
                 Err(_e) => {println!("memset (exit id) failed"); return;}
             };
 
-            // println!("0x125A: {:08b}", mem.force_get(0x125A));
-            //println!("Error: {:?}", exit);
-            //println!("-----------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------");
 
             self.pc = self.kernel_trap_address;
+            self.last_exit = Some(exit);
         }
         else {
             panic!("Tried to handle exit but full halted");
@@ -1077,9 +1085,8 @@ impl Cpu {
         Ok(result)
     }
 
-    fn memgetmutable<'a>(&mut self, address: u16, mem: &'a mut Bus) -> Result<&'a mut u8, CPUExit> {
-        // self.access = Access::R;
-        Ok(mem.get_mutable_ref(address, self.mode, Access::W)?)
+    fn memgetmutable<'a>(&mut self, address: u16, mem: &'a mut Bus, dest: Dest) -> Result<&'a mut u8, CPUExit> {
+        Ok(mem.get_mutable_ref(address, self.mode, dest)?)
     }
 
     fn memgetcore(&mut self, address: u16, mem: &mut Bus) -> Result<u8, CPUExit> {
@@ -1094,8 +1101,17 @@ impl Cpu {
 
     pub fn step(&mut self, mem: &mut Bus) {
         self.access = Access::X;
-        // self.debug(mem);
-        match self.act(mem) {
+        self.cycles += 1;
+
+        // irqs only preempt user code, the kernel runs with them held pending
+        if self.mode == CPUMode::U && mem.irq_pending() {
+            self.handle_exit(CPUExit::Interrupt, mem);
+            return;
+        }
+
+        let result = self.act(mem);
+        let flushed = mem.flush_mmio(); // hand any mmio write from this instruction to its device
+        match result.and(flushed) {
             Ok(()) => (),
             Err(e) => self.handle_exit(e, mem),
         }
@@ -1106,7 +1122,6 @@ impl Cpu {
             CPUMode::K => {
                 self.halted = true;
                 println!("Halting...");
-                // self.debug(mem);
                 Ok(())
             },
             CPUMode::U => return Err(CPUExit::Halt),
@@ -1180,10 +1195,6 @@ impl Cpu {
         let reg = get_bits_msb(instruction, 10, 15);
 
 
-        // println!("\n\n\nInstruction mnemonic: {}", self.convert_instruction(opcode));
-        // println!("Instruction: 0b{:08b}_{:08b}\nPC: 0x{:0x}\nSP: 0x{:0x}\nMode: {:?}\nAccess:{:?}", instruction1, instruction2, self.pc, self.sp, self.mode, self.access);
-        // mem.status();
-        // self.status();
 
 
         if self.mode == CPUMode::U {
@@ -1256,20 +1267,46 @@ impl Cpu {
             self.instruction_ctr += 1;
             if self.instruction_ctr >= self.instruction_lim {
                 self.instruction_ctr = 0;
-                // println!("Timer exiting");
                 return Err(CPUExit::Timer);
             }
         }
 
-        // println!("SP: {:0x}", self.sp);
 
         Ok(())
 
-        // self.status();
         
     }
 
 
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.bytes(&self.regs);
+        out.u8(self.flags.bits());
+        out.u16(self.pc);
+        out.u16(self.sp);
+        out.bool(self.halted);
+        out.bool(self.mode == CPUMode::U);
+        out.u8(match self.access { Access::R => 0, Access::W => 1, Access::X => 2 });
+        out.u16(self.instruction_ctr);
+        out.u16(self.instruction_lim);
+        out.u64(self.cycles);
+    }
+
+    // the trap address comes from the memory map, it stays as booted
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.regs = input.array()?;
+        self.flags.set_bits(input.u8()?);
+        self.pc = input.u16()?;
+        self.sp = input.u16()?;
+        self.halted = input.bool()?;
+        self.mode = if input.bool()? { CPUMode::U } else { CPUMode::K };
+        self.access = match input.u8()? { 0 => Access::R, 1 => Access::W, _ => Access::X };
+        self.instruction_ctr = input.u16()?;
+        self.instruction_lim = input.u16()?;
+        self.cycles = input.u64()?;
+        self.last_exit = None;
+        Ok(())
+    }
+
     pub fn status(&self) {
         print!("Registers: [");
         for i in 0..7 {
@@ -0,0 +1,504 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::binary::parse_number;
+use crate::charset;
+use crate::cpu::{Access, CPUMode};
+use crate::disasm;
+use crate::screenshot;
+use crate::vm::Vm;
+
+
+/*
+ * interactive debugger, driven one command line at a time so it works from a
+ * headless repl (repl) or from the window, which feeds it lines read on another thread.
+ * addresses can be numbers, labels (label, program:label, label+0x4), pc or sp.
+ * an empty line repeats the last command
+ */
+
+const HELP: &str = "\
+s, step [n]              run n instructions (default 1)
+n, next                  step, running a call through to its return
+c, continue [cycles]     run until a breakpoint/watchpoint, halt, or the cycle budget
+                         (5M cycles if none is given without a window, c again to go on)
+rs, rstep [n]            step back n instructions (default 1)
+rc, rcontinue            run backwards to the previous breakpoint/watchpoint
+history                  how far back the vm can go
+b, break [addr]          set a breakpoint, or list them
+db, delete addr          remove a breakpoint
+w, watch [r|w|x addr [len]]  watch reads/writes/execution of len bytes (default 1), or list
+dw, unwatch n            remove watchpoint n
+r, regs                  registers, flags, mode
+set reg value            change r0-r7, pc, sp, or a flag (zero, carry, sign, overflow)
+poke addr value...       write bytes to memory
+x addr [len]             hexdump (default 64 bytes)
+dis [addr] [n]           disassemble n instructions (default 8 from pc)
+bt                       call stack, from return addresses on the stack
+sym name|addr            look up a label or an address
+screenshot file.png      save the screen
+q, quit                  stop the vm";
+
+const DEFAULT_DUMP: u16 = 64;
+const DEFAULT_DISASSEMBLE: usize = 8;
+const MAX_FRAMES: usize = 32;
+// stdin is blocked while the repl runs, so a bare c there can't be stopped by hand
+const DEFAULT_CONTINUE: u64 = 5_000_000;
+
+// what the front-end should do after a command
+pub enum Action {
+    Stay,
+    Run(Option<u64>), // until something stops it, or for at most this many cycles
+    Quit,
+}
+
+struct Watch {
+    first: u16,
+    last: u16,
+    access: Access,
+}
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<Watch>,
+    step_over: Option<u16>, // one-off breakpoint for next
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            step_over: None,
+            last_command: String::new(),
+        }
+    }
+
+    // headless: reads commands from stdin until quit or eof
+    pub fn repl(&mut self, vm: &mut Vm) {
+        println!("Debugger, type help for commands");
+        self.show_location(vm);
+        let stdin = io::stdin();
+        loop {
+            prompt();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            match self.command(vm, &line) {
+                Action::Stay => (),
+                Action::Run(limit) => {
+                    self.run(vm, Some(limit.unwrap_or(DEFAULT_CONTINUE)));
+                },
+                Action::Quit => return,
+            }
+        }
+    }
+
+    // runs until a stop or the cycle limit. true if something stopped it
+    pub fn run(&mut self, vm: &mut Vm, limit: Option<u64>) -> bool {
+        let end = limit.map(|n| vm.cpu.cycles.saturating_add(n));
+        while end.is_none_or(|end| vm.cpu.cycles < end) {
+            vm.step();
+            if self.check(vm) {
+                return true;
+            }
+        }
+        println!("Ran {} cycles", limit.unwrap_or(0));
+        self.show_location(vm);
+        false
+    }
+
+    // window: runs one frame, stopping early if a breakpoint/watchpoint trips or
+    // the cycle count reaches end. true if it stopped
+    pub fn run_frame(&mut self, vm: &mut Vm, end: Option<u64>) -> bool {
+        vm.run_frame_until(|vm| {
+            if self.check(vm) {
+                return true;
+            }
+            if end.is_some_and(|end| vm.cpu.cycles >= end) {
+                self.show_location(vm);
+                return true;
+            }
+            false
+        })
+    }
+
+    // after every instruction while running. prints why it stopped, if it did
+    fn check(&mut self, vm: &mut Vm) -> bool {
+        let pc = vm.cpu.pc;
+        let reason = if vm.cpu.halted {
+            "Halted".to_string()
+        }
+        else if let Some((addr, access)) = vm.mem.take_watch_hit() {
+            format!("Watchpoint: {} {}", if access == Access::R { "read" } else { "write" }, vm.symbols.format(addr))
+        }
+        else if self.step_over == Some(pc) {
+            String::new()
+        }
+        else if self.breakpoints.contains(&pc) {
+            "Breakpoint".to_string()
+        }
+        else if self.watches.iter().any(|w| w.access == Access::X && (w.first..=w.last).contains(&pc)) {
+            "Watchpoint: execute".to_string()
+        }
+        else {
+            return false;
+        };
+
+        self.step_over = None;
+        if !reason.is_empty() {
+            println!("{}", reason);
+        }
+        self.show_location(vm);
+        true
+    }
+
+    fn show_location(&self, vm: &Vm) {
+        let inst = disasm::disassemble(vm.mem.ram(), vm.cpu.pc, &vm.symbols);
+        match vm.symbols.source_line(vm.cpu.pc) {
+            Some(line) => println!("{}: {}    ({})", vm.symbols.format(vm.cpu.pc), inst.text, line),
+            None => println!("{}: {}", vm.symbols.format(vm.cpu.pc), inst.text),
+        }
+    }
+
+    fn sync_watches(&self, vm: &mut Vm) {
+        vm.mem.set_watches(self.watches.iter()
+            .filter(|w| w.access != Access::X) // execution is checked against pc here
+            .map(|w| (w.first, w.last, w.access))
+            .collect());
+    }
+
+    pub fn command(&mut self, vm: &mut Vm, line: &str) -> Action {
+        let mut line = line.trim().to_string();
+        if line.is_empty() {
+            line = self.last_command.clone();
+        }
+        self.last_command = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some(first) = words.first() else {
+            return Action::Stay;
+        };
+
+        match self.run_command(vm, first, &words[1..]) {
+            Ok(action) => action,
+            Err(e) => {
+                println!("{}", e);
+                Action::Stay
+            },
+        }
+    }
+
+    fn run_command(&mut self, vm: &mut Vm, command: &str, args: &[&str]) -> Result<Action, String> {
+        let arg = |n: usize| args.get(n).copied().ok_or("missing argument".to_string());
+
+        match command {
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(Action::Quit),
+
+            "s" | "step" => {
+                let count = match args.first() { Some(n) => number(n)?, None => 1 };
+                for _ in 0..count {
+                    vm.step();
+                    if vm.cpu.halted {
+                        println!("Halted");
+                        break;
+                    }
+                }
+                if let Some((addr, access)) = vm.mem.take_watch_hit() {
+                    println!("Watchpoint: {} {}", if access == Access::R { "read" } else { "write" }, vm.symbols.format(addr));
+                }
+                self.show_location(vm);
+            },
+            "n" | "next" => {
+                let inst = disasm::disassemble(vm.mem.ram(), vm.cpu.pc, &vm.symbols);
+                if vm.mem.ram()[vm.cpu.pc as usize] >> 2 != disasm::OP_CALL {
+                    return self.run_command(vm, "step", &[]);
+                }
+                self.step_over = Some(vm.cpu.pc.wrapping_add(inst.len));
+                return Ok(Action::Run(None));
+            },
+            "c" | "continue" => {
+                let limit = match args.first() { Some(n) => Some(number(n)?), None => None };
+                return Ok(Action::Run(limit));
+            },
+            "rs" | "rstep" => {
+                let count = match args.first() { Some(n) => number(n)?, None => 1 };
+                if !vm.reverse_step(count).map_err(|e| e.message)? {
+                    println!("Reached the start of history (cycle {})", vm.cpu.cycles);
+                }
+                self.show_location(vm);
+            },
+            "rc" | "rcontinue" => {
+                // the instruction that tripped it is the one about to run again
+                let mut reason = String::new();
+                let found = vm.reverse_until(|vm, pc| {
+                    let hit = match vm.mem.take_watch_hit() {
+                        Some((addr, access)) => format!("Watchpoint: {} {}", if access == Access::R { "read" } else { "write" }, vm.symbols.format(addr)),
+                        None if self.breakpoints.contains(&pc) => "Breakpoint".to_string(),
+                        None if self.watches.iter().any(|w| w.access == Access::X && (w.first..=w.last).contains(&pc)) => "Watchpoint: execute".to_string(),
+                        None => return false,
+                    };
+                    reason = hit;
+                    true
+                }).map_err(|e| e.message)?;
+                println!("{}", if found { reason } else { format!("Reached the start of history (cycle {})", vm.cpu.cycles) });
+                self.show_location(vm);
+            },
+            "history" => match vm.history() {
+                Some(history) => println!("Cycle {}, can go back to {}, furthest reached {}, {} checkpoints",
+                    vm.cpu.cycles, history.oldest(), history.present, history.checkpoints.len()),
+                None => println!("History is off"),
+            },
+
+            "b" | "break" => match args.first() {
+                Some(a) => {
+                    let addr = self.address(vm, a)?;
+                    self.breakpoints.insert(addr);
+                    println!("Breakpoint at {}", vm.symbols.format(addr));
+                },
+                None => for addr in &self.breakpoints {
+                    println!("{}", vm.symbols.format(*addr));
+                },
+            },
+            "db" | "delete" => {
+                let addr = self.address(vm, arg(0)?)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("No breakpoint at {}", vm.symbols.format(addr)));
+                }
+            },
+            "w" | "watch" => {
+                if args.is_empty() {
+                    for (i, w) in self.watches.iter().enumerate() {
+                        println!("{}: {:?} {}..={}", i, w.access, vm.symbols.format(w.first), vm.symbols.format(w.last));
+                    }
+                    return Ok(Action::Stay);
+                }
+                let access = match arg(0)? {
+                    "r" => Access::R,
+                    "w" => Access::W,
+                    "x" => Access::X,
+                    other => return Err(format!("Watch r, w or x, not {}", other)),
+                };
+                let first = self.address(vm, arg(1)?)?;
+                let len = match args.get(2) { Some(n) => number(n)?.max(1) as u16, None => 1 };
+                let last = first.saturating_add(len - 1);
+                self.watches.push(Watch { first, last, access });
+                self.sync_watches(vm);
+                println!("Watchpoint {}: {:?} {}..={}", self.watches.len() - 1, access, vm.symbols.format(first), vm.symbols.format(last));
+            },
+            "dw" | "unwatch" => {
+                let n = number(arg(0)?)? as usize;
+                if n >= self.watches.len() {
+                    return Err(format!("No watchpoint {}", n));
+                }
+                self.watches.remove(n);
+                self.sync_watches(vm);
+            },
+
+            "r" | "regs" => self.show_registers(vm),
+            "set" => {
+                let value = self.address(vm, arg(1)?)?;
+                let flag = value != 0;
+                vm.forget_future();
+                match arg(0)? {
+                    "pc" => vm.cpu.pc = value,
+                    "sp" => vm.cpu.sp = value,
+                    "zero" | "z" => vm.cpu.flags.zero = flag,
+                    "carry" | "c" => vm.cpu.flags.carry = flag,
+                    "sign" | "s" => vm.cpu.flags.sign = flag,
+                    "overflow" | "o" => vm.cpu.flags.overflow = flag,
+                    reg => match reg.strip_prefix('r').and_then(|n| n.parse::<usize>().ok()) {
+                        Some(n) if n < 8 => vm.cpu.regs[n] = value as u8,
+                        _ => return Err(format!("Unknown register {}", reg)),
+                    },
+                }
+                self.show_registers(vm);
+            },
+            "poke" => {
+                let addr = self.address(vm, arg(0)?)?;
+                vm.forget_future();
+                for (i, v) in args[1..].iter().enumerate() {
+                    vm.mem.force_set(addr.wrapping_add(i as u16), number(v)? as u8);
+                }
+            },
+
+            "x" => {
+                let addr = self.address(vm, arg(0)?)?;
+                let len = match args.get(1) { Some(n) => number(n)? as u16, None => DEFAULT_DUMP };
+                hexdump(vm, addr, len);
+            },
+            "dis" => {
+                let mut addr = match args.first() { Some(a) => self.address(vm, a)?, None => vm.cpu.pc };
+                let count = match args.get(1) { Some(n) => number(n)? as usize, None => DEFAULT_DISASSEMBLE };
+                for _ in 0..count {
+                    if vm.symbols.describe(addr).is_some_and(|name| !name.contains('+')) {
+                        println!("{}:", vm.symbols.describe(addr).unwrap_or_default());
+                    }
+                    let inst = disasm::disassemble(vm.mem.ram(), addr, &vm.symbols);
+                    let bytes: Vec<String> = (0..inst.len).map(|i| format!("{:02X}", vm.mem.ram()[addr.wrapping_add(i) as usize])).collect();
+                    println!("{} 0x{:04X}  {:<12} {}", if addr == vm.cpu.pc { "=>" } else { "  " }, addr, bytes.join(" "), inst.text);
+                    addr = addr.wrapping_add(inst.len);
+                }
+            },
+            "bt" => self.backtrace(vm),
+            "sym" => {
+                let a = arg(0)?;
+                match vm.symbols.lookup(a) {
+                    Some(addr) => println!("{} = 0x{:04X}", a, addr),
+                    None => {
+                        let addr = self.address(vm, a)?;
+                        match vm.symbols.source_line(addr) {
+                            Some(line) => println!("{}  {}", vm.symbols.format(addr), line),
+                            None => println!("{}", vm.symbols.format(addr)),
+                        }
+                    },
+                }
+            },
+            "screenshot" => {
+                vm.render_frame();
+                screenshot::save_png(&vm.video, Path::new(arg(0)?)).map_err(|e| e.message)?;
+                println!("Saved {}", arg(0)?);
+            },
+
+            other => return Err(format!("Unknown command {}, try help", other)),
+        }
+        Ok(Action::Stay)
+    }
+
+    // number, pc, sp, label, program:label, or any of those + an offset
+    fn address(&self, vm: &Vm, text: &str) -> Result<u16, String> {
+        let (base, offset) = match text.split_once('+') {
+            Some((base, offset)) => (base, number(offset)?),
+            None => (text, 0),
+        };
+        let base = match base {
+            "pc" => vm.cpu.pc,
+            "sp" => vm.cpu.sp,
+            _ => match parse_number(base) {
+                Some(n) => n as u16,
+                None => vm.symbols.lookup(base).ok_or(format!("Unknown or ambiguous label {}", base))?,
+            },
+        };
+        Ok(base.wrapping_add(offset as u16))
+    }
+
+    fn show_registers(&self, vm: &Vm) {
+        let cpu = &vm.cpu;
+        let regs: Vec<String> = cpu.regs.iter().enumerate().map(|(i, r)| format!("r{} {:02X}", i, r)).collect();
+        println!("{}", regs.join("  "));
+        let flag = |set: bool, c: char| if set { c.to_ascii_uppercase() } else { c };
+        println!("pc {}  sp 0x{:04X}  mode {}  flags {}{}{}{}  cycles {}  frame {}",
+            vm.symbols.format(cpu.pc), cpu.sp,
+            if cpu.mode == CPUMode::K { "kernel" } else { "user" },
+            flag(cpu.flags.zero, 'z'), flag(cpu.flags.carry, 'c'), flag(cpu.flags.sign, 's'), flag(cpu.flags.overflow, 'o'),
+            cpu.cycles, vm.frames);
+    }
+
+    // call pushes the return address high byte first and the stack grows down, so each
+    // one sits low byte first above sp. anything there that points just past a call is a frame
+    fn backtrace(&self, vm: &Vm) {
+        let ram = vm.mem.ram();
+        let top = vm.mem.region(vm.cpu.sp).map(|r| r.range().end).unwrap_or(0);
+        println!("#0  {}", vm.symbols.format(vm.cpu.pc));
+
+        let mut frames = 1;
+        let mut at = vm.cpu.sp as u32 + 1;
+        while at + 1 < top && frames < MAX_FRAMES {
+            let ret = (ram[at as usize + 1] as u16) << 8 | ram[at as usize] as u16;
+            if disasm::is_call_before(ram, ret) {
+                println!("#{:<2} {}  (stack 0x{:04X})", frames, vm.symbols.format(ret), at);
+                frames += 1;
+                at += 2;
+            }
+            else {
+                at += 1;
+            }
+        }
+    }
+}
+
+// the debugger alongside the window: commands are typed on stdin and read on a
+// thread of their own, the window polls for them between frames
+pub struct Console {
+    debugger: Debugger,
+    lines: Receiver<String>,
+    running: Option<Option<u64>>, // Some while running, with the cycle to stop at
+}
+
+impl Console {
+    // the vm starts paused so breakpoints can go in before anything runs
+    pub fn spawn(vm: &Vm) -> Self {
+        let (send, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let debugger = Debugger::new();
+        println!("Debugger, type help for commands. Paused, c to run");
+        debugger.show_location(vm);
+        prompt();
+        Self { debugger, lines, running: None }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.running.is_none()
+    }
+
+    // handles whatever's been typed since the last frame. false once the user quits
+    pub fn poll(&mut self, vm: &mut Vm) -> bool {
+        while let Ok(line) = self.lines.try_recv() {
+            match self.debugger.command(vm, &line) {
+                Action::Stay => prompt(),
+                Action::Run(limit) => self.running = Some(limit.map(|n| vm.cpu.cycles.saturating_add(n))),
+                Action::Quit => return false,
+            }
+        }
+        true
+    }
+
+    // true if the frame got cut short, the vm is paused again then
+    pub fn run_frame(&mut self, vm: &mut Vm) -> bool {
+        let Some(end) = self.running else {
+            return true;
+        };
+        if self.debugger.run_frame(vm, end) {
+            self.running = None;
+            prompt();
+            return true;
+        }
+        false
+    }
+}
+
+fn number(text: &str) -> Result<u64, String> {
+    parse_number(text).ok_or(format!("Bad number {}", text))
+}
+
+pub fn prompt() {
+    print!("(dbg) ");
+    let _ = io::stdout().flush();
+}
+
+fn hexdump(vm: &Vm, addr: u16, len: u16) {
+    let ram = vm.mem.ram();
+    let end = addr as u32 + len as u32;
+    let mut line = addr as u32;
+    while line < end.min(0x10000) {
+        let row: Vec<u8> = (line..(line + 16).min(end).min(0x10000)).map(|a| ram[a as usize]).collect();
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        // text in the machine's own character set
+        let text: String = row.iter().map(|b| charset::char_of(*b).unwrap_or('.')).collect();
+        println!("0x{:04X}  {:<47}  {}", line, hex.join(" "), text);
+        line += 16;
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::{VecDeque};
 
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
 
 
 pub struct Mouse {
@@ -15,6 +17,17 @@ impl Mouse {
             y: 0,
         }
     }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.x);
+        out.u8(self.y);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.x = input.u8()?;
+        self.y = input.u8()?;
+        Ok(())
+    }
 }
 
 
@@ -35,6 +48,32 @@ impl Mouse {
  * 
  * 
  */
+
+// key name -> code. names are the character for letters and digits,
+// otherwise up, down, left, right, backspace, enter, escape, space, tab
+pub fn keycode(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return match c {
+            'a'..='z' => Some(c as u8 - b'a' + 1),
+            '1'..='9' => Some(c as u8 - b'1' + 31),
+            '0' => Some(40),
+            _ => None,
+        };
+    }
+    match name {
+        "up" => Some(27),
+        "down" => Some(28),
+        "left" => Some(29),
+        "right" => Some(30),
+        "backspace" => Some(50),
+        "enter" => Some(51),
+        "escape" => Some(52),
+        "space" => Some(53),
+        "tab" => Some(54),
+        _ => None,
+    }
+}
  
 pub struct Keyboard {
     queue: VecDeque<u8>, // the u8 is an identifier for one key/character
@@ -62,6 +101,11 @@ impl Keyboard {
         }
     }
 
+    // true while a key is waiting to be read (inject_key drops keys until then)
+    pub fn pending(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
     pub fn status(&mut self) -> u8 {
         let mut status: u8 = 0b0000_0000;
         if self.queue.len() > 0 {
@@ -76,4 +120,68 @@ impl Keyboard {
     pub fn debug(&mut self) {
         println!("Keys pressed: {:?}", self.queue);
     }
-}
\ No newline at end of file
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.block(&self.queue.iter().copied().collect::<Vec<u8>>());
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.queue = input.block()?.iter().copied().collect();
+        Ok(())
+    }
+}
+
+
+/*
+ * interrupt lines, mapped at mmio + 0x2 / 0x3
+ *
+ * 0x2: status, one bit per pending line. writing a 1 to a bit clears it
+ * 0x3: enable mask. a pending line only interrupts user code when its bit is set
+ *
+ * the cpu checks the lines between user instructions and exits with
+ * CPUExit::Interrupt; kernel code is never interrupted, lines just stay pending
+ */
+
+pub const IRQ_STATUS_OFFSET: u16 = 0x2;
+pub const IRQ_ENABLE_OFFSET: u16 = 0x3;
+
+pub const IRQ_RTC: u8 = 0b0000_0001;
+pub const IRQ_DMA: u8 = 0b0000_0010;
+pub const IRQ_VBLANK: u8 = 0b0000_0100;
+
+pub struct Interrupts {
+    pub status: u8,
+    pub enable: u8,
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Self {
+            status: 0,
+            enable: 0,
+        }
+    }
+
+    pub fn raise(&mut self, line: u8) {
+        self.status |= line;
+    }
+
+    pub fn acknowledge(&mut self, lines: u8) {
+        self.status &= !lines;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.status & self.enable != 0
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.status);
+        out.u8(self.enable);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.status = input.u8()?;
+        self.enable = input.u8()?;
+        Ok(())
+    }
+}
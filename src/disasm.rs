@@ -0,0 +1,136 @@
+use crate::symbols::Symbols;
+
+
+/*
+ * turns machine code back into the assembler's syntax
+ *
+ * byte 1: oooooo mm   opcode, mode high bits
+ * byte 2: mm aaa bbb  mode low bits, first register, second register
+ * then 0-2 operand bytes, depending on the op and mode:
+ *
+ * double ops: rr, rm (r1 <- [r2:r2+1]), mr ([r1:r1+1] <- r2) are 2 bytes,
+ *             ri is 3, rm/mr with an immediate address are 4
+ * single ops: r and m (address in r:r+1) are 2 bytes, i is 3 or 4 (8 or 16 bit
+ *             operand, see single_is_16), m with an immediate address is 4
+ * zero ops:   2 bytes, except nop which the cpu steps over one byte at a time
+ */
+
+pub enum Kind {
+    Zero,
+    Single,
+    Double,
+}
+
+pub struct Instruction {
+    pub len: u16,
+    pub text: String,
+}
+
+pub const OP_CALL: u8 = 0b010_110;
+
+pub fn op_info(opcode: u8) -> Option<(&'static str, Kind)> {
+    Some(match opcode {
+        0b000_000 => ("nop", Kind::Zero),
+        0b000_001 => ("mov", Kind::Double),
+        0b000_010 => ("add", Kind::Double),
+        0b000_011 => ("sub", Kind::Double),
+        0b000_100 => ("mul", Kind::Double),
+        0b000_101 => ("div", Kind::Double),
+        0b000_110 => ("mod", Kind::Double),
+        0b000_111 => ("and", Kind::Double),
+        0b001_000 => ("or", Kind::Double),
+        0b001_001 => ("xor", Kind::Double),
+        0b001_010 => ("not", Kind::Single),
+        0b001_011 => ("jmp", Kind::Single),
+        0b001_100 => ("jz", Kind::Single),
+        0b001_101 => ("jc", Kind::Single),
+        0b001_110 => ("jo", Kind::Single),
+        0b001_111 => ("js", Kind::Single),
+        0b010_000 => ("jnz", Kind::Single),
+        0b010_001 => ("jg", Kind::Single),
+        0b010_010 => ("jl", Kind::Single),
+        0b010_011 => ("cmp", Kind::Double),
+        0b010_100 => ("push", Kind::Single),
+        0b010_101 => ("pop", Kind::Single),
+        0b010_110 => ("call", Kind::Single),
+        0b010_111 => ("ret", Kind::Zero),
+        0b011_000 => ("shl", Kind::Single),
+        0b011_001 => ("shr", Kind::Double),
+        0b011_010 => ("sar", Kind::Single),
+        0b011_011 => ("ssp", Kind::Single),
+        0b011_100 => ("skip", Kind::Single),
+        0b011_101 => ("sys", Kind::Zero),
+        0b011_110 => ("kret", Kind::Zero),
+        0b011_111 => ("gsp", Kind::Single),
+        0b100_000 => ("pnk", Kind::Zero),
+        0b100_001 => ("dbg", Kind::Single),
+        0b100_010 => ("shrw", Kind::Double),
+        0b100_011 => ("gfls", Kind::Single),
+        0b100_100 => ("sfls", Kind::Single),
+        0b100_101 => ("sdb", Kind::Single),
+        0b100_110 => ("andn", Kind::Double),
+        0b100_111 => ("gcu", Kind::Single),
+        0b111_111 => ("hlt", Kind::Zero),
+        _ => return None,
+    })
+}
+
+// single ops whose immediate is an address (same split as the assembler's ops table)
+fn single_is_16(mnemonic: &str) -> bool {
+    matches!(mnemonic, "jmp" | "jz" | "jc" | "jo" | "js" | "jnz" | "jg" | "jl" | "call" | "gsp" | "gfls" | "sfls" | "gcu" | "ssp")
+}
+
+// decodes the instruction at addr. mem is the whole 64K address space
+pub fn disassemble(mem: &[u8], addr: u16, symbols: &Symbols) -> Instruction {
+    let byte = |offset: u16| mem[addr.wrapping_add(offset) as usize];
+    let word = |offset: u16| (byte(offset) as u16) << 8 | byte(offset + 1) as u16;
+
+    let opcode = byte(0) >> 2;
+    let mode = (byte(0) & 0b11) << 2 | byte(1) >> 6;
+    let r1 = (byte(1) >> 3) & 0b111;
+    let r2 = byte(1) & 0b111;
+
+    let Some((mnemonic, kind)) = op_info(opcode) else {
+        return Instruction { len: 1, text: format!(".byte 0x{:02X}", byte(0)) };
+    };
+
+    let (len, operands) = match kind {
+        Kind::Zero if opcode == 0 => (1, String::new()),
+        Kind::Zero => (2, String::new()),
+        Kind::Double => match mode {
+            0b0000 => (2, format!("rr r{}, r{}", r1, r2)),
+            0b0001 => (2, format!("rm r{}, r{}", r1, r2)),
+            0b0010 => (2, format!("mr r{}, r{}", r1, r2)),
+            0b0011 => (3, format!("ri r{}, 0x{:02X}", r1, byte(2))),
+            0b0100 => (4, format!("rm r{}, {}", r1, symbols.format(word(2)))),
+            0b0101 => (4, format!("mr {}, r{}", symbols.format(word(2)), r2)),
+            _ => (2, format!("?{:04b}", mode)),
+        },
+        Kind::Single => match mode {
+            0b0000 => (2, format!("r r{}", r1)),
+            0b0001 => (2, format!("m r{}", r1)),
+            0b0010 if single_is_16(mnemonic) => (4, format!("i {}", symbols.format(word(2)))),
+            0b0010 => (3, format!("i 0x{:02X}", byte(2))),
+            0b0011 => (4, format!("m {}", symbols.format(word(2)))),
+            _ => (2, format!("?{:04b}", mode)),
+        },
+    };
+
+    let text = if operands.is_empty() { mnemonic.to_string() } else { format!("{} {}", mnemonic, operands) };
+    Instruction { len, text }
+}
+
+// just the size of the instruction at addr
+pub fn length(mem: &[u8], addr: u16) -> u16 {
+    disassemble(mem, addr, &Symbols::new()).len
+}
+
+// true if the bytes before a return address are a call that would have pushed it
+pub fn is_call_before(mem: &[u8], ret: u16) -> bool {
+    let is_call = |at: u16, modes: &[u8]| {
+        let b0 = mem[at as usize];
+        let b1 = mem[at.wrapping_add(1) as usize];
+        b0 >> 2 == OP_CALL && modes.contains(&((b0 & 0b11) << 2 | b1 >> 6))
+    };
+    is_call(ret.wrapping_sub(4), &[0b0010]) || is_call(ret.wrapping_sub(2), &[0b0000, 0b0001])
+}
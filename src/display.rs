@@ -0,0 +1,138 @@
+use crate::vc::VideoController;
+
+
+/*
+ * gets the guest framebuffer onto a host window of any size
+ *
+ * integer scaling keeps every guest pixel the same size (crisp, maybe with wide borders),
+ * fit scaling fills as much of the window as the aspect ratio allows. either way the
+ * picture is centred and the rest is letterboxed black.
+ *
+ * the guest pixel each host pixel shows is worked out once per window size/mode
+ * (see Blitter::layout), so a redraw is just lookups and row copies
+ */
+
+// brightness of the scanline gaps with the crt filter, out of 256
+const SCANLINE_LEVEL: u32 = 150;
+const BORDER: u32 = 0xFF000000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Scaling {
+    Integer,
+    Fit,
+}
+
+pub struct DisplayOptions {
+    pub scale: u32, // initial window size, in host pixels per guest pixel
+    pub scaling: Scaling,
+    pub fullscreen: bool,
+    pub crt: bool,
+}
+
+impl DisplayOptions {
+    pub fn new() -> Self {
+        Self {
+            scale: 4,
+            scaling: Scaling::Integer,
+            fullscreen: false,
+            crt: false,
+        }
+    }
+}
+
+// one host row: which guest row it shows (None for letterbox), and whether it's a scanline gap
+#[derive(Clone, Copy)]
+struct Row {
+    guest: Option<usize>,
+    dim: bool,
+}
+
+pub struct Blitter {
+    key: (usize, usize, usize, usize, Scaling, bool), // what the layout was built for
+    cols: Vec<Option<usize>>, // guest x for every host column
+    rows: Vec<Row>,
+}
+
+impl Blitter {
+    pub fn new() -> Self {
+        Self {
+            key: (0, 0, 0, 0, Scaling::Integer, false),
+            cols: Vec::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    // rebuilds the lookup tables, only when something they depend on changed
+    fn layout(&mut self, width: usize, height: usize, out_w: usize, out_h: usize, scaling: Scaling, crt: bool) {
+        let key = (width, height, out_w, out_h, scaling, crt);
+        if key == self.key {
+            return;
+        }
+        self.key = key;
+
+        let fit = (out_w as f64 / width as f64).min(out_h as f64 / height as f64);
+        let scale = match scaling {
+            Scaling::Integer => fit.floor().max(1.0),
+            Scaling::Fit => fit,
+        };
+        let scaled_w = ((width as f64 * scale) as usize).min(out_w);
+        let scaled_h = ((height as f64 * scale) as usize).min(out_h);
+        let left = (out_w - scaled_w) / 2;
+        let top = (out_h - scaled_h) / 2;
+
+        self.cols = (0..out_w)
+            .map(|x| (x >= left && x < left + scaled_w).then(|| (x - left) * width / scaled_w))
+            .collect();
+
+        // the crt filter darkens the last host row of every guest row, if there's room for a gap
+        let gaps = crt && scale >= 2.0;
+        self.rows = (0..out_h)
+            .map(|y| {
+                if y < top || y >= top + scaled_h {
+                    return Row { guest: None, dim: false };
+                }
+                let guest = (y - top) * height / scaled_h;
+                let next = (y + 1 - top) * height / scaled_h;
+                Row { guest: Some(guest), dim: gaps && next != guest }
+            })
+            .collect();
+    }
+
+    // draws the video controller's last frame over the whole out_w x out_h buffer
+    pub fn blit(&mut self, video: &VideoController, out: &mut [u32], out_w: usize, out_h: usize, options: &DisplayOptions) {
+        let (width, height) = (video.mode.width(), video.mode.height());
+        let pixels = &video.pixels;
+        self.layout(width, height, out_w, out_h, options.scaling, options.crt);
+
+        let mut last: Option<(usize, bool)> = None; // row just drawn, to copy instead of redo
+        for (y, row) in self.rows.iter().enumerate() {
+            let (before, rest) = out.split_at_mut(y * out_w);
+            let line = &mut rest[..out_w];
+
+            let Some(guest_y) = row.guest else {
+                line.fill(BORDER);
+                continue;
+            };
+
+            if last == Some((guest_y, row.dim)) {
+                line.copy_from_slice(&before[(y - 1) * out_w..]);
+                continue;
+            }
+            last = Some((guest_y, row.dim));
+
+            let src = &pixels[guest_y * width..(guest_y + 1) * width];
+            for (out_px, col) in line.iter_mut().zip(&self.cols) {
+                *out_px = match col {
+                    Some(x) if row.dim => BORDER | dim(src[*x]),
+                    Some(x) => BORDER | src[*x],
+                    None => BORDER,
+                };
+            }
+        }
+    }
+}
+
+fn dim(rgb: u32) -> u32 {
+    let channel = |shift: u32| (((rgb >> shift) & 0xFF) * SCANLINE_LEVEL / 256) << shift;
+    channel(16) | channel(8) | channel(0)
+}
@@ -0,0 +1,226 @@
+use crate::cpu::CPUMode;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+
+/*
+ * dma controller, mapped at mmio + DMA_OFFSET
+ *
+ * 0x0: control
+ *      bit 0: write 1 to start a transfer, reads 1 while busy
+ *      bit 1: fill mode (writes the fill value instead of copying from source)
+ *      bit 2: as task, check accesses against the user task that started it instead of
+ *             the kernel (the task is latched at start, a context switch doesn't change it)
+ * 0x1: status
+ *      bit 0: busy
+ *      bit 1: done (write 1 to clear)
+ *      bit 2: fault, the transfer hit memory the requester can't touch (write 1 to clear)
+ * 0x2-0x3: source address, big-endian
+ * 0x4-0x5: destination address, big-endian
+ * 0x6-0x7: length, bytes per row, big-endian
+ * 0x8: rows (0 counts as 1)
+ * 0x9: source stride, added to the source for each row (0 repeats the same source row)
+ * 0xA: destination stride, added to the destination for each row
+ * 0xB: fill value
+ *
+ * a transfer runs alongside the cpu, DMA_BYTES_PER_CYCLE bytes per cycle.
+ * the dma irq is raised when it finishes, faulted or not
+ */
+
+pub const DMA_OFFSET: u16 = 0x50;
+pub const DMA_SIZE: u16 = 0x10;
+
+pub const DMA_BYTES_PER_CYCLE: u64 = 4;
+
+const CTRL_START: u8 = 0b0000_0001;
+const CTRL_FILL: u8 = 0b0000_0010;
+const CTRL_AS_TASK: u8 = 0b0000_0100;
+
+const STATUS_BUSY: u8 = 0b0000_0001;
+const STATUS_DONE: u8 = 0b0000_0010;
+const STATUS_FAULT: u8 = 0b0000_0100;
+
+pub enum DmaStep {
+    Copy { src: u16, dst: u16 },
+    Fill { dst: u16, val: u8 },
+}
+
+pub struct Dma {
+    control: u8,
+    status: u8,
+    src: u16,
+    dst: u16,
+    len: u16,
+    rows: u8,
+    src_stride: u8,
+    dst_stride: u8,
+    fill: u8,
+
+    // transfer in progress
+    mode: CPUMode,
+    task: u8, // current task when it started, what user mode accesses are checked against
+    row: u8,
+    col: u16,
+    last_cycles: u64,
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Self {
+            control: 0,
+            status: 0,
+            src: 0,
+            dst: 0,
+            len: 0,
+            rows: 0,
+            src_stride: 0,
+            dst_stride: 0,
+            fill: 0,
+
+            mode: CPUMode::K,
+            task: 0,
+            row: 0,
+            col: 0,
+            last_cycles: 0,
+        }
+    }
+
+    pub fn busy(&self) -> bool {
+        self.status & STATUS_BUSY != 0
+    }
+
+    // mode is what the requester was running as when it wrote the start bit
+    pub fn mode(&self) -> CPUMode {
+        self.mode
+    }
+
+    pub fn task(&self) -> u8 {
+        self.task
+    }
+
+    pub fn read(&self, offset: u16) -> u8 {
+        match offset {
+            0x0 => (self.control & !CTRL_START) | (self.status & STATUS_BUSY),
+            0x1 => self.status,
+            0x2 => (self.src >> 8) as u8,
+            0x3 => self.src as u8,
+            0x4 => (self.dst >> 8) as u8,
+            0x5 => self.dst as u8,
+            0x6 => (self.len >> 8) as u8,
+            0x7 => self.len as u8,
+            0x8 => self.rows,
+            0x9 => self.src_stride,
+            0xA => self.dst_stride,
+            0xB => self.fill,
+            _ => 0,
+        }
+    }
+
+    // task is the current task byte at the time of the write
+    pub fn write(&mut self, offset: u16, val: u8, mode: CPUMode, task: u8) {
+        // registers are locked while a transfer is running
+        if self.busy() && offset != 0x1 {
+            return;
+        }
+        match offset {
+            0x0 => {
+                self.control = val & !CTRL_START;
+                if val & CTRL_START != 0 {
+                    self.start(mode, task);
+                }
+            },
+            0x1 => self.status &= !(val & (STATUS_DONE | STATUS_FAULT)),
+            0x2 => self.src = (self.src & 0x00FF) | ((val as u16) << 8),
+            0x3 => self.src = (self.src & 0xFF00) | val as u16,
+            0x4 => self.dst = (self.dst & 0x00FF) | ((val as u16) << 8),
+            0x5 => self.dst = (self.dst & 0xFF00) | val as u16,
+            0x6 => self.len = (self.len & 0x00FF) | ((val as u16) << 8),
+            0x7 => self.len = (self.len & 0xFF00) | val as u16,
+            0x8 => self.rows = val,
+            0x9 => self.src_stride = val,
+            0xA => self.dst_stride = val,
+            0xB => self.fill = val,
+            _ => (),
+        }
+    }
+
+    fn start(&mut self, mode: CPUMode, task: u8) {
+        self.mode = if self.control & CTRL_AS_TASK != 0 { CPUMode::U } else { mode };
+        self.task = task;
+        self.row = 0;
+        self.col = 0;
+        self.status = STATUS_BUSY;
+    }
+
+    // how many bytes the dma gets to move since the last tick
+    pub fn budget(&mut self, cycles: u64) -> u64 {
+        let elapsed = cycles.saturating_sub(self.last_cycles);
+        self.last_cycles = cycles;
+        if self.busy() { elapsed * DMA_BYTES_PER_CYCLE } else { 0 }
+    }
+
+    // next byte to move, or None once the transfer is finished (call finish then)
+    pub fn next_step(&mut self) -> Option<DmaStep> {
+        if !self.busy() || self.row >= self.rows.max(1) || self.len == 0 {
+            return None;
+        }
+
+        let src = self.src
+            .wrapping_add(self.row as u16 * self.src_stride as u16)
+            .wrapping_add(self.col);
+        let dst = self.dst
+            .wrapping_add(self.row as u16 * self.dst_stride as u16)
+            .wrapping_add(self.col);
+
+        self.col += 1;
+        if self.col == self.len {
+            self.col = 0;
+            self.row += 1;
+        }
+
+        if self.control & CTRL_FILL != 0 {
+            Some(DmaStep::Fill { dst, val: self.fill })
+        }
+        else {
+            Some(DmaStep::Copy { src, dst })
+        }
+    }
+
+    pub fn finish(&mut self, faulted: bool) {
+        self.status = STATUS_DONE | if faulted { STATUS_FAULT } else { 0 };
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.control);
+        out.u8(self.status);
+        out.u16(self.src);
+        out.u16(self.dst);
+        out.u16(self.len);
+        out.u8(self.rows);
+        out.u8(self.src_stride);
+        out.u8(self.dst_stride);
+        out.u8(self.fill);
+        out.bool(self.mode == CPUMode::U);
+        out.u8(self.task);
+        out.u8(self.row);
+        out.u16(self.col);
+        out.u64(self.last_cycles);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.control = input.u8()?;
+        self.status = input.u8()?;
+        self.src = input.u16()?;
+        self.dst = input.u16()?;
+        self.len = input.u16()?;
+        self.rows = input.u8()?;
+        self.src_stride = input.u8()?;
+        self.dst_stride = input.u8()?;
+        self.fill = input.u8()?;
+        self.mode = if input.bool()? { CPUMode::U } else { CPUMode::K };
+        self.task = input.u8()?;
+        self.row = input.u8()?;
+        self.col = input.u16()?;
+        self.last_cycles = input.u64()?;
+        Ok(())
+    }
+}
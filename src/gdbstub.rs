@@ -0,0 +1,630 @@
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::cpu::{Access, CPUExit, CPUMode, Fault};
+use crate::history::HistoryError;
+use crate::vm::Vm;
+
+
+/*
+ * gdb remote serial protocol server, so gdb (or anything else that speaks rsp)
+ * can drive the machine over a local socket. the stub sends its own target.xml:
+ *
+ *   (gdb) target remote :1234       or target remote /tmp/vm.sock
+ *
+ * registers, in gdb's numbering (little endian on the wire):
+ *   0-7   r0-r7   8 bit
+ *   8     pc      16 bit
+ *   9     sp      16 bit
+ *   10    flags   8 bit: carry, sign, zero, overflow from bit 0 up (gfls order)
+ *   11    mode    8 bit: 0 kernel, 1 user
+ *
+ * memory goes straight to the bus with no permission checks or mmio side effects.
+ * Z0/Z1 breakpoints stop before the instruction at the address runs, Z2-Z4
+ * watchpoints after the instruction that touched the memory. every trap into the
+ * kernel (CPUExit) stops too, reported as a signal (see exit_signal);
+ * "monitor exits off" turns that off. bs/bc (reverse-stepi, reverse-continue) go
+ * back through the vm's history, stopping before the instruction that tripped a
+ * breakpoint or watchpoint
+ */
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.dnasm.cpu">
+    <flags id="flags_t" size="1">
+      <field name="carry" start="0" end="0"/>
+      <field name="sign" start="1" end="1"/>
+      <field name="zero" start="2" end="2"/>
+      <field name="overflow" start="3" end="3"/>
+    </flags>
+    <reg name="r0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="r1" bitsize="8" type="uint8"/>
+    <reg name="r2" bitsize="8" type="uint8"/>
+    <reg name="r3" bitsize="8" type="uint8"/>
+    <reg name="r4" bitsize="8" type="uint8"/>
+    <reg name="r5" bitsize="8" type="uint8"/>
+    <reg name="r6" bitsize="8" type="uint8"/>
+    <reg name="r7" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="flags" bitsize="8" type="flags_t"/>
+    <reg name="mode" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// gdb's own signal numbers
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 12;
+const SIGALRM: u8 = 14;
+const SIGIO: u8 = 23;
+
+const REG_PC: usize = 8;
+const REG_SP: usize = 9;
+const REG_FLAGS: usize = 10;
+const REG_MODE: usize = 11;
+const REG_COUNT: usize = 12;
+
+const PACKET_SIZE: usize = 0x4000;
+const POLL_CYCLES: u32 = 10_000; // how often a running machine looks for a ctrl-c
+
+#[derive(Debug)]
+pub struct GdbError {
+    pub message: String,
+}
+
+impl From<io::Error> for GdbError {
+    fn from(e: io::Error) -> Self {
+        GdbError { message: format!("gdb connection: {}", e) }
+    }
+}
+
+// a tcp or unix socket stream
+trait Connection: Read + Write {
+    fn set_nonblocking(&self, on: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, on)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, on: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, on)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WatchKind {
+    Write, // Z2
+    Read, // Z3
+    Access, // Z4
+}
+
+struct Watch {
+    kind: WatchKind,
+    first: u16,
+    last: u16,
+}
+
+// why the machine stopped, as sent back to gdb
+enum Stop {
+    Signal(u8),
+    Breakpoint { hardware: bool },
+    Watch(WatchKind, u16),
+    Halted,
+    HistoryStart, // went back as far as history goes
+}
+
+// what a packet asks of the server loop
+enum Response {
+    Reply(String),
+    Resume { step: bool },
+    Reverse { step: bool },
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    conn: Box<dyn Connection>,
+    pending: Vec<u8>, // received but not yet handled
+    breakpoints: BTreeSet<u16>,
+    hw_breakpoints: BTreeSet<u16>,
+    watches: Vec<Watch>,
+    stop_on_exits: bool,
+    last_stop: String,
+}
+
+impl GdbStub {
+    // waits for one client. addr is a port or host:port for tcp, anything else is a unix socket path
+    pub fn listen(addr: &str) -> Result<Self, GdbError> {
+        let err = |e: io::Error| GdbError { message: format!("couldn't listen on {}: {}", addr, e) };
+
+        let tcp: Option<SocketAddr> = match addr.parse::<u16>() {
+            Ok(port) => Some(([127, 0, 0, 1], port).into()),
+            Err(_) => addr.parse().ok(),
+        };
+
+        let conn: Box<dyn Connection> = match tcp {
+            Some(tcp) => {
+                let listener = TcpListener::bind(tcp).map_err(err)?;
+                println!("Waiting for gdb on {}", listener.local_addr().map_err(err)?);
+                let (stream, _) = listener.accept().map_err(err)?;
+                let _ = stream.set_nodelay(true); // packets are tiny and strictly back and forth
+                Box::new(stream)
+            },
+            #[cfg(unix)]
+            None => {
+                let listener = UnixListener::bind(addr).map_err(err)?;
+                println!("Waiting for gdb on {}", addr);
+                let (stream, _) = listener.accept().map_err(err)?;
+                let _ = std::fs::remove_file(addr); // only one client, so the path isn't needed anymore
+                Box::new(stream)
+            },
+            #[cfg(not(unix))]
+            None => return Err(GdbError { message: format!("{} isn't a port or host:port", addr) }),
+        };
+        println!("gdb connected");
+
+        Ok(Self {
+            conn,
+            pending: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            hw_breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            stop_on_exits: true,
+            last_stop: format!("S{:02x}", SIGTRAP),
+        })
+    }
+
+    // handles packets until gdb detaches, kills the target or hangs up
+    pub fn serve(&mut self, vm: &mut Vm) -> Result<(), GdbError> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(vm, &packet) {
+                Response::Reply(reply) => self.send(&reply)?,
+                Response::Resume { step } => {
+                    let Some(stop) = self.resume(vm, step)? else {
+                        break; // hung up mid-run
+                    };
+                    self.last_stop = self.stop_reply(vm, &stop);
+                    let reply = self.last_stop.clone();
+                    self.send(&reply)?;
+                },
+                Response::Reverse { step } => match self.reverse(vm, step) {
+                    Ok(stop) => {
+                        self.last_stop = self.stop_reply(vm, &stop);
+                        let reply = self.last_stop.clone();
+                        self.send(&reply)?;
+                    },
+                    Err(e) => {
+                        println!("gdb: {}", e.message);
+                        self.send("E01")?;
+                    },
+                },
+                Response::Detach => {
+                    self.send("OK")?;
+                    break;
+                },
+                Response::Kill => break,
+            }
+        }
+        println!("gdb detached after {} cycles", vm.cpu.cycles);
+        Ok(())
+    }
+
+    // next packet body, acked. None once the client hangs up.
+    // a lone ctrl-c (0x03) comes back as a packet of its own
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // acks and noise between packets
+            let start = self.pending.iter().position(|b| *b == b'$' || *b == 0x03).unwrap_or(self.pending.len());
+            self.pending.drain(..start);
+
+            if self.pending.first() == Some(&0x03) {
+                self.pending.remove(0);
+                return Ok(Some(vec![0x03]));
+            }
+
+            if let Some(end) = self.pending.iter().position(|b| *b == b'#') && self.pending.len() >= end + 3 {
+                let body = self.pending[1..end].to_vec();
+                let sent = std::str::from_utf8(&self.pending[end + 1..end + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok());
+                self.pending.drain(..end + 3);
+                if sent == Some(checksum(&body)) {
+                    self.conn.write_all(b"+")?;
+                    return Ok(Some(body));
+                }
+                self.conn.write_all(b"-")?; // asks for it again
+                continue;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = self.conn.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.pending.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn send(&mut self, body: &str) -> io::Result<()> {
+        // $ # } * are escaped as } then the byte xor 0x20
+        let mut escaped = Vec::with_capacity(body.len());
+        for b in body.bytes() {
+            if matches!(b, b'$' | b'#' | b'}' | b'*') {
+                escaped.extend_from_slice(&[b'}', b ^ 0x20]);
+            }
+            else {
+                escaped.push(b);
+            }
+        }
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(format!("#{:02x}", checksum(&escaped)).as_bytes());
+        self.conn.write_all(&packet)?;
+        self.conn.flush()
+    }
+
+    fn handle(&mut self, vm: &mut Vm, packet: &[u8]) -> Response {
+        let text = String::from_utf8_lossy(packet);
+        let (command, args) = text.split_at(text.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = |s: &str| Response::Reply(s.to_string());
+
+        match command {
+            "?" => Response::Reply(self.last_stop.clone()),
+            "\u{3}" => reply(""), // ctrl-c while already stopped
+
+            "g" => Response::Reply((0..REG_COUNT).map(|n| read_register(vm, n)).collect()),
+            "G" => {
+                let mut at = 0;
+                for n in 0..REG_COUNT {
+                    let width = register_size(n) * 2;
+                    let Some(value) = args.get(at..at + width).and_then(from_le_hex) else {
+                        return reply("E01");
+                    };
+                    vm.forget_future();
+                    write_register(vm, n, value);
+                    at += width;
+                }
+                reply("OK")
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REG_COUNT => Response::Reply(read_register(vm, n)),
+                _ => reply("E01"),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, v)| Some((usize::from_str_radix(n, 16).ok()?, from_le_hex(v)?)));
+                match parsed {
+                    Some((n, value)) if n < REG_COUNT => {
+                        vm.forget_future();
+                        write_register(vm, n, value);
+                        reply("OK")
+                    },
+                    _ => reply("E01"),
+                }
+            },
+
+            "m" => match parse_range(args) {
+                Some((addr, len)) if len <= PACKET_SIZE / 2 => Response::Reply((0..len)
+                    .map(|i| format!("{:02x}", vm.mem.force_get(addr.wrapping_add(i as u16))))
+                    .collect()),
+                _ => reply("E01"),
+            },
+            "M" => {
+                let Some((range, data)) = args.split_once(':') else {
+                    return reply("E01");
+                };
+                match (parse_range(range), from_hex(data)) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+                        vm.forget_future();
+                        for (i, byte) in bytes.iter().enumerate() {
+                            vm.mem.force_set(addr.wrapping_add(i as u16), *byte);
+                        }
+                        reply("OK")
+                    },
+                    _ => reply("E01"),
+                }
+            },
+
+            "Z" | "z" => self.set_point(vm, command == "Z", args),
+
+            "c" | "s" | "C" | "S" => {
+                // C/S carry a signal to deliver, which means nothing to this cpu
+                let addr = if command == "c" || command == "s" { args } else { args.split_once(';').map_or("", |(_, a)| a) };
+                if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                    vm.cpu.pc = addr;
+                }
+                Response::Resume { step: command.eq_ignore_ascii_case("s") }
+            },
+            "b" if args == "s" || args == "c" => Response::Reverse { step: args == "s" },
+            "v" if args == "Cont?" => reply("vCont;c;C;s;S"),
+            "v" if args.starts_with("Cont;") => {
+                // one thread, so the first action is the one that applies
+                match args["Cont;".len()..].chars().next() {
+                    Some('c' | 'C') => Response::Resume { step: false },
+                    Some('s' | 'S') => Response::Resume { step: true },
+                    _ => reply("E01"),
+                }
+            },
+            "D" => Response::Detach,
+            "k" => Response::Kill,
+
+            "q" | "Q" => self.query(vm, &text),
+            "H" | "T" => reply("OK"), // one thread, always alive
+            _ => reply(""), // unsupported
+        }
+    }
+
+    fn query(&mut self, vm: &mut Vm, text: &str) -> Response {
+        let reply = |s: &str| Response::Reply(s.to_string());
+        if text.starts_with("qSupported") {
+            return Response::Reply(format!("PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;vContSupported+;ReverseStep+;ReverseContinue+", PACKET_SIZE));
+        }
+        if let Some(range) = text.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = range.split_once(',')
+                .and_then(|(o, l)| Some((usize::from_str_radix(o, 16).ok()?, usize::from_str_radix(l, 16).ok()?))) else {
+                return reply("E01");
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = (start + len).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { "m" } else { "l" };
+            return Response::Reply(format!("{}{}", more, &TARGET_XML[start..end]));
+        }
+        if let Some(hex) = text.strip_prefix("qRcmd,") {
+            let command = from_hex(hex).map(|b| String::from_utf8_lossy(&b).trim().to_string()).unwrap_or_default();
+            let output = self.monitor(vm, &command);
+            return Response::Reply(output.bytes().map(|b| format!("{:02x}", b)).collect());
+        }
+        match text {
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "qSymbol::" => reply("OK"),
+            _ => reply(""),
+        }
+    }
+
+    // monitor <command> from gdb, returns what to print
+    fn monitor(&mut self, vm: &mut Vm, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["exits", "on"] => {
+                self.stop_on_exits = true;
+                "Stopping on every trap into the kernel\n".to_string()
+            },
+            ["exits", "off"] => {
+                self.stop_on_exits = false;
+                "Not stopping on traps into the kernel\n".to_string()
+            },
+            ["sym", name] => match vm.symbols.lookup(name) {
+                Some(addr) => format!("{} = 0x{:04X}\n", name, addr),
+                None => format!("Unknown or ambiguous label {}\n", name),
+            },
+            ["where"] => format!("{}, {} cycles\n", vm.symbols.format(vm.cpu.pc), vm.cpu.cycles),
+            _ => "monitor commands: exits on|off, sym <label>, where\n".to_string(),
+        }
+    }
+
+    // Z/z type,addr,kind
+    fn set_point(&mut self, vm: &mut Vm, insert: bool, args: &str) -> Response {
+        let mut parts = args.split([',', ';']);
+        let (Some(kind), Some(addr), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+            return Response::Reply("E01".to_string());
+        };
+        let (Ok(addr), Ok(len)) = (u16::from_str_radix(addr, 16), u16::from_str_radix(len, 16)) else {
+            return Response::Reply("E01".to_string());
+        };
+
+        let watch = match kind {
+            "0" | "1" => {
+                let set = if kind == "0" { &mut self.breakpoints } else { &mut self.hw_breakpoints };
+                if insert { set.insert(addr); } else { set.remove(&addr); }
+                return Response::Reply("OK".to_string());
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Response::Reply(String::new()),
+        };
+
+        let last = addr.saturating_add(len.max(1) - 1);
+        if insert {
+            self.watches.push(Watch { kind: watch, first: addr, last });
+        }
+        else if let Some(i) = self.watches.iter().position(|w| w.kind == watch && w.first == addr && w.last == last) {
+            self.watches.remove(i);
+        }
+
+        // the bus only knows reads and writes, an access watch is one of each
+        vm.mem.set_watches(self.watches.iter()
+            .flat_map(|w| {
+                let read = (w.kind != WatchKind::Write).then_some((w.first, w.last, Access::R));
+                let write = (w.kind != WatchKind::Read).then_some((w.first, w.last, Access::W));
+                read.into_iter().chain(write)
+            })
+            .collect());
+        Response::Reply("OK".to_string())
+    }
+
+    // steps once, or runs until something stops it or gdb sends a ctrl-c.
+    // None if the client hung up meanwhile
+    fn resume(&mut self, vm: &mut Vm, step: bool) -> io::Result<Option<Stop>> {
+        vm.mem.take_watch_hit();
+
+        if step {
+            vm.step();
+            return Ok(Some(self.stop_reason(vm).unwrap_or(Stop::Signal(SIGTRAP))));
+        }
+
+        loop {
+            for _ in 0..POLL_CYCLES {
+                vm.step();
+                if let Some(stop) = self.stop_reason(vm) {
+                    return Ok(Some(stop));
+                }
+            }
+
+            self.conn.set_nonblocking(true)?;
+            let mut chunk = [0u8; 256];
+            let read = self.conn.read(&mut chunk);
+            self.conn.set_nonblocking(false)?;
+            match read {
+                Ok(0) => return Ok(None),
+                Ok(n) => self.pending.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+            if let Some(i) = self.pending.iter().position(|b| *b == 0x03) {
+                self.pending.remove(i);
+                return Ok(Some(Stop::Signal(SIGINT)));
+            }
+        }
+    }
+
+    // after every instruction while running
+    fn stop_reason(&mut self, vm: &mut Vm) -> Option<Stop> {
+        if vm.cpu.halted {
+            return Some(Stop::Halted);
+        }
+        if let Some((addr, access)) = vm.mem.take_watch_hit() {
+            return Some(Stop::Watch(self.watch_kind(addr, access), addr));
+        }
+        if let Some(exit) = vm.cpu.last_exit.take() && self.stop_on_exits {
+            return Some(Stop::Signal(exit_signal(&exit)));
+        }
+        if self.breakpoints.contains(&vm.cpu.pc) {
+            return Some(Stop::Breakpoint { hardware: false });
+        }
+        if self.hw_breakpoints.contains(&vm.cpu.pc) {
+            return Some(Stop::Breakpoint { hardware: true });
+        }
+        None
+    }
+
+    // the watch the bus tripped on
+    fn watch_kind(&self, addr: u16, access: Access) -> WatchKind {
+        self.watches.iter()
+            .find(|w| (w.first..=w.last).contains(&addr) && match w.kind {
+                WatchKind::Write => access == Access::W,
+                WatchKind::Read => access == Access::R,
+                WatchKind::Access => true,
+            })
+            .map_or(WatchKind::Access, |w| w.kind)
+    }
+
+    // bs goes back one instruction, bc to the last one that hit a breakpoint or
+    // watchpoint. exits don't stop it
+    fn reverse(&mut self, vm: &mut Vm, step: bool) -> Result<Stop, HistoryError> {
+        let mut stop = None;
+        let found = vm.reverse_until(|vm, pc| {
+            let hit = match vm.mem.take_watch_hit() {
+                Some((addr, access)) => Stop::Watch(self.watch_kind(addr, access), addr),
+                None if self.breakpoints.contains(&pc) => Stop::Breakpoint { hardware: false },
+                None if self.hw_breakpoints.contains(&pc) => Stop::Breakpoint { hardware: true },
+                None if step => Stop::Signal(SIGTRAP),
+                None => return false,
+            };
+            stop = Some(hit);
+            true
+        })?;
+        Ok(if found { stop.unwrap() } else { Stop::HistoryStart })
+    }
+
+    fn stop_reply(&self, vm: &Vm, stop: &Stop) -> String {
+        // pc and sp ride along so gdb doesn't have to ask for them
+        let expedited = format!("{:02x}:{};{:02x}:{};", REG_PC, read_register(vm, REG_PC), REG_SP, read_register(vm, REG_SP));
+        match stop {
+            Stop::Halted => "W00".to_string(),
+            Stop::HistoryStart => format!("T{:02x}{}replaylog:begin;", SIGTRAP, expedited),
+            Stop::Signal(signal) => format!("T{:02x}{}", signal, expedited),
+            Stop::Breakpoint { hardware } => format!("T{:02x}{}{}:;", SIGTRAP, expedited, if *hardware { "hwbreak" } else { "swbreak" }),
+            Stop::Watch(kind, addr) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}{}:{:04x};", SIGTRAP, expedited, name, addr)
+            },
+        }
+    }
+}
+
+// the signal gdb shows for each way into the kernel
+fn exit_signal(exit: &CPUExit) -> u8 {
+    match exit {
+        CPUExit::None => SIGTRAP,
+        CPUExit::Timer => SIGALRM,
+        CPUExit::Halt => SIGABRT,
+        CPUExit::Syscall => SIGSYS,
+        CPUExit::Fault(Fault::IllegalMemAccess) => SIGSEGV,
+        CPUExit::Fault(Fault::IllegalInstruction | Fault::UnknownAction) => SIGILL,
+        CPUExit::Interrupt => SIGIO,
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    if n == REG_PC || n == REG_SP { 2 } else { 1 }
+}
+
+fn read_register(vm: &Vm, n: usize) -> String {
+    let cpu = &vm.cpu;
+    let value = match n {
+        0..=7 => cpu.regs[n] as u16,
+        REG_PC => cpu.pc,
+        REG_SP => cpu.sp,
+        REG_FLAGS => cpu.flags.bits() as u16,
+        REG_MODE => (cpu.mode == CPUMode::U) as u16,
+        _ => 0,
+    };
+    (0..register_size(n)).map(|i| format!("{:02x}", (value >> (i * 8)) as u8)).collect()
+}
+
+fn write_register(vm: &mut Vm, n: usize, value: u16) {
+    let cpu = &mut vm.cpu;
+    match n {
+        0..=7 => cpu.regs[n] = value as u8,
+        REG_PC => cpu.pc = value,
+        REG_SP => cpu.sp = value,
+        REG_FLAGS => cpu.flags.set_bits(value as u8),
+        REG_MODE => cpu.mode = if value & 1 != 0 { CPUMode::U } else { CPUMode::K },
+        _ => (),
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// little endian register value
+fn from_le_hex(text: &str) -> Option<u16> {
+    let bytes = from_hex(text)?;
+    if bytes.is_empty() || bytes.len() > 2 {
+        return None;
+    }
+    Some(bytes.iter().rev().fold(0u16, |value, b| value << 8 | *b as u16))
+}
+
+// addr,len
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
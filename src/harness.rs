@@ -0,0 +1,251 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::binary::parse_number;
+use crate::device;
+use crate::screenshot;
+use crate::snapshot;
+use crate::vm::Vm;
+
+
+/*
+ * end-to-end scenarios: boot headless, feed keys in at set cycles, then check the
+ * screen against a golden png and peek at ram/registers. one directive per line,
+ * # starts a comment, numbers are decimal or 0x hex
+ *
+ * program <path> [addr]    assemble <path>.dnasm at addr. none at all boots the os
+ * snapshot <file>          start from a save state (relative to the scenario file)
+ *                          instead of cycle 0. cycles stay counted from boot
+ * cycles <n>               how long to run (stops early if the cpu halts)
+ * key <cycle> <key>        key name (see device::keycode) or raw code, injected once
+ *                          the cycle is reached and the keyboard has room for it
+ * image <file.png>         golden screen, relative to the scenario file
+ * ram <addr> <value>       expected byte
+ * reg <r0-r7|pc|sp> <value>
+ * replay                   record the run too, then replay the recording from the
+ *                          start; the replayed run has to end with the same ram
+ *
+ * a screen mismatch writes <image>.actual.png and <image>.diff.png next to the
+ * golden, matching pixels dimmed and differing ones red
+ */
+
+const DEFAULT_CYCLES: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub struct ScenarioError {
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reg {
+    R(usize),
+    Pc,
+    Sp,
+}
+
+pub struct Scenario {
+    pub programs: Vec<(String, Option<u16>)>,
+    pub snapshot: Option<PathBuf>,
+    pub cycles: u64,
+    pub keys: Vec<(u64, u8)>,
+    pub image: Option<PathBuf>,
+    pub ram: Vec<(u16, u8)>,
+    pub regs: Vec<(Reg, u16)>,
+    pub replay: bool,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, ScenarioError> {
+        let text = fs::read_to_string(path).map_err(|e| ScenarioError {
+            message: format!("couldn't read {}: {}", path.display(), e),
+        })?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        let mut scenario = Scenario {
+            programs: Vec::new(),
+            snapshot: None,
+            cycles: DEFAULT_CYCLES,
+            keys: Vec::new(),
+            image: None,
+            ram: Vec::new(),
+            regs: Vec::new(),
+            replay: false,
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |what: &str| ScenarioError {
+                message: format!("{}:{}: {}", path.display(), i + 1, what),
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let arg = |n: usize| words.get(n).copied().ok_or_else(|| err("missing argument"));
+            let num = |n: usize, max: u64| arg(n).and_then(|w| parse_number(w)
+                .filter(|v| *v <= max)
+                .ok_or_else(|| err(&format!("bad number {}", w))));
+
+            match words[0] {
+                "program" => {
+                    let addr = if words.len() > 2 { Some(num(2, 0xFFFF)? as u16) } else { None };
+                    scenario.programs.push((arg(1)?.to_string(), addr));
+                },
+                "snapshot" => scenario.snapshot = Some(dir.join(arg(1)?)),
+                "cycles" => scenario.cycles = num(1, u64::MAX)?,
+                "key" => {
+                    let cycle = num(1, u64::MAX)?;
+                    let key = arg(2)?;
+                    let code = match device::keycode(key) {
+                        Some(code) => code,
+                        None => num(2, 0xFF)? as u8,
+                    };
+                    scenario.keys.push((cycle, code));
+                },
+                "image" => scenario.image = Some(dir.join(arg(1)?)),
+                "ram" => scenario.ram.push((num(1, 0xFFFF)? as u16, num(2, 0xFF)? as u8)),
+                "reg" => {
+                    let reg = match arg(1)? {
+                        "pc" => Reg::Pc,
+                        "sp" => Reg::Sp,
+                        r => match r.strip_prefix('r').and_then(|n| n.parse::<usize>().ok()) {
+                            Some(n) if n < 8 => Reg::R(n),
+                            _ => return Err(err(&format!("unknown register {}", r))),
+                        },
+                    };
+                    let max = if let Reg::R(_) = reg { 0xFF } else { 0xFFFF };
+                    scenario.regs.push((reg, num(2, max)? as u16));
+                },
+                "replay" => scenario.replay = true,
+                other => return Err(err(&format!("unknown directive {}", other))),
+            }
+        }
+
+        scenario.keys.sort_by_key(|(cycle, _)| *cycle);
+        Ok(scenario)
+    }
+
+    // programs for boot_machine, None means the os
+    pub fn programs(&self) -> Option<Vec<(String, Option<u16>)>> {
+        if self.programs.is_empty() { None } else { Some(self.programs.clone()) }
+    }
+
+    // runs a booted vm through the scenario. with bless the screen is saved as the
+    // new golden image instead of being compared
+    pub fn run(&self, vm: &mut Vm, bless: bool) -> Result<(), ScenarioError> {
+        if let Some(path) = &self.snapshot {
+            snapshot::read_file(&path.to_string_lossy())
+                .and_then(|data| vm.restore(&data))
+                .map_err(|e| ScenarioError { message: format!("{}: {}", path.display(), e.message) })?;
+        }
+
+        if self.replay {
+            vm.start_recording();
+        }
+        let mut keys = self.keys.iter().peekable();
+        while !vm.cpu.halted && vm.cpu.cycles < self.cycles {
+            if let Some((cycle, code)) = keys.peek() && vm.cpu.cycles >= *cycle && !vm.mem.key_pending() {
+                vm.inject_key(*code);
+                keys.next();
+            }
+            vm.step();
+        }
+        let recording = vm.stop_recording();
+
+        let mut failures: Vec<String> = Vec::new();
+        if keys.peek().is_some() {
+            failures.push(format!("{} key(s) never got injected", keys.count()));
+        }
+
+        for (addr, expected) in &self.ram {
+            let actual = vm.mem.force_get(*addr);
+            if actual != *expected {
+                failures.push(format!("ram {:04X}: expected {:02X}, got {:02X}", addr, expected, actual));
+            }
+        }
+
+        for (reg, expected) in &self.regs {
+            let actual = match reg {
+                Reg::R(n) => vm.cpu.regs[*n] as u16,
+                Reg::Pc => vm.cpu.pc,
+                Reg::Sp => vm.cpu.sp,
+            };
+            if actual != *expected {
+                failures.push(format!("{:?}: expected {:04X}, got {:04X}", reg, expected, actual));
+            }
+        }
+
+        if let Some(image) = &self.image {
+            // whatever's in vram when the run stops, same as --screenshot
+            vm.render_frame();
+            if bless {
+                screenshot::save_png(&vm.video, image).map_err(|e| ScenarioError { message: e.message })?;
+                println!("Blessed {}", image.display());
+            }
+            else if let Some(failure) = compare_screen(vm, image) {
+                failures.push(failure);
+            }
+        }
+
+        // done last, replaying winds the machine back to the start and runs it again
+        if let Some(recording) = recording {
+            vm.start_replay(&recording).map_err(|e| ScenarioError { message: e.message })?;
+            while !vm.cpu.halted && vm.cpu.cycles < recording.end_cycle {
+                vm.step();
+            }
+            if vm.ram_hash() != recording.ram_hash {
+                failures.push(format!("replay: ram hash {:016X} at cycle {}, the recorded run ended with {:016X} at cycle {}",
+                    vm.ram_hash(), vm.cpu.cycles, recording.ram_hash, recording.end_cycle));
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(ScenarioError { message: failures.join("\n") })
+        }
+    }
+}
+
+// None if the screen matches the golden image, otherwise what went wrong
+fn compare_screen(vm: &Vm, image: &Path) -> Option<String> {
+    let width = vm.video.mode.width() as u32;
+    let height = vm.video.mode.height() as u32;
+    let actual = vm.video.rgba();
+
+    let (golden_w, golden_h, golden) = match screenshot::read_rgba(image) {
+        Ok(g) => g,
+        Err(e) => return Some(format!("{} (run with --bless to create it)", e.message)),
+    };
+
+    let actual_path = image.with_extension("actual.png");
+    let _ = screenshot::write_rgba(&actual_path, width, height, &actual);
+
+    if (golden_w, golden_h) != (width, height) {
+        return Some(format!("screen is {}x{}, golden {} is {}x{}, see {}",
+            width, height, image.display(), golden_w, golden_h, actual_path.display()));
+    }
+
+    let mut wrong = 0;
+    let mut diff = Vec::with_capacity(actual.len());
+    for (a, g) in actual.chunks(4).zip(golden.chunks(4)) {
+        if a == g {
+            diff.extend_from_slice(&[a[0] / 4, a[1] / 4, a[2] / 4, 0xFF]);
+        }
+        else {
+            wrong += 1;
+            diff.extend_from_slice(&[0xFF, 0x00, 0x00, 0xFF]);
+        }
+    }
+
+    if wrong == 0 {
+        let _ = fs::remove_file(&actual_path);
+        return None;
+    }
+
+    let diff_path = image.with_extension("diff.png");
+    let _ = screenshot::write_rgba(&diff_path, width, height, &diff);
+    Some(format!("{} pixel(s) differ from {}, see {} and {}",
+        wrong, image.display(), actual_path.display(), diff_path.display()))
+}
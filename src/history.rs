@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+
+/*
+ * reverse execution. while history is on, the vm snapshots itself every
+ * CHECKPOINT_CYCLES and logs every key that comes in (the rtc logs host clock
+ * readings the same way). going back to an earlier cycle restores the last
+ * checkpoint at or before it and runs forward again, feeding the logged input back
+ * in at the same cycles, so it lands on exactly the state it had the first time.
+ *
+ * running forward from the past keeps feeding the log until it catches up with
+ * present, the furthest cycle reached. changing anything on the way (a new key, a
+ * register, memory) makes a different future, the logged one is thrown away
+ */
+
+pub const CHECKPOINT_CYCLES: u64 = 100_000;
+pub const MAX_CHECKPOINTS: usize = 64; // a bit over 6 seconds of virtual time
+
+#[derive(Debug)]
+pub struct HistoryError {
+    pub message: String,
+}
+
+pub struct History {
+    pub checkpoints: VecDeque<(u64, Vec<u8>)>, // (cycle, snapshot), oldest first
+    pub keys: Vec<(u64, u8)>, // injected since the oldest checkpoint
+    pub present: u64,
+}
+
+impl History {
+    pub fn new(cycle: u64, snapshot: Vec<u8>) -> Self {
+        Self {
+            checkpoints: VecDeque::from([(cycle, snapshot)]),
+            keys: Vec::new(),
+            present: cycle,
+        }
+    }
+
+    pub fn oldest(&self) -> u64 {
+        self.checkpoints.front().map_or(self.present, |(cycle, _)| *cycle)
+    }
+
+    pub fn checkpoint_due(&self, cycle: u64) -> bool {
+        self.checkpoints.back().is_none_or(|(last, _)| cycle >= last + CHECKPOINT_CYCLES)
+    }
+
+    pub fn add_checkpoint(&mut self, cycle: u64, snapshot: Vec<u8>) {
+        self.checkpoints.push_back((cycle, snapshot));
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+            let oldest = self.oldest();
+            self.keys.retain(|(c, _)| *c >= oldest);
+        }
+    }
+
+    // index of the newest checkpoint at or before cycle
+    pub fn checkpoint_at(&self, cycle: u64) -> Result<usize, HistoryError> {
+        if cycle > self.present {
+            return Err(HistoryError { message: format!("cycle {} hasn't been reached yet (at most {})", cycle, self.present) });
+        }
+        match self.checkpoints.iter().rposition(|(c, _)| *c <= cycle) {
+            Some(idx) => Ok(idx),
+            None => Err(HistoryError { message: format!("no history before cycle {}", self.oldest()) }),
+        }
+    }
+
+    // logged keys from the checkpoint on, for running that stretch again
+    pub fn keys_from(&self, cycle: u64) -> VecDeque<(u64, u8)> {
+        self.keys.iter().filter(|(c, _)| *c >= cycle).copied().collect()
+    }
+
+    // cycle is the new present, anything logged after it didn't happen
+    pub fn forget_after(&mut self, cycle: u64) {
+        self.checkpoints.retain(|(c, _)| *c <= cycle);
+        self.keys.retain(|(c, _)| *c < cycle);
+        self.present = cycle;
+    }
+}
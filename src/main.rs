@@ -6,29 +6,66 @@ mod vm;
 mod binary;
 mod device;
 mod assembler;
+mod charset;
+mod screenshot;
+mod rtc;
+mod audio;
+mod rng;
+mod dma;
+mod harness;
+mod display;
+mod symbols;
+mod disasm;
+mod debugger;
+mod gdbstub;
+mod trace;
+mod snapshot;
+mod replay;
+mod history;
+mod profile;
+mod memmap;
+mod cli;
+mod config;
 
 use cpu::Cpu;
 use bus::Bus;
 use vc::VideoController;
 use vm::Vm;
 use device::{Mouse, Keyboard};
-
-use std::{fs, io, num::NonZero, rc::Rc};
+use rtc::Rtc;
+use audio::Audio;
+use rng::Rng;
+use screenshot::FrameDumper;
+use display::{Blitter, DisplayOptions, Scaling};
+use symbols::Symbols;
+use debugger::{Console, Debugger};
+use gdbstub::GdbStub;
+use trace::{TraceFilter, Tracer};
+use profile::Profiler;
+use replay::Recording;
+use cli::Args;
+use config::Config;
+
+use std::{fs, io, num::NonZero, path::Path, rc::Rc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use winit::{
     application::ApplicationHandler,
     event::{ElementState, KeyEvent, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
-    window::{Window, WindowId},
+    window::{Fullscreen, Window, WindowId},
     keyboard::{Key, NamedKey}
 };
 
 // ── softbuffer replaces pixels ────────────────────────────────────────────────
 use softbuffer::{Context, Surface};
 
-use crate::assembler::{Assembler, AssemblerError, Lexer, Parser};
+use crate::assembler::{Assembled, Assembler, Lexer, Parser};
 
 // ── constants ─────────────────────────────────────────────────────────────────
 const SIZE: u8 = 128;
+const DEFAULT_HEADLESS_CYCLES: u64 = 10_000_000;
+const DEFAULT_BENCH_CYCLES: u64 = 5_000_000;
+const MAX_FRAMES_PER_REDRAW: u64 = 4; // how far the vm may run ahead to catch up with the wall clock
+const QUICKSAVE_PATH: &str = "quicksave.dnsnap"; // F5 / F9
 
 // ── app wrapper ───────────────────────────────────────────────────────────────
 struct App {
@@ -36,15 +73,88 @@ struct App {
     context:  Option<Context<Rc<Window>>>,
     surface:  Option<Surface<Rc<Window>, Rc<Window>>>,
     vm:       Vm,
+    started:  Instant,
+    samples:  Vec<i16>, // audio drained from the vm each frame
+    display:  DisplayOptions,
+    blitter:  Blitter,
+    console:  Option<Console>, // --debug
+    #[cfg(feature = "host-audio")]
+    speaker:  Option<audio::host::HostAudio>,
 }
 
 impl App {
-    fn new(vm: Vm) -> Self {
+    fn new(vm: Vm, display: DisplayOptions, debug: bool) -> Self {
+        let console = debug.then(|| Console::spawn(&vm));
+        // vm may already be partway in, from --load-snapshot
+        let started = Self::clock_start(vm.frames);
         Self {
             window:  None,
             context: None,
             surface: None,
             vm,
+            started,
+            samples: Vec::new(),
+            display,
+            blitter: Blitter::new(),
+            console,
+            #[cfg(feature = "host-audio")]
+            speaker: audio::host::HostAudio::open(),
+        }
+    }
+
+    // when the wall clock would have to have started for frames to be due right now
+    fn clock_start(frames: u64) -> Instant {
+        let now = Instant::now();
+        now.checked_sub(Duration::from_secs_f64(frames as f64 / vm::FRAME_HZ as f64)).unwrap_or(now)
+    }
+}
+
+impl App {
+    // F12: saves the last frame next to wherever the vm was started from
+    fn screenshot(&self) {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let path = format!("screenshot_{}_{}.png", secs, self.vm.frames);
+        match screenshot::save_png(&self.vm.video, Path::new(&path)) {
+            Ok(()) => println!("Saved {}", path),
+            Err(e) => eprintln!("{}", e.message),
+        }
+    }
+
+    // F5: one save slot, next to wherever the vm was started from
+    fn quicksave(&self) {
+        match snapshot::write_file(QUICKSAVE_PATH, &self.vm.snapshot()) {
+            Ok(()) => println!("Saved {} at cycle {}", QUICKSAVE_PATH, self.vm.cpu.cycles),
+            Err(e) => eprintln!("{}", e.message),
+        }
+    }
+
+    // F9: back to the last F5. the audio already queued for the host is dropped
+    fn quickload(&mut self) {
+        if self.vm.recording() {
+            eprintln!("Can't load {} while recording", QUICKSAVE_PATH);
+            return;
+        }
+        match snapshot::read_file(QUICKSAVE_PATH).and_then(|data| self.vm.restore(&data)) {
+            Ok(()) => {
+                self.samples.clear();
+                self.started = Self::clock_start(self.vm.frames);
+                println!("Loaded {} at cycle {}", QUICKSAVE_PATH, self.vm.cpu.cycles);
+            },
+            Err(e) => eprintln!("{}", e.message),
+        }
+    }
+
+    // F11
+    fn toggle_fullscreen(&mut self) {
+        self.display.fullscreen = !self.display.fullscreen;
+        if let Some(window) = &self.window {
+            window.set_fullscreen(self.display.fullscreen.then_some(Fullscreen::Borderless(None)));
+        }
+    }
+
+    fn resize_surface(&mut self, width: u32, height: u32) {
+        if let (Some(surface), Some(w), Some(h)) = (self.surface.as_mut(), NonZero::new(width), NonZero::new(height)) {
+            surface.resize(w, h).unwrap();
         }
     }
 }
@@ -53,12 +163,15 @@ impl ApplicationHandler for App {
     // create window + softbuffer objects once winit says we're ready
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         // ---- window ---------------------------------------------------------
+        let scale = self.display.scale.max(1);
         let window = Rc::new(
         event_loop
             .create_window(
                 Window::default_attributes()
-                    .with_inner_size(winit::dpi::PhysicalSize::new(512, 512)) // 4x scale
-                    .with_resizable(false)
+                    .with_inner_size(winit::dpi::PhysicalSize::new(SIZE as u32 * scale, SIZE as u32 * scale))
+                    .with_min_inner_size(winit::dpi::PhysicalSize::new(SIZE as u32, SIZE as u32))
+                    .with_resizable(true)
+                    .with_fullscreen(self.display.fullscreen.then_some(Fullscreen::Borderless(None)))
             )
             .unwrap(),
     );
@@ -67,13 +180,13 @@ impl ApplicationHandler for App {
         // ---- softbuffer context + surface -----------------------------------
         // SAFETY: softbuffer requires raw-handle stability; winit guarantees it.
         let context = unsafe { Context::new(window.clone()).unwrap() };
-        let mut surface = unsafe { Surface::new(&context, window.clone()).unwrap() };
-        surface.resize(Option::expect(NonZero::new(size.width), "hi"), Option::expect(NonZero::new(size.height), "hi")).unwrap();
+        let surface = unsafe { Surface::new(&context, window.clone()).unwrap() };
 
         // ---- stash ----------------------------------------------------------
         self.window  = Some(window);
         self.context = Some(context);
         self.surface = Some(surface);
+        self.resize_surface(size.width, size.height);
 
         // kick-start first frame
         self.window.as_ref().unwrap().request_redraw();
@@ -88,117 +201,104 @@ impl ApplicationHandler for App {
         match event {
             WindowEvent::CloseRequested => event_loop.exit(),
 
+            WindowEvent::Resized(size) => {
+                // the surface only changes size here, not every frame
+                self.resize_surface(size.width, size.height);
+                self.window.as_ref().unwrap().request_redraw();
+            },
+
             WindowEvent::KeyboardInput {
                 event: KeyEvent {logical_key: key, state: ElementState::Pressed, .. },
                 ..
             } => {
-                let keycode: u8 = match key.as_ref() {
-                    Key::Character("a") => 1,
-                    Key::Character("b") => 2,
-                    Key::Character("c") => 3,
-                    Key::Character("d") => 4,
-                    Key::Character("e") => 5,
-                    Key::Character("f") => 6,
-                    Key::Character("g") => 7,
-                    Key::Character("h") => 8,
-                    Key::Character("i") => 9,
-                    Key::Character("j") => 10,
-                    Key::Character("k") => 11,
-                    Key::Character("l") => 12,
-                    Key::Character("m") => 13,
-                    Key::Character("n") => 14,
-                    Key::Character("o") => 15,
-                    Key::Character("p") => 16,
-                    Key::Character("q") => 17,
-                    Key::Character("r") => 18,
-                    Key::Character("s") => 19,
-                    Key::Character("t") => 20,
-                    Key::Character("u") => 21,
-                    Key::Character("v") => 22,
-                    Key::Character("w") => 23,
-                    Key::Character("x") => 24,
-                    Key::Character("y") => 25,
-                    Key::Character("z") => 26,
-                    Key::Named(NamedKey::ArrowUp) => 27,
-                    Key::Named(NamedKey::ArrowDown) => 28,
-                    Key::Named(NamedKey::ArrowLeft) => 29,
-                    Key::Named(NamedKey::ArrowRight) => 30,
-                    Key::Character("1") => 31,
-                    Key::Character("2") => 32,
-                    Key::Character("3") => 33,
-                    Key::Character("4") => 34,
-                    Key::Character("5") => 35,
-                    Key::Character("6") => 36,
-                    Key::Character("7") => 37,
-                    Key::Character("8") => 38,
-                    Key::Character("9") => 39,
-                    Key::Character("0") => 40,
-                    Key::Named(NamedKey::Backspace) => 50,
-                    Key::Named(NamedKey::Enter) => 51,
-                    Key::Named(NamedKey::Escape) => 52,
-                    Key::Named(NamedKey::Space) => 53,
-                    Key::Named(NamedKey::Tab) => 54,
-                    _ => 0,
+                match key {
+                    Key::Named(NamedKey::F12) => return self.screenshot(),
+                    Key::Named(NamedKey::F5) => return self.quicksave(),
+                    Key::Named(NamedKey::F9) => return self.quickload(),
+                    Key::Named(NamedKey::F11) => return self.toggle_fullscreen(),
+                    Key::Named(NamedKey::F10) => {
+                        self.display.crt = !self.display.crt;
+                        return;
+                    },
+                    Key::Named(NamedKey::F8) => {
+                        self.display.scaling = match self.display.scaling {
+                            Scaling::Integer => Scaling::Fit,
+                            Scaling::Fit => Scaling::Integer,
+                        };
+                        return;
+                    },
+                    _ => (),
+                }
+
+                let name = match key.as_ref() {
+                    Key::Character(c) => c,
+                    Key::Named(NamedKey::ArrowUp) => "up",
+                    Key::Named(NamedKey::ArrowDown) => "down",
+                    Key::Named(NamedKey::ArrowLeft) => "left",
+                    Key::Named(NamedKey::ArrowRight) => "right",
+                    Key::Named(NamedKey::Backspace) => "backspace",
+                    Key::Named(NamedKey::Enter) => "enter",
+                    Key::Named(NamedKey::Escape) => "escape",
+                    Key::Named(NamedKey::Space) => "space",
+                    Key::Named(NamedKey::Tab) => "tab",
+                    _ => "",
                 };
+                let keycode = device::keycode(name).unwrap_or(0);
 
                 if keycode != 0 {
-                    self.vm.mem.key_inject(keycode);
+                    self.vm.inject_key(keycode);
                     println!("Key pressed: {}", keycode);
                 }
             },
 
             WindowEvent::RedrawRequested => {
-                self.vm.step_many(100);
+                if let Some(console) = &mut self.console && !console.poll(&mut self.vm) {
+                    event_loop.exit();
+                    return;
+                }
+
+                if self.console.as_ref().is_some_and(|c| c.paused()) {
+                    // hold the clock too, so the vm doesn't race to catch up once it's resumed
+                    self.started = Self::clock_start(self.vm.frames);
+                }
+                else {
+                    // run however many virtual frames the wall clock says are due
+                    let due = (self.started.elapsed().as_secs_f64() * vm::FRAME_HZ as f64) as u64;
+                    let mut ran = 0;
+                    while self.vm.frames < due && ran < MAX_FRAMES_PER_REDRAW && !self.vm.cpu.halted {
+                        match &mut self.console {
+                            Some(console) => if console.run_frame(&mut self.vm) {
+                                break;
+                            },
+                            None => self.vm.run_frame(),
+                        }
+                        ran += 1;
+                    }
+                }
+
+                self.samples.clear();
+                self.vm.mem.drain_audio(&mut self.samples);
+                #[cfg(feature = "host-audio")]
+                if let Some(speaker) = &self.speaker {
+                    speaker.push(&self.samples);
+                }
                 if !self.vm.cpu.halted {
                     // self.vm.cpu.status();
                     // self.vm.mem.status();
                 }
                 
-                self.vm
-                    .video
-                    .update_framebuffer(self.vm.mem.get_range(0x2400, 0x3400));
+                let size = self.window.as_ref().unwrap().inner_size();
+                if size.width == 0 || size.height == 0 {
+                    // minimised, nothing to draw into
+                    self.window.as_ref().unwrap().request_redraw();
+                    return;
+                }
 
                 // ---- blit VM framebuffer into softbuffer surface -------------
-                let surf  = self.surface.as_mut().unwrap();
-                surf
-                    .resize(Option::expect(NonZero::new(512), "hi"), Option::expect(NonZero::new(512), "hi"))
-                    .unwrap();
-
                 {
-                    // make counter to check how many times inner loop runs
                     let surf = self.surface.as_mut().unwrap();
                     let mut buf = surf.buffer_mut().unwrap();
-                    
-                    let scale = 4; // 512 / 128
-                    for (byte_idx, &byte) in self.vm.video.framebuffer.iter().enumerate() {
-                        for pixel_in_byte in 0..4 {
-                            let fb_pixel_idx = byte_idx * 4 + pixel_in_byte;
-
-                            let x = fb_pixel_idx % 128;
-                            let y = fb_pixel_idx / 128;
-
-                            let shift = 6 - pixel_in_byte * 2;
-                            let value = (byte >> shift) & 0b11;
-
-                            let intensity = (value as u32 * 255) / 3;
-                            let color =
-                                0xFF000000 |
-                                (intensity << 16) |
-                                (intensity << 8) |
-                                intensity;
-
-                            for dy in 0..scale {
-                                for dx in 0..scale {
-                                    let screen_idx =
-                                        (y * scale + dy) * (128 * scale) +
-                                        (x * scale + dx);
-                                    buf[screen_idx] = color;
-                                }
-                            }
-                        }
-                    }
-
-                    
+                    self.blitter.blit(&self.vm.video, &mut buf, size.width as usize, size.height as usize, &self.display);
                     buf.present().unwrap();
                 } // buffer presented on drop
 
@@ -244,207 +344,519 @@ impl ApplicationHandler for App {
 // const LEXING: bool = false; // debugging lexer
 
 
-fn load_assembly(memory: &mut Bus, file_path: String, start_pos: Option<u16>) {
+// assembles file_path.dnasm, handing the source back too for the listing
+fn assemble(file_path: &str, start_pos: Option<u16>) -> Result<(Assembled, String), String> {
+    let code = fs::read_to_string(file_path.to_string() + ".dnasm")
+        .map_err(|e| format!("couldn't read {}.dnasm: {}", file_path, e))?;
 
-    let mut output: String = String::new();
+    let lex: Lexer = Lexer::new(&code);
+    let mut parser: Parser = Parser::new(lex, file_path.to_string());
+    let program = parser.parse().map_err(|e| format!("{}.dnasm: {}", e.filename, e.message))?;
 
-    let code = match fs::read_to_string(&(file_path.clone() + ".dnasm")) {
-        Ok(s) => s,
-        Err(e) => {panic!("Assembler Error: {:?}", e);},
-    };
-    
+    let mut assembler = Assembler::new(program, parser.lines(), start_pos);
+    let assembled = assembler.assemble().map_err(|e| format!("{}.dnasm: {}", file_path, e.message))?;
+    Ok((assembled, code))
+}
 
-    let lex: Lexer = Lexer::new(&code);
+// file_path.sym and file_path.lst next to the source. regions label the listing
+fn write_sidecars(file_path: &str, assembled: &Assembled, code: &str, regions: &[(String, std::ops::Range<u32>)]) {
+    let written = assembled.write_symbols(&(file_path.to_string() + ".sym"))
+        .and_then(|()| assembled.write_listing(&(file_path.to_string() + ".lst"), code, regions));
+    if let Err(e) = written {
+        eprintln!("{}", e.message);
+    }
+}
+
+// assembles file_path.dnasm into memory, writes its symbols to file_path.sym and a
+// listing to file_path.lst, and returns them
+fn load_assembly(memory: &mut Bus, file_path: &str, start_pos: Option<u16>) -> Result<Assembled, String> {
+    let (assembled, code) = assemble(file_path, start_pos)?;
+
+    let mut bases: Vec<u16> = assembled.segments.keys().copied().collect();
+    bases.sort_unstable();
+    for base in bases {
+        let bytes = &assembled.segments[&base];
+        for (offset, byte) in bytes.iter().enumerate() {
+            let addr = base + offset as u16;
+            memory.force_set(addr, *byte); // ideally checked
+        }
+    }
 
-    let mut parser: Parser = Parser::new(lex, file_path);
+    let regions: Vec<(String, std::ops::Range<u32>)> = memory.regions().iter().map(|r| (r.name(), r.range())).collect();
+    write_sidecars(file_path, &assembled, &code, &regions);
+    Ok(assembled)
+}
 
-    let mut assembler = Assembler::new(match parser.parse() {
-        Ok(p) => p,
-        Err(e) => {panic!("Assembler Error: {:?}", e);},
-    }, start_pos);
-    let assembled = assembler.assemble();
+// the labels of the machine's programs, assembled without loading them or writing sidecars
+fn machine_symbols(machine: &Config) -> Result<Symbols, String> {
+    let mut symbols = Symbols::new();
+    for (path, start) in machine.programs() {
+        let (assembled, _) = assemble(&path, start)?;
+        symbols.add_program(&path, &assembled);
+    }
+    Ok(symbols)
+}
 
-    
 
+// os assemble FILE.dnasm [--at ADDR] [-o FILE.bin]: everything from the lowest
+// assembled address to the highest, gaps zero filled
+fn assemble_command(args: &Args, machine: &Config) -> Result<(), String> {
+    let source = &args.positional[0];
+    let file_path = source.strip_suffix(".dnasm").unwrap_or(source);
+    let at = args.number("--at", 0xFFFF).map_err(|e| e.message)?.map(|a| a as u16);
+    let (assembled, code) = assemble(file_path, at)?;
 
-    if let Ok(map) = assembled {
+    let regions: Vec<(String, std::ops::Range<u32>)> = machine.regions.iter().map(|r| (r.name(), r.start as u32..r.end)).collect();
+    write_sidecars(file_path, &assembled, &code, &regions);
 
-        let mut bases: Vec<u16> = map.keys().copied().collect();
-        bases.sort_unstable();
-        for base in bases {
-            let bytes = &map[&base];
-            for (offset, byte) in bytes.iter().enumerate() {
-                let addr = base + offset as u16;
-                memory.force_set(addr, *byte); // ideally checked
-                output.push_str(format!("Instruction ({:0x}): {:08b}\n", base, *byte).as_str());
+    let first = assembled.segments.keys().copied().min().unwrap_or(0) as usize;
+    let last = assembled.segments.iter().map(|(base, bytes)| *base as usize + bytes.len()).max().unwrap_or(first);
+    let mut image = vec![0u8; last - first];
+    for (base, bytes) in &assembled.segments {
+        let at = *base as usize - first;
+        image[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    let out = args.value("-o").unwrap_or(file_path.to_string() + ".bin");
+    fs::write(&out, &image).map_err(|e| format!("couldn't write {}: {}", out, e))?;
+    println!("Assembled {} bytes (0x{:04X}-0x{:04X}) to {}", image.len(), first, last.max(first + 1) - 1, out);
+    Ok(())
+}
+
+// os disassemble FILE [--at ADDR] [--count N]. a .bin is loaded at ADDR; a .dnasm is
+// assembled there first, so its labels show up
+fn disassemble_command(args: &Args) -> Result<(), String> {
+    let path = &args.positional[0];
+    let at = args.number("--at", 0xFFFF).map_err(|e| e.message)?.map(|a| a as u16);
+    let count = args.number("--count", u32::MAX as u64).map_err(|e| e.message)?;
+
+    let mut mem = vec![0u8; 0x10000];
+    let mut symbols = Symbols::new();
+    let mut spans: Vec<(u16, usize)> = Vec::new(); // (start, length) to go through
+    if let Some(file_path) = path.strip_suffix(".dnasm") {
+        let (assembled, _) = assemble(file_path, at)?;
+        for (base, bytes) in &assembled.segments {
+            mem[*base as usize..*base as usize + bytes.len()].copy_from_slice(bytes);
+            spans.push((*base, bytes.len()));
+        }
+        spans.sort_unstable();
+        symbols.add_program(file_path, &assembled);
+    }
+    else {
+        let bytes = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+        let start = at.unwrap_or(0);
+        let len = bytes.len().min(0x10000 - start as usize);
+        mem[start as usize..start as usize + len].copy_from_slice(&bytes[..len]);
+        spans.push((start, len));
+    }
+
+    let mut left = count.unwrap_or(u64::MAX);
+    for (start, len) in spans {
+        let mut addr = start as usize;
+        while addr < start as usize + len && left > 0 {
+            if symbols.describe(addr as u16).is_some_and(|name| !name.contains('+')) {
+                println!("{}:", symbols.describe(addr as u16).unwrap_or_default());
             }
-            
+            let inst = disasm::disassemble(&mem, addr as u16, &symbols);
+            let bytes: Vec<String> = (0..inst.len as usize).map(|i| format!("{:02X}", mem[(addr + i) & 0xFFFF])).collect();
+            println!("0x{:04X}  {:<12} {}", addr, bytes.join(" "), inst.text);
+            addr += inst.len as usize;
+            left -= 1;
         }
+    }
+    Ok(())
+}
+
+
+
+
 
-        // match fs::write(file_path + "_out.txt", output) {
-        //     Ok(()) => (),
-        //     Err(e) => {panic!("Unable to write file with error {:?}", e);},
-        // };
+// what to do with a headless run's output
+struct HeadlessOptions {
+    cycle_limit: u64,
+    wav_path: Option<String>,
+    screenshot: Option<String>, // png of the screen when the run stops
+    save_snapshot: Option<String>, // save state of the machine when the run stops
+    record: Option<String>,
+    dumper: Option<FrameDumper>,
+}
+
+// runs without a window until the cpu halts or the cycle budget is spent (counted
+// from wherever the machine starts, boot or a snapshot). devices run off the
+// virtual clock so two runs of the same image match
+fn run_headless(mut vm: Vm, options: HeadlessOptions) {
+    let mut samples: Vec<i16> = Vec::new();
+    let stop_at = vm.cpu.cycles.saturating_add(options.cycle_limit);
+    while !vm.cpu.halted && vm.cpu.cycles < stop_at {
+        let frames = vm.frames;
+        vm.step();
+
+        vm.mem.drain_audio(&mut samples);
+        if options.wav_path.is_none() {
+            samples.clear();
+        }
+
+        if let Some(dumper) = &options.dumper && vm.frames != frames
+            && let Err(e) = dumper.frame(&vm.video, vm.frames) {
+            eprintln!("{}", e.message);
+        }
     }
-    else if let Err(e) = assembled {
-        panic!("Assembler Error: {:?}", e);
+    println!("Headless run stopped after {} cycles, {} frames{}", vm.cpu.cycles, vm.frames, if vm.cpu.halted { " (halted)" } else { "" });
+    finish_trace(&mut vm);
+    finish_profile(&mut vm);
+    finish_recording(&mut vm, &options.record);
+
+    if let Some(path) = &options.wav_path {
+        match audio::write_wav(path, &samples) {
+            Ok(()) => println!("Wrote {} audio samples to {}", samples.len(), path),
+            Err(e) => eprintln!("Couldn't write {}: {}", path, e),
+        }
+    }
+
+    if let Some(path) = &options.screenshot {
+        // whatever's in vram right now, not the last vblank
+        vm.render_frame();
+        match screenshot::save_png(&vm.video, Path::new(path)) {
+            Ok(()) => println!("Saved {}", path),
+            Err(e) => eprintln!("{}", e.message),
+        }
+    }
+
+    if let Some(path) = &options.save_snapshot {
+        match snapshot::write_file(path, &vm.snapshot()) {
+            Ok(()) => println!("Saved {}", path),
+            Err(e) => eprintln!("{}", e.message),
+        }
+    }
+}
+
+
+fn finish_trace(vm: &mut Vm) {
+    if let Some(records) = vm.stop_trace() {
+        println!("Traced {} instructions", records);
+    }
+}
+
+fn finish_profile(vm: &mut Vm) {
+    if let Some(cycles) = vm.stop_profile() {
+        println!("Profiled {} cycles", cycles);
+    }
+}
+
+fn finish_recording(vm: &mut Vm, path: &Option<String>) {
+    if let (Some(path), Some(recording)) = (path, vm.stop_recording()) {
+        match recording.save(path) {
+            Ok(()) => println!("Recorded {} keys and {} clock readings to {}, ram hash {:016X} at cycle {}",
+                recording.keys.len(), recording.clock.len(), path, recording.ram_hash, recording.end_cycle),
+            Err(e) => eprintln!("{}", e.message),
+        }
+    }
+}
+
+// runs a replay to where its recording stopped. true if ram came out the same
+fn run_replay(mut vm: Vm, recording: &Recording) -> bool {
+    while !vm.cpu.halted && vm.cpu.cycles < recording.end_cycle {
+        vm.step();
+    }
+    finish_trace(&mut vm);
+    finish_profile(&mut vm);
+    let hash = vm.ram_hash();
+    if hash == recording.ram_hash && vm.cpu.cycles == recording.end_cycle {
+        println!("Replay matched: ram hash {:016X} at cycle {}", hash, vm.cpu.cycles);
+        true
     }
     else {
-        panic!("Failed");
+        println!("Replay diverged: ram hash {:016X} at cycle {}, recorded {:016X} at cycle {}",
+            hash, vm.cpu.cycles, recording.ram_hash, recording.end_cycle);
+        false
     }
-    
 }
 
+// --trace FILE [--trace-range FIRST-LAST] [--trace-task N] [--trace-mode kernel|user]
+fn trace_options(args: &Args) -> Result<Option<Tracer>, String> {
+    let arg_value = |flag: &str| args.value(flag);
+    let Some(path) = arg_value("--trace") else {
+        return Ok(None);
+    };
+    let mut filter = TraceFilter::new();
+    if let Some(range) = arg_value("--trace-range") {
+        filter.range = Some(TraceFilter::parse_range(&range).ok_or(format!("Bad --trace-range {}, expected FIRST-LAST", range))?);
+    }
+    if let Some(task) = arg_value("--trace-task") {
+        filter.task = Some(task.parse::<u8>().map_err(|_| format!("Bad --trace-task {}", task))?);
+    }
+    if let Some(mode) = arg_value("--trace-mode") {
+        filter.mode = Some(match mode.as_str() {
+            "kernel" | "k" => cpu::CPUMode::K,
+            "user" | "u" => cpu::CPUMode::U,
+            _ => return Err(format!("Bad --trace-mode {}, expected kernel or user", mode)),
+        });
+    }
+    Tracer::create(&path, filter).map(Some).map_err(|e| e.message)
+}
 
 
+// runs src/bench_vram in place of the os and reports how fast the emulator went
+fn run_bench(mut vm: Vm, cycle_limit: u64) {
+    let start = Instant::now();
+    while !vm.cpu.halted && vm.cpu.cycles < cycle_limit {
+        vm.step();
+    }
+    let secs = start.elapsed().as_secs_f64();
+    println!("Bench: {} instructions in {:.3}s, {:.0} instructions/s ({} frames)",
+        vm.cpu.cycles, secs, vm.cpu.cycles as f64 / secs, vm.frames);
+}
 
 
-fn main() {
+// boots a fresh headless machine for every scenario. true if they all passed
+fn run_scenarios(paths: &[String], mut config: MachineConfig, machine: &Config, bless: bool) -> bool {
+    config.headless = true;
+    let mut failed = 0;
+    for path in paths {
+        let result = harness::Scenario::load(Path::new(path)).and_then(|scenario| {
+            config.programs = scenario.programs();
+            let mut vm = boot_machine(&config, machine).map_err(|message| harness::ScenarioError { message })?;
+            scenario.run(&mut vm, bless)
+        });
+        match result {
+            Ok(()) => println!("PASS {}", path),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}\n{}", path, e.message);
+            },
+        }
+    }
+    println!("{} of {} scenario(s) passed", paths.len() - failed, paths.len());
+    failed == 0
+}
+
 
+// what the command line changes about the machine the config describes. the
+// window, headless runs, the bench and scenarios all boot through boot_machine
+struct MachineConfig {
+    headless: bool, // virtual clock and a fixed rng seed
+    seed: Option<u32>,
+    font: Option<String>,
+    programs: Option<Vec<(String, Option<u16>)>>, // (path without .dnasm, load address), None for the config's images
+}
+
+fn boot_machine(config: &MachineConfig, machine: &Config) -> Result<Vm, String> {
     let keyb = Keyboard::new();
     let ms = Mouse::new();
-    
-    // kernel / system
-    let bootloader   = 0x0000..0x0400; // 1 KB
-    let kernel_core  = 0x0400..0x1000;
-    let kernel_traps = 0x1000..0x1200;
-    let kernel_data  = 0x1200..0x1800;
-    let kernel_heap  = 0x1800..0x2000;
-    let kernel_stack = 0x2000..0x2400;
-
-    // reserved (not passed into Bus::new directly)
-    let vram         = 0x2400..0x3400; // 4 KB
-
-    let mmio         = 0x3400..0x3800; // 1 KB
-
-    // user space
-    // differentiate tasks for memory safety
-    let user_code_0  = 0x3800..0x4000;
-    let user_data_0  = 0x4000..0x4200;
-    let user_heap_0  = 0x4200..0x4800;
-    let user_vram_0  = 0x4800..0x5800; // vram (0x2400) + 0x2400 = user vram 0
-    let user_stack_0 = 0x5800..0x6000;
-
-    let user_code_1  = 0x6000..0x6800;
-    let user_data_1  = 0x6800..0x6A00;
-    let user_heap_1  = 0x6A00..0x7000;
-    let user_vram_1  = 0x7000..0x8000; // user vram 0 (0x4800) + 0x2800 = user vram 1
-    let user_stack_1 = 0x8000..0x8800;
-
-    let user_code_2  = 0x8800..0x9000;
-    let user_data_2  = 0x9000..0x9200;
-    let user_heap_2  = 0x9200..0x9800;
-    let user_vram_2  = 0x9800..0xA800; // user vram 1 (0x7000) + 0x2800 = user vram 2
-    let user_stack_2 = 0xA800..0xB000;
-
-    let user_code_3  = 0xB000..0xB800;
-    let user_data_3  = 0xB800..0xBA00;
-    let user_heap_3  = 0xBA00..0xC000;
-    let user_vram_3  = 0xC000..0xD000; // user vram 2 (0x9800) + 0x2800 = user vram 3
-    let user_stack_3 = 0xD000..0xD800;
-
-    // formula for getting x,y for per-prog vram: 0x4800 + (user idx * 0x2800)
-
-    let shared_data = 0xD800..0xF800;
-
-    // let user_code_7  = 0xE000..0xE800;
-    // let user_data_7  = 0xE800..0xEA00;
-    // let user_heap_7  = 0xEA00..0xF400;
-    // let user_stack_7 = 0xF400..0xF800;
-
-
-    let cpu = Cpu::new(kernel_traps.start);
-    let vc  = VideoController::new(128, 128, vram.start);
-
-    let mut memory = Bus::new(
-        ms,
-        keyb,
-
-        bootloader,
-        kernel_core.clone(),
-        kernel_traps.clone(),
-        kernel_data.clone(),
-        kernel_heap.clone(),
-        kernel_stack.clone(),
-        vram.clone(),
-        mmio.clone(),
-
-        // later: make this way more concise. probably use a vec or array
-        user_code_0.clone(),
-        user_data_0.clone(),
-        user_heap_0.clone(),
-        user_vram_0.clone(),
-        user_stack_0.clone(),
-
-        user_code_1.clone(),
-        user_data_1.clone(),
-        user_heap_1.clone(),
-        user_vram_1.clone(),
-        user_stack_1.clone(),
-
-        user_code_2.clone(),
-        user_data_2.clone(),
-        user_heap_2.clone(),
-        user_vram_2.clone(),
-        user_stack_2.clone(),
-
-        user_code_3.clone(),
-        user_data_3.clone(),
-        user_heap_3.clone(),
-        user_vram_3.clone(),
-        user_stack_3.clone(),
-
-        // user_code_4.clone(),
-        // user_data_4.clone(),
-        // user_heap_4.clone(),
-        // user_stack_4.clone(),
-
-        // user_code_5.clone(),
-        // user_data_5.clone(),
-        // user_heap_5.clone(),
-        // user_stack_5.clone(),
-
-        // user_code_6.clone(),
-        // user_data_6.clone(),
-        // user_heap_6.clone(),
-        // user_stack_6.clone(),
-
-        shared_data.clone(),
-
-        // user_code_7,
-        // user_data_7,
-        // user_heap_7,
-        // user_stack_7,
+    let rtc = if config.headless { Rtc::virtual_clock() } else { Rtc::host() };
+    let audio = Audio::new();
+    // headless runs are meant to be repeatable, so they get a fixed seed
+    let rng = match config.seed.or(machine.machine.seed) {
+        Some(seed) => Rng::new(seed),
+        None if config.headless => Rng::new(rng::DEFAULT_SEED),
+        None => Rng::entropy(),
+    };
 
-    );
+    // Config::validate checks these are there
+    let start = |name: &str| machine.range(name).unwrap().start as u16;
+
+    let mut cpu = Cpu::new(start("kernel_traps"));
+    cpu.instruction_lim = machine.machine.timeslice;
+    let mut vc  = VideoController::new(start("vram"), start("mmio"), machine.task_vram());
+    if let Some(path) = config.font.clone().or_else(|| machine.font()) {
+        let loaded = fs::read(&path)
+            .map_err(|e| format!("{}", e))
+            .and_then(|rom| vc.load_font(&rom).map_err(|e| e.message));
+        if let Err(e) = loaded {
+            eprintln!("Couldn't load font {}: {}, using the built-in one", path, e);
+        }
+    }
 
+    let mut memory = Bus::new(ms, keyb, rtc, audio, rng, machine.memory_map());
+    memory.map_devices(machine.device_slots());
 
-    // load bootloader
-    load_assembly(&mut memory, "src\\boot".to_string(), None);
+    let programs = match &config.programs {
+        Some(programs) => programs.clone(),
+        None => machine.programs(),
+    };
 
-    load_assembly(&mut memory, "src\\kernel".to_string(), Some(kernel_core.start));
+    let mut symbols = Symbols::new();
+    for (path, start) in programs {
+        let assembled = load_assembly(&mut memory, &path, start)?;
+        symbols.add_program(&path, &assembled);
+    }
 
-    load_assembly(&mut memory, "src\\kernel_data".to_string(), Some(kernel_data.start));
+    let mut vm = Vm::new(memory, vc, cpu);
+    vm.set_clock_hz(machine.machine.clock_hz);
+    vm.symbols = symbols;
+    Ok(vm)
+}
 
-    load_assembly(&mut memory, "src\\kerheap".to_string(), Some(kernel_heap.start));
 
-    load_assembly(&mut memory, "src\\program_handling".to_string(), Some(kernel_data.start));
+// os run / os debug, see cli::USAGE
+fn run(args: &Args, machine: &Config) -> Result<(), String> {
+    let number = |flag: &str, max: u64| args.number(flag, max).map_err(|e| e.message);
+    let debug = args.command == "debug" || args.flag("--debug");
+    let gdb = args.value("--gdb");
+    let replay = args.value("--replay");
+    let record = args.value("--record");
+    let headless = args.command == "debug" || gdb.is_some() || replay.is_some()
+        || args.flag("--headless") || machine.frontend.headless;
+    let cycle_limit = number("--cycles", u64::MAX)?.unwrap_or(DEFAULT_HEADLESS_CYCLES);
+
+    let config = MachineConfig {
+        headless,
+        seed: number("--seed", u32::MAX as u64)?.map(|n| n as u32),
+        font: args.value("--font"),
+        programs: None,
+    };
+    let mut vm = boot_machine(&config, machine)?;
 
-    load_assembly(&mut memory, "src\\mouse".to_string(), Some(user_code_0.start));
+    if let Some(path) = args.value("--load-snapshot") {
+        snapshot::read_file(&path).and_then(|data| vm.restore(&data)).map_err(|e| e.message)?;
+        println!("Loaded {} at cycle {}", path, vm.cpu.cycles);
+    }
 
-    load_assembly(&mut memory, "src\\shell_disp".to_string(), Some(user_code_1.start));
+    let recording = match replay {
+        Some(path) => {
+            let recording = Recording::load(&path).map_err(|e| e.message)?;
+            vm.start_replay(&recording).map_err(|e| e.message)?;
+            Some(recording)
+        },
+        None => None,
+    };
+    if record.is_some() {
+        vm.start_recording();
+    }
+    // the debuggers can step backwards
+    if debug || gdb.is_some() {
+        vm.enable_history();
+    }
 
-    load_assembly(&mut memory, "src\\shared".to_string(), Some(shared_data.start));
-    
+    let (report, stacks) = (args.value("--profile"), args.value("--profile-stacks"));
+    if report.is_some() || stacks.is_some() {
+        vm.start_profile(Profiler::new(report, stacks));
+    }
 
-    let vm  = Vm::new(memory, vc, cpu);
+    if let Some(tracer) = trace_options(args)? {
+        vm.start_trace(tracer);
+    }
+
+    if headless && debug {
+        Debugger::new().repl(&mut vm);
+        finish_trace(&mut vm);
+        finish_profile(&mut vm);
+        finish_recording(&mut vm, &record);
+        return Ok(());
+    }
+
+    if let Some(addr) = gdb {
+        if let Err(e) = GdbStub::listen(&addr).and_then(|mut stub| stub.serve(&mut vm)) {
+            eprintln!("{}", e.message);
+        }
+        finish_trace(&mut vm);
+        finish_profile(&mut vm);
+        finish_recording(&mut vm, &record);
+        return Ok(());
+    }
+
+    if let Some(recording) = &recording {
+        if !run_replay(vm, recording) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if headless {
+        let dumper = match args.value("--dump-frames") {
+            Some(dir) => {
+                let every = number("--every", u64::MAX)?.unwrap_or(1);
+                Some(FrameDumper::new(&dir, every).map_err(|e| e.message)?)
+            },
+            None => None,
+        };
+
+        run_headless(vm, HeadlessOptions {
+            cycle_limit,
+            wav_path: args.value("--wav"),
+            screenshot: args.value("--screenshot"),
+            save_snapshot: args.value("--save-snapshot"),
+            record,
+            dumper,
+        });
+        return Ok(());
+    }
 
-    
     let event_loop = EventLoop::new().unwrap();
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let mut app = App::new(vm);
+    let mut display = DisplayOptions::new();
+    display.scale = number("--scale", u32::MAX as u64)?.map_or(machine.frontend.scale, |n| n as u32);
+    if args.flag("--fit") || machine.frontend.fit {
+        display.scaling = Scaling::Fit;
+    }
+    display.fullscreen = args.flag("--fullscreen") || machine.frontend.fullscreen;
+    display.crt = args.flag("--crt") || machine.frontend.crt;
+
+    let mut app = App::new(vm, display, debug);
     if let Err(e) = event_loop.run_app(&mut app) {
         eprintln!("winit error: {e}");
     }
-}
\ No newline at end of file
+    finish_trace(&mut app.vm);
+    finish_profile(&mut app.vm);
+    finish_recording(&mut app.vm, &record);
+    Ok(())
+}
+
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = match Args::parse(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\nsee `os help`", e.message);
+            std::process::exit(2);
+        },
+    };
+    let machine = match args.value("--config") {
+        Some(path) => Config::load(Path::new(&path)),
+        None => Ok(Config::builtin()),
+    };
+    let machine = match machine {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprintln!("{}", e.message);
+            std::process::exit(1);
+        },
+    };
+    let config = MachineConfig {
+        headless: true,
+        seed: None,
+        font: None,
+        programs: None,
+    };
+
+    let result = match args.command.as_str() {
+        "help" => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        },
+        "assemble" => assemble_command(&args, &machine),
+        "disassemble" => disassemble_command(&args),
+        "test" => {
+            if !run_scenarios(&args.positional, config, &machine, args.flag("--bless")) {
+                std::process::exit(1);
+            }
+            Ok(())
+        },
+        "bench" => args.number("--cycles", u64::MAX).map_err(|e| e.message).and_then(|cycles| {
+            let config = MachineConfig { programs: Some(vec![("src/bench_vram".to_string(), None)]), ..config };
+            run_bench(boot_machine(&config, &machine)?, cycles.unwrap_or(DEFAULT_BENCH_CYCLES));
+            Ok(())
+        }),
+        "render-trace" => machine_symbols(&machine).and_then(|symbols| {
+            let stdout = io::stdout();
+            trace::render(Path::new(&args.positional[0]), &symbols, &mut stdout.lock()).map(|_| ()).map_err(|e| e.message)
+        }),
+        _ => run(&args, &machine),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,180 @@
+use std::ops::Range;
+
+use serde::Deserialize;
+
+use crate::cpu::{Access, CPUExit, CPUMode, Fault};
+
+
+/*
+ * the memory map: a list of regions, each with a kind, the task that owns it (user
+ * regions only) and what kernel and user mode may do there. the kind only names
+ * the region and picks its default permissions (see RegionKind::perms), a config
+ * can give others.
+ *
+ * user mode gets a region's user permissions only while its owner is the current
+ * task (the byte at bus::CURRENT_TASK); regions without an owner are the same for
+ * every task. kernel mode ignores owners.
+ *
+ * a map has to cover every address from 0x0000 to 0xFFFF exactly once: gaps are
+ * spelled out as unmapped regions, which fault on any access. ends are exclusive,
+ * so they're u32 for the last region to end at 0x10000
+ */
+
+#[derive(Debug)]
+pub struct MapError {
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionKind {
+    Bootloader,
+    KernelCore,
+    KernelTraps,
+    KernelData,
+    KernelHeap,
+    KernelStack,
+    Vram,
+    Mmio,
+    UserCode,
+    UserData,
+    UserHeap,
+    UserVram,
+    UserStack,
+    SharedData,
+    Unmapped,
+}
+
+// what one mode may do in a region
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Perms {
+    pub r: bool,
+    pub w: bool,
+    pub x: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemRange {
+    pub kind: RegionKind,
+    pub range: Range<u32>, // end up to 0x10000
+    pub task: Option<u8>, // owner
+    pub kernel: Perms,
+    pub user: Perms,
+}
+
+impl RegionKind {
+    pub fn is_user(&self) -> bool {
+        matches!(self, Self::UserCode | Self::UserData | Self::UserHeap | Self::UserVram | Self::UserStack)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bootloader => "bootloader",
+            Self::KernelCore => "kernel_core",
+            Self::KernelTraps => "kernel_traps",
+            Self::KernelData => "kernel_data",
+            Self::KernelHeap => "kernel_heap",
+            Self::KernelStack => "kernel_stack",
+            Self::Vram => "vram",
+            Self::Mmio => "mmio",
+            Self::UserCode => "user_code",
+            Self::UserData => "user_data",
+            Self::UserHeap => "user_heap",
+            Self::UserVram => "user_vram",
+            Self::UserStack => "user_stack",
+            Self::SharedData => "shared_data",
+            Self::Unmapped => "unmapped",
+        }
+    }
+
+    // (kernel, user) permissions unless the config says otherwise
+    pub fn perms(&self) -> (Perms, Perms) {
+        let perms = |s: &str| Perms::parse(s).unwrap();
+        match self {
+            Self::Bootloader | Self::KernelCore | Self::KernelTraps => (perms("rx"), perms("")),
+            Self::KernelData | Self::KernelHeap | Self::KernelStack | Self::Vram | Self::Mmio => (perms("rw"), perms("")),
+            Self::UserCode => (perms("rx"), perms("rx")),
+            Self::UserData | Self::UserHeap | Self::UserVram | Self::UserStack => (perms("rw"), perms("rw")),
+            Self::SharedData => (perms("rw"), perms("rx")),
+            Self::Unmapped => (perms(""), perms("")),
+        }
+    }
+}
+
+impl Perms {
+    // any of r, w and x, e.g. "rw". "" is no access
+    pub fn parse(s: &str) -> Option<Self> {
+        if !s.chars().all(|c| matches!(c, 'r' | 'w' | 'x')) {
+            return None;
+        }
+        Some(Self { r: s.contains('r'), w: s.contains('w'), x: s.contains('x') })
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::R => self.r,
+            Access::W => self.w,
+            Access::X => self.x,
+        }
+    }
+}
+
+impl MemRange {
+    // a region with its kind's permissions
+    pub fn new(kind: RegionKind, range: Range<u32>, task: Option<u8>) -> Self {
+        let (kernel, user) = kind.perms();
+        Self { kind, range, task, kernel, user }
+    }
+
+    pub fn check_access(&self, mode: CPUMode, access: Access, task_index: u8) -> Result<(), CPUExit> {
+        match self.allows(mode, access, task_index) {
+            true => Ok(()),
+            false => Err(CPUExit::Fault(Fault::IllegalMemAccess)),
+        }
+    }
+
+    pub fn range(&self) -> Range<u32> {
+        self.range.clone()
+    }
+
+    // user_code_1, kernel_data..., for the config, listings and dumps
+    pub fn name(&self) -> String {
+        match self.task {
+            Some(t) => format!("{}_{}", self.kind.name(), t),
+            None => self.kind.name().to_string(),
+        }
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.range.contains(&(addr as u32))
+    }
+
+    pub fn allows(&self, mode: CPUMode, access: Access, current_task: u8) -> bool {
+        match mode {
+            CPUMode::K => self.kernel.allows(access),
+            CPUMode::U => self.user.allows(access) && self.task.is_none_or(|t| t == current_task),
+        }
+    }
+}
+
+// regions sorted by start, none overlapping and no gaps
+pub fn check(regions: &[MemRange]) -> Result<(), MapError> {
+    let mut next = 0u32;
+    let mut last: Option<&MemRange> = None; // the one that ends at next
+    for region in regions {
+        let r = &region.range;
+        if r.start > next {
+            return Err(MapError { message: format!("nothing covers 0x{:04X}..0x{:04X}, add an unmapped region if that's meant", next, r.start) });
+        }
+        if let Some(last) = last && r.start < next {
+            return Err(MapError { message: format!("{} (0x{:04X}..0x{:04X}) overlaps {} (0x{:04X}..0x{:04X})",
+                region.name(), r.start, r.end, last.name(), last.range.start, last.range.end) });
+        }
+        next = r.end;
+        last = Some(region);
+    }
+    if next != 0x10000 {
+        return Err(MapError { message: format!("nothing covers 0x{:04X}..0x10000, add an unmapped region if that's meant", next) });
+    }
+    Ok(())
+}
@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+
+use crate::bus::{Bus, CURRENT_TASK};
+use crate::cpu::{CPUExit, CPUMode, Cpu};
+use crate::disasm;
+use crate::symbols::Symbols;
+
+
+/*
+ * exact profiler: every instruction is counted against its pc, the task that was
+ * running (the byte at CURRENT_TASK) and the call stack it ran under.
+ *
+ * call stacks are rebuilt from the instructions: a call pushes a frame named after
+ * where it jumped to, and a frame is popped once sp is back above where it was
+ * before the call (a ret, or anything else that unwinds the stack). each user task
+ * has its own stack of frames. the kernel's starts empty on every trap and is
+ * dropped at kret, since a trap doesn't switch stacks.
+ *
+ * write_report gives the text report, write_folded one line per stack with the
+ * cycles spent in it (task 1;draw_line;put_char;loop 1234), which flamegraph.pl
+ * and speedscope read as is
+ */
+
+const TOP_PCS: usize = 40;
+const TOP_LABELS: usize = 40;
+const TOP_FUNCTIONS: usize = 40;
+
+const KERNEL_CONTEXT: usize = 0x100; // tasks are 0-0xFF
+const CONTEXTS: usize = 0x101;
+
+#[derive(Debug)]
+pub struct ProfileError {
+    pub message: String,
+}
+
+#[derive(Default, Clone)]
+struct TaskStats {
+    instructions: u64,
+    user_cycles: u64,
+    kernel_cycles: u64,
+    syscalls: u64,
+    timer_exits: u64,
+    other_exits: u64, // faults, halts, interrupts
+}
+
+// one call in a tree of every stack seen, so a stack is just an index
+struct Node {
+    parent: usize,
+    target: u16, // where the call went, or the context for a root
+    children: HashMap<u16, usize>,
+}
+
+struct Frame {
+    node: usize,
+    sp: u16, // before the call
+}
+
+// the cpu just before an instruction
+pub struct Before {
+    pc: u16,
+    sp: u16,
+    mode: CPUMode,
+    task: u8,
+    cycles: u64,
+    opcode: u8,
+}
+
+pub struct Profiler {
+    report: Option<String>, // paths written by finish
+    stacks_path: Option<String>,
+    pc_instructions: Vec<u64>,
+    pc_cycles: Vec<u64>,
+    tasks: Vec<TaskStats>, // by task
+    nodes: Vec<Node>,
+    roots: Vec<Option<usize>>, // by context: each task, then the kernel
+    stacks: Vec<Vec<Frame>>, // by context
+    calls: HashMap<u16, u64>, // call target -> times called
+    folded: HashMap<(usize, u16), u64>, // (stack, pc) -> cycles
+    first_cycle: Option<u64>,
+    last_cycle: u64,
+}
+
+impl Profiler {
+    pub fn new(report: Option<String>, stacks_path: Option<String>) -> Self {
+        Self {
+            report,
+            stacks_path,
+            pc_instructions: vec![0; 0x10000],
+            pc_cycles: vec![0; 0x10000],
+            tasks: vec![TaskStats::default(); 0x100],
+            nodes: Vec::new(),
+            roots: vec![None; CONTEXTS],
+            stacks: (0..CONTEXTS).map(|_| Vec::new()).collect(),
+            calls: HashMap::new(),
+            folded: HashMap::new(),
+            first_cycle: None,
+            last_cycle: 0,
+        }
+    }
+
+    pub fn before(&self, cpu: &Cpu, mem: &Bus) -> Before {
+        let ram = mem.ram();
+        Before {
+            pc: cpu.pc,
+            sp: cpu.sp,
+            mode: cpu.mode,
+            task: ram[CURRENT_TASK as usize],
+            cycles: cpu.cycles,
+            opcode: ram[cpu.pc as usize] >> 2,
+        }
+    }
+
+    pub fn record(&mut self, before: &Before, cpu: &Cpu) {
+        let cycles = cpu.cycles - before.cycles;
+        self.first_cycle.get_or_insert(before.cycles);
+        self.last_cycle = cpu.cycles;
+
+        self.pc_instructions[before.pc as usize] += 1;
+        self.pc_cycles[before.pc as usize] += cycles;
+
+        let stats = &mut self.tasks[before.task as usize];
+        stats.instructions += 1;
+        match before.mode {
+            CPUMode::U => stats.user_cycles += cycles,
+            CPUMode::K => stats.kernel_cycles += cycles,
+        }
+        match &cpu.last_exit {
+            Some(CPUExit::Syscall) => stats.syscalls += 1,
+            Some(CPUExit::Timer) => stats.timer_exits += 1,
+            Some(_) => stats.other_exits += 1,
+            None => (),
+        }
+
+        let context = match before.mode {
+            CPUMode::U => before.task as usize,
+            CPUMode::K => KERNEL_CONTEXT,
+        };
+        let node = self.current(context);
+        *self.folded.entry((node, before.pc)).or_insert(0) += cycles;
+
+        if cpu.last_exit.is_some() || cpu.mode != before.mode {
+            self.stacks[KERNEL_CONTEXT].clear(); // into the kernel, or back out of it with kret
+        }
+        else if before.opcode == disasm::OP_CALL {
+            let child = self.child(node, cpu.pc);
+            self.stacks[context].push(Frame { node: child, sp: before.sp });
+            *self.calls.entry(cpu.pc).or_insert(0) += 1;
+        }
+        else {
+            let frames = &mut self.stacks[context];
+            while frames.last().is_some_and(|f| f.sp <= cpu.sp) {
+                frames.pop();
+            }
+        }
+    }
+
+    // the stack a context is in right now
+    fn current(&mut self, context: usize) -> usize {
+        if let Some(frame) = self.stacks[context].last() {
+            return frame.node;
+        }
+        if let Some(root) = self.roots[context] {
+            return root;
+        }
+        let root = self.nodes.len();
+        self.nodes.push(Node { parent: root, target: context as u16, children: HashMap::new() });
+        self.roots[context] = Some(root);
+        root
+    }
+
+    fn child(&mut self, node: usize, target: u16) -> usize {
+        if let Some(child) = self.nodes[node].children.get(&target) {
+            return *child;
+        }
+        let child = self.nodes.len();
+        self.nodes.push(Node { parent: node, target, children: HashMap::new() });
+        self.nodes[node].children.insert(target, child);
+        child
+    }
+
+    // root first
+    fn path(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while self.nodes[node].parent != node {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path.reverse();
+        path
+    }
+
+    fn frame_name(&self, node: usize, symbols: &Symbols) -> String {
+        let target = self.nodes[node].target;
+        if self.nodes[node].parent != node {
+            symbols.describe(target).unwrap_or_else(|| format!("0x{:04X}", target))
+        }
+        else if target as usize == KERNEL_CONTEXT {
+            "kernel".to_string()
+        }
+        else {
+            format!("task {}", target)
+        }
+    }
+
+    // writes out whichever files it was asked for
+    pub fn finish(&self, symbols: &Symbols, ram: &[u8]) -> Result<(), ProfileError> {
+        if let Some(path) = &self.report {
+            write_file(path, |out| self.write_report(out, symbols, ram))?;
+        }
+        if let Some(path) = &self.stacks_path {
+            write_file(path, |out| self.write_folded(out, symbols))?;
+        }
+        Ok(())
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.pc_cycles.iter().sum()
+    }
+
+    pub fn write_report(&self, out: &mut dyn Write, symbols: &Symbols, ram: &[u8]) -> std::io::Result<()> {
+        let total = self.total_cycles().max(1);
+        let instructions: u64 = self.pc_instructions.iter().sum();
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total as f64;
+        let kernel: u64 = self.tasks.iter().map(|t| t.kernel_cycles).sum();
+        let user: u64 = self.tasks.iter().map(|t| t.user_cycles).sum();
+
+        writeln!(out, "Profile of cycles {} to {}: {} instructions, {} cycles",
+            self.first_cycle.unwrap_or(0), self.last_cycle, instructions, self.total_cycles())?;
+        writeln!(out, "kernel {} cycles ({:.1}%), user {} cycles ({:.1}%)", kernel, percent(kernel), user, percent(user))?;
+
+        // task is whatever CURRENT_TASK held, kernel time included (the scheduler
+        // counts against the last task it ran)
+        writeln!(out, "\nTasks")?;
+        writeln!(out, "{:>5} {:>12} {:>12} {:>7} {:>12} {:>12} {:>9} {:>9} {:>9}",
+            "task", "instructions", "cycles", "%", "kernel", "user", "syscalls", "timer", "other")?;
+        for (task, t) in self.tasks.iter().enumerate().filter(|(_, t)| t.instructions > 0) {
+            let cycles = t.kernel_cycles + t.user_cycles;
+            writeln!(out, "{:>5} {:>12} {:>12} {:>6.1}% {:>12} {:>12} {:>9} {:>9} {:>9}",
+                task, t.instructions, cycles, percent(cycles), t.kernel_cycles, t.user_cycles, t.syscalls, t.timer_exits, t.other_exits)?;
+        }
+
+        writeln!(out, "\nHot instructions")?;
+        writeln!(out, "{:>12} {:>7} {:>12}  {:<32} {:<28} source", "cycles", "%", "count", "address", "instruction")?;
+        let mut pcs: Vec<usize> = (0..0x10000).filter(|pc| self.pc_cycles[*pc] > 0).collect();
+        pcs.sort_by_key(|pc| std::cmp::Reverse(self.pc_cycles[*pc]));
+        for pc in pcs.iter().take(TOP_PCS) {
+            let cycles = self.pc_cycles[*pc];
+            let inst = disasm::disassemble(ram, *pc as u16, symbols);
+            writeln!(out, "{:>12} {:>6.1}% {:>12}  {:<32} {:<28} {}",
+                cycles, percent(cycles), self.pc_instructions[*pc], symbols.format(*pc as u16), inst.text,
+                symbols.source_line(*pc as u16).unwrap_or_default())?;
+        }
+
+        // self time, by the nearest label at or below each pc
+        let mut labels: HashMap<String, (u64, u64)> = HashMap::new();
+        for pc in &pcs {
+            let label = symbols.describe(*pc as u16).map_or_else(|| "?".to_string(), |name| name.split('+').next().unwrap().to_string());
+            let entry = labels.entry(label).or_insert((0, 0));
+            entry.0 += self.pc_cycles[*pc];
+            entry.1 += self.pc_instructions[*pc];
+        }
+        let mut labels: Vec<_> = labels.into_iter().collect();
+        labels.sort_by(|(a, (a_cycles, _)), (b, (b_cycles, _))| b_cycles.cmp(a_cycles).then(a.cmp(b)));
+        writeln!(out, "\nLabels (self)")?;
+        writeln!(out, "{:>12} {:>7} {:>12}  label", "cycles", "%", "count")?;
+        for (label, (cycles, count)) in labels.iter().take(TOP_LABELS) {
+            writeln!(out, "{:>12} {:>6.1}% {:>12}  {}", cycles, percent(*cycles), count, label)?;
+        }
+
+        // inclusive time: everything run while a call to the function was on the
+        // stack, counted once however deep it recursed
+        let mut functions: HashMap<u16, u64> = HashMap::new();
+        for ((node, _), cycles) in &self.folded {
+            let mut targets: Vec<u16> = self.path(*node).iter()
+                .skip(1) // the root isn't a call
+                .map(|n| self.nodes[*n].target)
+                .collect();
+            targets.sort();
+            targets.dedup();
+            for target in targets {
+                *functions.entry(target).or_insert(0) += cycles;
+            }
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|(a, a_cycles), (b, b_cycles)| b_cycles.cmp(a_cycles).then(a.cmp(b)));
+        writeln!(out, "\nFunctions (inclusive, from calls)")?;
+        writeln!(out, "{:>12} {:>7} {:>10}  function", "cycles", "%", "calls")?;
+        for (target, cycles) in functions.iter().take(TOP_FUNCTIONS) {
+            writeln!(out, "{:>12} {:>6.1}% {:>10}  {}", cycles, percent(*cycles), self.calls.get(target).unwrap_or(&0), symbols.format(*target))?;
+        }
+        Ok(())
+    }
+
+    // collapsed stacks, context;caller;callee;label cycles
+    pub fn write_folded(&self, out: &mut dyn Write, symbols: &Symbols) -> std::io::Result<()> {
+        let mut stacks: HashMap<String, u64> = HashMap::new();
+        for ((node, pc), cycles) in &self.folded {
+            let mut frames: Vec<String> = self.path(*node).iter().map(|n| self.frame_name(*n, symbols)).collect();
+            // the label it was at, unless that's the function's own
+            let label = symbols.describe(*pc).map_or_else(|| format!("0x{:04X}", pc), |name| name.split('+').next().unwrap().to_string());
+            if frames.last() != Some(&label) {
+                frames.push(label);
+            }
+            *stacks.entry(frames.join(";")).or_insert(0) += cycles;
+        }
+        let mut stacks: Vec<_> = stacks.into_iter().collect();
+        stacks.sort();
+        for (stack, cycles) in stacks {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_file(path: &str, write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>) -> Result<(), ProfileError> {
+    let err = |e: std::io::Error| ProfileError { message: format!("couldn't write {}: {}", path, e) };
+    let mut out = std::io::BufWriter::new(fs::File::create(path).map_err(err)?);
+    write(&mut out).and_then(|()| out.flush()).map_err(err)
+}
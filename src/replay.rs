@@ -0,0 +1,251 @@
+use std::fs;
+
+use crate::rtc::ClockSample;
+
+
+/*
+ * record/replay of everything that reaches the machine from outside: keys and the
+ * host clock. the rng is seeded inside the snapshot, so it follows along.
+ *
+ * a recording is MAGIC, a version byte, then
+ * u8      1 if the rtc was on the host clock, 0 for the virtual one
+ * u32     snapshot length, then the snapshot taken when recording started
+ * events, each a varint of cycles since the previous event, a kind byte, then:
+ *
+ * EVENT_KEY     u8 key code, injected before the instruction at that cycle
+ * EVENT_MILLIS  u64 host clock the rtc read (ms since the vm started)
+ * EVENT_SECS    u64 host date the rtc latched (unix seconds)
+ * EVENT_END     u64 hash of ram when recording stopped (ram_hash), always last
+ *
+ * clock events carry the rtc's cycle count, which can be one behind the cpu's.
+ * they're only ever matched against the rtc again, so that doesn't matter
+ */
+
+const MAGIC: &[u8; 8] = b"DNREPLAY";
+const VERSION: u8 = 1;
+
+const EVENT_KEY: u8 = 0;
+const EVENT_MILLIS: u8 = 1;
+const EVENT_SECS: u8 = 2;
+const EVENT_END: u8 = 3;
+
+#[derive(Debug)]
+pub struct ReplayError {
+    pub message: String,
+}
+
+pub struct Recording {
+    pub start: Vec<u8>, // snapshot
+    pub host_clock: bool,
+    pub keys: Vec<(u64, u8)>,
+    pub clock: Vec<(u64, ClockSample)>,
+    pub end_cycle: u64,
+    pub ram_hash: u64,
+}
+
+impl Recording {
+    pub fn new(start: Vec<u8>, host_clock: bool) -> Self {
+        Self {
+            start,
+            host_clock,
+            keys: Vec::new(),
+            clock: Vec::new(),
+            end_cycle: 0,
+            ram_hash: 0,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(self.host_clock as u8);
+        out.extend_from_slice(&(self.start.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.start);
+
+        // keys and clock readings are each in cycle order, merge them
+        let mut keys = self.keys.iter().peekable();
+        let mut clock = self.clock.iter().peekable();
+        let mut last = 0;
+        let mut event = |out: &mut Vec<u8>, cycle: u64, kind: u8| {
+            push_varint(out, cycle - last);
+            last = cycle;
+            out.push(kind);
+        };
+        loop {
+            let key_next = match (keys.peek(), clock.peek()) {
+                (Some((k, _)), Some((c, _))) => k <= c,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if key_next {
+                let (cycle, code) = keys.next().unwrap();
+                event(&mut out, *cycle, EVENT_KEY);
+                out.push(*code);
+            }
+            else {
+                let (cycle, sample) = clock.next().unwrap();
+                let (kind, value) = match sample {
+                    ClockSample::Millis(ms) => (EVENT_MILLIS, ms),
+                    ClockSample::Secs(secs) => (EVENT_SECS, secs),
+                };
+                event(&mut out, *cycle, kind);
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        event(&mut out, self.end_cycle, EVENT_END);
+        out.extend_from_slice(&self.ram_hash.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReplayError> {
+        let err = |what: &str| ReplayError { message: what.to_string() };
+        if data.len() < MAGIC.len() + 2 || &data[..MAGIC.len()] != MAGIC {
+            return Err(err("not a recording"));
+        }
+        if data[MAGIC.len()] != VERSION {
+            return Err(err(&format!("recording version {}, this build reads {}", data[MAGIC.len()], VERSION)));
+        }
+
+        let mut reader = Reader { data, at: MAGIC.len() + 1 };
+        let truncated = || err("recording ends early");
+        let host_clock = reader.byte().ok_or_else(truncated)? != 0;
+        let len = u32::from_be_bytes(reader.take(4).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+        let mut recording = Recording::new(reader.take(len).ok_or_else(truncated)?.to_vec(), host_clock);
+
+        let mut cycle = 0u64;
+        loop {
+            cycle += reader.varint().ok_or_else(truncated)?;
+            match reader.byte().ok_or_else(truncated)? {
+                EVENT_KEY => recording.keys.push((cycle, reader.byte().ok_or_else(truncated)?)),
+                EVENT_MILLIS => recording.clock.push((cycle, ClockSample::Millis(reader.u64().ok_or_else(truncated)?))),
+                EVENT_SECS => recording.clock.push((cycle, ClockSample::Secs(reader.u64().ok_or_else(truncated)?))),
+                EVENT_END => {
+                    recording.end_cycle = cycle;
+                    recording.ram_hash = reader.u64().ok_or_else(truncated)?;
+                    return Ok(recording);
+                },
+                kind => return Err(err(&format!("unknown event {} at cycle {}", kind, cycle))),
+            }
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), ReplayError> {
+        fs::write(path, self.to_bytes()).map_err(|e| ReplayError { message: format!("couldn't write {}: {}", path, e) })
+    }
+
+    pub fn load(path: &str) -> Result<Self, ReplayError> {
+        let data = fs::read(path).map_err(|e| ReplayError { message: format!("couldn't read {}: {}", path, e) })?;
+        Self::from_bytes(&data).map_err(|e| ReplayError { message: format!("{}: {}", path, e.message) })
+    }
+}
+
+// fnv-1a, enough to tell two runs apart
+pub fn ram_hash(ram: &[u8]) -> u64 {
+    ram.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+fn push_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let data: &'a [u8] = self.data;
+        let out = data.get(self.at..self.at + len)?;
+        self.at += len;
+        Some(out)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            n |= ((b & 0x7F) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::vm::Vm;
+
+    // on the host clock, running a program whose ram depends on the clock and every key
+    fn boot() -> Vm {
+        let boot = crate::MachineConfig {
+            headless: false,
+            seed: None,
+            font: None,
+            programs: Some(vec![("scenarios/replay_inputs".to_string(), Some(0))]),
+        };
+        crate::boot_machine(&boot, &Config::builtin()).unwrap()
+    }
+
+    fn replayed_hash(recording: &Recording) -> u64 {
+        let mut vm = boot();
+        vm.start_replay(recording).unwrap();
+        while vm.cpu.cycles < recording.end_cycle {
+            vm.step();
+        }
+        vm.ram_hash()
+    }
+
+    #[test]
+    fn replay_follows_keys_and_the_host_clock() {
+        let mut vm = boot();
+        vm.start_recording();
+        for code in [0x11, 0x17, 0x23] {
+            for _ in 0..20_000 {
+                vm.step();
+            }
+            vm.inject_key(code);
+        }
+        for _ in 0..20_000 {
+            vm.step();
+        }
+        let recording = vm.stop_recording().unwrap();
+        assert!(recording.host_clock);
+        assert_eq!(recording.keys.len(), 3);
+        assert!(!recording.clock.is_empty());
+
+        let bytes = recording.to_bytes();
+        let recording = Recording::from_bytes(&bytes).unwrap();
+        assert_eq!(replayed_hash(&recording), recording.ram_hash);
+
+        // a different key, or a different clock reading, and ram comes out different
+        let mut key = Recording::from_bytes(&bytes).unwrap();
+        key.keys[1].1 = 0x18;
+        assert_ne!(replayed_hash(&key), recording.ram_hash);
+
+        let mut clock = Recording::from_bytes(&bytes).unwrap();
+        let (_, last) = clock.clock.iter_mut().rfind(|(_, s)| matches!(s, ClockSample::Millis(_))).unwrap();
+        if let ClockSample::Millis(ms) = last {
+            *ms += 1;
+        }
+        assert_ne!(replayed_hash(&clock), recording.ram_hash);
+    }
+}
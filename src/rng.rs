@@ -0,0 +1,92 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+
+/*
+ * random number generator, mapped at mmio + RNG_OFFSET
+ *
+ * 0x0: data, every read returns the next byte
+ * 0x1-0x4: seed, 32-bit big-endian. writing 0x4 reseeds from all four bytes,
+ *      so the same seed always gives the same sequence
+ */
+
+pub const RNG_OFFSET: u16 = 0x40;
+pub const RNG_SIZE: u16 = 0x08;
+
+// used for headless/test runs unless --seed says otherwise
+pub const DEFAULT_SEED: u32 = 0x5EED;
+
+pub struct Rng {
+    state: u64,
+    seed: [u8; 4],
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        let mut rng = Self {
+            state: 0,
+            seed: seed.to_be_bytes(),
+        };
+        rng.reseed(seed);
+        rng
+    }
+
+    // seeded from the host, different every run
+    pub fn entropy() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        hasher.write_u64(nanos);
+        Self::new(hasher.finish() as u32)
+    }
+
+    fn reseed(&mut self, seed: u32) {
+        // splitmix the seed so small/zero seeds still give a good (nonzero) xorshift state
+        let mut z = (seed as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        self.state = if z == 0 { 1 } else { z };
+    }
+
+    // xorshift64*
+    pub fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    pub fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            0x0 => self.next_byte(),
+            0x1..=0x4 => self.seed[offset as usize - 1],
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u16, val: u8) {
+        if let 0x1..=0x4 = offset {
+            self.seed[offset as usize - 1] = val;
+            if offset == 0x4 {
+                self.reseed(u32::from_be_bytes(self.seed));
+            }
+        }
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u64(self.state);
+        out.bytes(&self.seed);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.state = input.u64()?;
+        self.seed = input.array()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::device::{Interrupts, IRQ_RTC};
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+use crate::vm::CLOCK_HZ;
+
+
+/*
+ * real-time clock, mapped at mmio + RTC_OFFSET
+ *
+ * 0x0: control
+ *      bit 0: write 1 to latch date/time into 0x1-0x7
+ *      bit 1: alarm enable
+ *      bit 2: alarm fired (write 1 to clear)
+ * 0x1: seconds (0-59)
+ * 0x2: minutes (0-59)
+ * 0x3: hours (0-23)
+ * 0x4: day of month (1-31)
+ * 0x5: month (1-12)
+ * 0x6: year - 2000
+ * 0x7: weekday (0 = sunday)
+ * 0x8-0xB: tick counter, 32-bit big-endian, TICK_HZ ticks per second.
+ *      reading 0x8 latches all 4 bytes, reading 0xA latches just the low 16 bits,
+ *      so a 16-bit reader reads 0xA then 0xB and a 32-bit reader reads 0x8..0xB in order
+ * 0xC-0xF: alarm compare, 32-bit big-endian. when the tick counter reaches it
+ *      (and the alarm is enabled) the rtc irq is raised
+ */
+
+pub const RTC_OFFSET: u16 = 0x10;
+pub const RTC_SIZE: u16 = 0x10;
+
+pub const TICK_HZ: u64 = 1000;
+
+// date/time used as "now" at cycle 0 when running off the virtual clock
+const VIRTUAL_EPOCH_SECS: u64 = 946_684_800; // 2000-01-01 00:00:00 UTC
+
+const CTRL_LATCH: u8 = 0b0000_0001;
+const CTRL_ALARM_ENABLE: u8 = 0b0000_0010;
+const CTRL_ALARM_FIRED: u8 = 0b0000_0100;
+
+pub enum ClockSource {
+    Host ( Instant ), // wall clock, ticks count from vm start
+    Virtual, // everything derived from the cycle count, for headless/deterministic runs
+    Replay ( HostReplay ), // a recorded host clock played back, see replay.rs and history.rs
+}
+
+// one reading of the host clock, as the guest saw it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSample {
+    Millis(u64), // since vm start
+    Secs(u64), // unix time, for the date latch
+}
+
+// readings taken while recording or keeping history: (rtc cycle count, sample). a
+// millis reading is only kept when it changed, the guest polls far more often than
+// the clock moves
+pub struct ClockLog {
+    pub samples: Vec<(u64, ClockSample)>,
+    last_millis: Option<u64>,
+}
+
+// readings being fed back. each sample is handed out at the first reading of its
+// kind at or after its cycle, which is the same reading it was taken at
+pub struct HostReplay {
+    samples: VecDeque<(u64, ClockSample)>,
+    millis: u64,
+    secs: u64,
+    live_after: Option<u64>, // back on the host clock from this cycle, once the samples run out
+}
+
+pub struct Rtc {
+    source: ClockSource,
+    control: u8,
+    date: [u8; 7],
+    tick_latch: u32,
+    alarm: [u8; 4],
+    cycles: u64,
+    clock_hz: u64, // cpu cycles per second, for the virtual clock
+    log: Option<ClockLog>, // only while recording or keeping history on a host clock
+}
+
+impl Rtc {
+    pub fn new(source: ClockSource) -> Self {
+        Self {
+            source,
+            control: 0,
+            date: [0; 7],
+            tick_latch: 0,
+            alarm: [0; 4],
+            cycles: 0,
+            clock_hz: CLOCK_HZ,
+            log: None,
+        }
+    }
+
+    pub fn set_clock_hz(&mut self, hz: u64) {
+        self.clock_hz = hz;
+    }
+
+    pub fn host() -> Self {
+        Self::new(ClockSource::Host(Instant::now()))
+    }
+
+    pub fn virtual_clock() -> Self {
+        Self::new(ClockSource::Virtual)
+    }
+
+    pub fn is_host(&self) -> bool {
+        !matches!(self.source, ClockSource::Virtual)
+    }
+
+    pub fn ticks(&mut self) -> u32 {
+        let millis = match &mut self.source {
+            ClockSource::Host(start) => {
+                let millis = start.elapsed().as_millis() as u64;
+                if let Some(log) = &mut self.log && log.last_millis != Some(millis) {
+                    log.last_millis = Some(millis);
+                    log.samples.push((self.cycles, ClockSample::Millis(millis)));
+                }
+                millis
+            },
+            ClockSource::Virtual => return (self.cycles * TICK_HZ / self.clock_hz) as u32,
+            ClockSource::Replay(replay) => {
+                if let Some(&(cycle, ClockSample::Millis(millis))) = replay.samples.front() && cycle <= self.cycles {
+                    replay.millis = millis;
+                    replay.samples.pop_front();
+                }
+                if replay.samples.is_empty() && replay.live_after.is_some_and(|c| self.cycles >= c) {
+                    self.go_live();
+                    return self.ticks();
+                }
+                replay.millis
+            },
+        };
+        (millis * TICK_HZ / 1000) as u32
+    }
+
+    fn unix_secs(&mut self) -> u64 {
+        match &mut self.source {
+            ClockSource::Host(_) => {
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(VIRTUAL_EPOCH_SECS);
+                if let Some(log) = &mut self.log {
+                    log.samples.push((self.cycles, ClockSample::Secs(secs)));
+                }
+                secs
+            },
+            ClockSource::Virtual => VIRTUAL_EPOCH_SECS + self.cycles / self.clock_hz,
+            ClockSource::Replay(replay) => {
+                if let Some(&(cycle, ClockSample::Secs(secs))) = replay.samples.front() && cycle <= self.cycles {
+                    replay.secs = secs;
+                    replay.samples.pop_front();
+                }
+                if replay.samples.is_empty() && replay.live_after.is_some_and(|c| self.cycles >= c) {
+                    self.go_live();
+                    return self.unix_secs();
+                }
+                replay.secs
+            },
+        }
+    }
+
+    // back on the host clock after a replayed stretch, carrying on from the last
+    // replayed reading rather than jumping to however long the vm has really been up
+    fn go_live(&mut self) {
+        if let ClockSource::Replay(replay) = &self.source {
+            let millis = Duration::from_millis(replay.millis);
+            self.source = ClockSource::Host(Instant::now().checked_sub(millis).unwrap_or_else(Instant::now));
+        }
+    }
+
+    // starts logging what the host clock reads (a virtual clock has nothing to log,
+    // it only depends on the cycle count). if it's logging already, the next reading
+    // goes in even if it hasn't changed, so readings taken from here have a start value
+    pub fn record(&mut self) {
+        match &mut self.log {
+            Some(log) => log.last_millis = None,
+            None if matches!(self.source, ClockSource::Host(_)) => self.log = Some(ClockLog { samples: Vec::new(), last_millis: None }),
+            None => (),
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.log = None;
+    }
+
+    pub fn log(&self) -> &[(u64, ClockSample)] {
+        self.log.as_ref().map_or(&[], |log| &log.samples)
+    }
+
+    // drops readings from before cycle, apart from the millis one still current then
+    pub fn trim_log(&mut self, cycle: u64) {
+        if let Some(log) = &mut self.log {
+            let current = log.samples.iter().rposition(|(c, s)| *c < cycle && matches!(s, ClockSample::Millis(_)));
+            let mut idx = 0;
+            log.samples.retain(|(c, _)| {
+                idx += 1;
+                *c >= cycle || Some(idx - 1) == current
+            });
+        }
+    }
+
+    // the guest is taking a different path from cycle on (see history.rs): readings
+    // logged after it never happened, and a replayed clock goes back to the host's
+    pub fn forget_after(&mut self, cycle: u64) {
+        if let Some(log) = &mut self.log {
+            log.samples.retain(|(c, _)| *c < cycle);
+            log.last_millis = None;
+        }
+        if matches!(&self.source, ClockSource::Replay(replay) if replay.live_after.is_some()) {
+            self.go_live();
+        }
+    }
+
+    // runs off a recorded host clock, or the virtual one if the recording was made
+    // on that. whatever the machine was booted with is gone after this
+    pub fn replay(&mut self, host: Option<Vec<(u64, ClockSample)>>) {
+        self.log = None;
+        self.source = match host {
+            Some(samples) => ClockSource::Replay(HostReplay { samples: samples.into(), millis: 0, secs: VIRTUAL_EPOCH_SECS, live_after: None }),
+            None => ClockSource::Virtual,
+        };
+    }
+
+    // after a checkpoint at cycle has been restored: hands out the readings from
+    // samples again, starting with whatever was current at cycle. with live_after
+    // it goes back to the host clock once that cycle is passed
+    pub fn rewind(&mut self, samples: &[(u64, ClockSample)], cycle: u64, live_after: Option<u64>) {
+        let before = |kind: fn(&ClockSample) -> Option<u64>| samples.iter().rev()
+            .filter(|(c, _)| *c < cycle)
+            .find_map(|(_, s)| kind(s));
+        let millis = before(|s| if let ClockSample::Millis(ms) = s { Some(*ms) } else { None }).unwrap_or(0);
+        let secs = before(|s| if let ClockSample::Secs(secs) = s { Some(*secs) } else { None }).unwrap_or(VIRTUAL_EPOCH_SECS);
+        self.source = ClockSource::Replay(HostReplay {
+            samples: samples.iter().filter(|(c, _)| *c >= cycle).copied().collect(),
+            millis,
+            secs,
+            live_after,
+        });
+    }
+
+    fn latch_date(&mut self) {
+        let secs = self.unix_secs();
+        let days = (secs / 86400) as i64;
+        let (year, month, day) = civil_from_days(days);
+        let of_day = secs % 86400;
+
+        self.date = [
+            (of_day % 60) as u8,
+            (of_day / 60 % 60) as u8,
+            (of_day / 3600) as u8,
+            day as u8,
+            month as u8,
+            (year - 2000).clamp(0, 255) as u8,
+            ((days + 4) % 7) as u8, // 1970-01-01 was a thursday
+        ];
+    }
+
+    fn alarm_value(&self) -> u32 {
+        u32::from_be_bytes(self.alarm)
+    }
+
+    pub fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            0x0 => self.control,
+            0x1..=0x7 => self.date[offset as usize - 1],
+            0x8 => {
+                self.tick_latch = self.ticks();
+                (self.tick_latch >> 24) as u8
+            },
+            0x9 => (self.tick_latch >> 16) as u8,
+            0xA => {
+                let ticks = self.ticks();
+                self.tick_latch = (self.tick_latch & 0xFFFF_0000) | (ticks & 0xFFFF);
+                (self.tick_latch >> 8) as u8
+            },
+            0xB => self.tick_latch as u8,
+            0xC..=0xF => self.alarm[offset as usize - 0xC],
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u16, val: u8) {
+        match offset {
+            0x0 => {
+                if val & CTRL_LATCH != 0 {
+                    self.latch_date();
+                }
+                let fired = if val & CTRL_ALARM_FIRED != 0 { 0 } else { self.control & CTRL_ALARM_FIRED };
+                self.control = (val & CTRL_ALARM_ENABLE) | fired;
+            },
+            0xC..=0xF => {
+                self.alarm[offset as usize - 0xC] = val;
+                // re-arm on a new compare value
+                self.control &= !CTRL_ALARM_FIRED;
+            },
+            _ => (),
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u64, irq: &mut Interrupts) {
+        self.cycles = cycles;
+
+        let armed = self.control & CTRL_ALARM_ENABLE != 0 && self.control & CTRL_ALARM_FIRED == 0;
+        if armed && self.ticks() >= self.alarm_value() {
+            self.control |= CTRL_ALARM_FIRED;
+            irq.raise(IRQ_RTC);
+        }
+    }
+
+    // the source itself stays as booted. a host clock carries on counting from the
+    // snapshot's tick count (either kind), so alarms the guest set still line up
+    pub fn save_state(&self, out: &mut StateWriter) {
+        let elapsed = match &self.source {
+            ClockSource::Host(start) => start.elapsed().as_millis() as u64,
+            ClockSource::Virtual => self.cycles * 1000 / self.clock_hz,
+            ClockSource::Replay(replay) => replay.millis,
+        };
+        out.u64(elapsed);
+        out.u8(self.control);
+        out.bytes(&self.date);
+        out.u32(self.tick_latch);
+        out.bytes(&self.alarm);
+        out.u64(self.cycles);
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        let elapsed = Duration::from_millis(input.u64()?);
+        self.control = input.u8()?;
+        self.date = input.array()?;
+        self.tick_latch = input.u32()?;
+        self.alarm = input.array()?;
+        self.cycles = input.u64()?;
+        if let ClockSource::Host(start) = &mut self.source {
+            *start = Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now);
+        }
+        Ok(())
+    }
+}
+
+// days since 1970-01-01 -> (year, month, day), from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,68 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::vc::VideoController;
+
+
+#[derive(Debug)]
+pub struct ScreenshotError {
+    pub message: String,
+}
+
+// writes the video controller's last frame as an rgba png, at the mode's own resolution
+pub fn save_png(video: &VideoController, path: &Path) -> Result<(), ScreenshotError> {
+    write_rgba(path, video.mode.width() as u32, video.mode.height() as u32, &video.rgba())
+}
+
+pub fn write_rgba(path: &Path, width: u32, height: u32, rgba: &[u8]) -> Result<(), ScreenshotError> {
+    let err = |e: &dyn std::fmt::Display| ScreenshotError { message: format!("couldn't write {}: {}", path.display(), e) };
+
+    let file = File::create(path).map_err(|e| err(&e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(|e| err(&e))?;
+    writer.write_image_data(rgba).map_err(|e| err(&e))?;
+    writer.finish().map_err(|e| err(&e))
+}
+
+// (width, height, rgba) of a png written by write_rgba
+pub fn read_rgba(path: &Path) -> Result<(u32, u32, Vec<u8>), ScreenshotError> {
+    let err = |e: &dyn std::fmt::Display| ScreenshotError { message: format!("couldn't read {}: {}", path.display(), e) };
+
+    let file = File::open(path).map_err(|e| err(&e))?;
+    let mut reader = png::Decoder::new(BufReader::new(file)).read_info().map_err(|e| err(&e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|e| err(&e))?;
+    if info.color_type != png::ColorType::Rgba || info.bit_depth != png::BitDepth::Eight {
+        return Err(err(&"not an 8-bit rgba png"));
+    }
+    buf.truncate(info.buffer_size());
+    Ok((info.width, info.height, buf))
+}
+
+// dumps every `every`th frame into a directory as frame_00001.png, frame_00002.png...
+pub struct FrameDumper {
+    dir: PathBuf,
+    every: u64,
+}
+
+impl FrameDumper {
+    pub fn new(dir: &str, every: u64) -> Result<Self, ScreenshotError> {
+        fs::create_dir_all(dir).map_err(|e| ScreenshotError { message: format!("couldn't create {}: {}", dir, e) })?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            every: every.max(1),
+        })
+    }
+
+    // call once per finished frame
+    pub fn frame(&self, video: &VideoController, frame: u64) -> Result<(), ScreenshotError> {
+        if !frame.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        save_png(video, &self.dir.join(format!("frame_{:05}.png", frame)))
+    }
+}
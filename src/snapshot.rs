@@ -0,0 +1,133 @@
+use std::fs;
+
+
+/*
+ * save states. a snapshot is MAGIC, a version byte, then every part of the
+ * machine that isn't rebuilt at boot, each written by its own save_state in
+ * the order Vm::snapshot calls them. all numbers big endian.
+ *
+ * the memory map, loaded programs and symbols aren't in it: a snapshot is
+ * restored into a machine booted the same way, then overwrites ram, cpu and
+ * devices. bump VERSION whenever any save_state changes what it writes
+ */
+
+const MAGIC: &[u8; 6] = b"DNSNAP";
+const VERSION: u8 = 2;
+
+#[derive(Debug)]
+pub struct SnapshotError {
+    pub message: String,
+}
+
+pub struct StateWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        Self { bytes }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // fixed size things, the reader has to know the length
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.bytes.extend_from_slice(data);
+    }
+
+    // things that can change size (the key queue, the last frame), length first
+    pub fn block(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.bytes(data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, SnapshotError> {
+        if data.len() < MAGIC.len() + 1 || &data[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError { message: "not a snapshot".to_string() });
+        }
+        if data[MAGIC.len()] != VERSION {
+            return Err(SnapshotError { message: format!("snapshot version {}, this build reads {}", data[MAGIC.len()], VERSION) });
+        }
+        Ok(Self { data, at: MAGIC.len() + 1 })
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let data: &'a [u8] = self.data;
+        let out = data.get(self.at..self.at + len)
+            .ok_or_else(|| SnapshotError { message: format!("snapshot ends early (at byte {})", self.at) })?;
+        self.at += len;
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn block(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    // restoring a snapshot that doesn't end where it should means the layouts disagree
+    pub fn finish(&self) -> Result<(), SnapshotError> {
+        if self.at != self.data.len() {
+            return Err(SnapshotError { message: format!("{} bytes left over at the end of the snapshot", self.data.len() - self.at) });
+        }
+        Ok(())
+    }
+}
+
+pub fn write_file(path: &str, snapshot: &[u8]) -> Result<(), SnapshotError> {
+    fs::write(path, snapshot).map_err(|e| SnapshotError { message: format!("couldn't write {}: {}", path, e) })
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, SnapshotError> {
+    fs::read(path).map_err(|e| SnapshotError { message: format!("couldn't read {}: {}", path, e) })
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use crate::assembler::Assembled;
+
+
+// how far past a label an address can be and still be shown as label+offset
+const MAX_LABEL_OFFSET: u16 = 0x100;
+
+// labels of every program loaded into the machine, for showing and typing addresses.
+// the same label name shows up in several programs (loop, check_key...), those
+// are told apart as program:label
+pub struct Symbols {
+    entries: Vec<Symbol>, // sorted by address
+    counts: HashMap<String, usize>, // how many programs use each label name
+    lines: Vec<Line>, // sorted by address
+}
+
+struct Symbol {
+    addr: u16,
+    program: String,
+    label: String,
+}
+
+// the source line a run of bytes came from
+struct Line {
+    addr: u16,
+    len: u16,
+    program: String,
+    line: usize,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            counts: HashMap::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    // program is the file path as loaded (src/mouse), only its last part is kept
+    pub fn add_program(&mut self, program: &str, assembled: &Assembled) {
+        let program = program.rsplit(['\\', '/']).next().unwrap_or(program).to_string();
+        if !self.lines.iter().any(|l| l.program == program) {
+            self.lines.extend(assembled.lines.iter().map(|l| Line { addr: l.addr, len: l.len, program: program.clone(), line: l.line }));
+            self.lines.sort_by_key(|l| l.addr);
+        }
+        for (label, addr) in &assembled.labels {
+            if self.entries.iter().any(|s| s.program == program && s.label == *label) {
+                continue; // same program loaded twice
+            }
+            *self.counts.entry(label.clone()).or_insert(0) += 1;
+            self.entries.push(Symbol { addr: *addr, program: program.clone(), label: label.clone() });
+        }
+        self.entries.sort_by(|a, b| a.addr.cmp(&b.addr).then(a.label.cmp(&b.label)));
+    }
+
+    fn name(&self, symbol: &Symbol) -> String {
+        if self.counts.get(&symbol.label).copied().unwrap_or(0) > 1 {
+            format!("{}:{}", symbol.program, symbol.label)
+        }
+        else {
+            symbol.label.clone()
+        }
+    }
+
+    // label or program:label -> address. None if unknown or ambiguous
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        let mut found = self.entries.iter().filter(|s| match name.split_once(':') {
+            Some((program, label)) => s.program == program && s.label == label,
+            None => s.label == name,
+        });
+        let first = found.next()?;
+        if found.next().is_some() { None } else { Some(first.addr) }
+    }
+
+    // nearest label at or below addr, as label or label+0x12
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let idx = self.entries.partition_point(|s| s.addr <= addr);
+        let symbol = self.entries[..idx].last()?;
+        let offset = addr - symbol.addr;
+        if offset > MAX_LABEL_OFFSET {
+            return None;
+        }
+        Some(if offset == 0 { self.name(symbol) } else { format!("{}+0x{:x}", self.name(symbol), offset) })
+    }
+
+    // program.dnasm:line the byte at addr was assembled from
+    pub fn source_line(&self, addr: u16) -> Option<String> {
+        let idx = self.lines.partition_point(|l| l.addr <= addr);
+        let line = self.lines[..idx].iter().rev().find(|l| addr < l.addr.saturating_add(l.len))?;
+        Some(format!("{}.dnasm:{}", line.program, line.line))
+    }
+
+    // 0x1234 <label+0x4>, or just 0x1234
+    pub fn format(&self, addr: u16) -> String {
+        match self.describe(addr) {
+            Some(name) => format!("0x{:04X} <{}>", addr, name),
+            None => format!("0x{:04X}", addr),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::binary::parse_number;
+use crate::bus::{Bus, CURRENT_TASK};
+use crate::cpu::{Access, CPUMode, Cpu};
+use crate::disasm;
+use crate::symbols::Symbols;
+
+
+/*
+ * execution trace: one record per instruction the filter lets through, written
+ * as it runs. the file starts with MAGIC and a version byte, then records:
+ *
+ * u8      what's in it: TRACE_REGS, TRACE_FLAGS, TRACE_SP, TRACE_MODE, TRACE_EXIT, TRACE_MEM
+ * varint  cycles since the previous record
+ * u16     pc (big endian, like everything else here)
+ * u8      task (the byte at CURRENT_TASK), bit 7 set in user mode
+ * u8      instruction length, then its bytes
+ * regs:   u8 mask of changed registers, then the new value of each
+ * flags:  u8 new flags (Flags::bits)
+ * sp:     u16 new sp
+ * mode:   nothing, the mode flipped
+ * exit:   u8 exit id (CPUExit::id), the instruction trapped into the kernel
+ * mem:    u8 count, then u8 0 read / 1 write, u16 address, u8 value for each
+ */
+
+const MAGIC: &[u8; 7] = b"DNTRACE";
+const VERSION: u8 = 1;
+
+const TRACE_REGS: u8 = 1 << 0;
+const TRACE_FLAGS: u8 = 1 << 1;
+const TRACE_SP: u8 = 1 << 2;
+const TRACE_MODE: u8 = 1 << 3;
+const TRACE_EXIT: u8 = 1 << 4;
+const TRACE_MEM: u8 = 1 << 5;
+
+const USER_BIT: u8 = 0x80;
+const MAX_ACCESSES: usize = 0xFF;
+
+// indexed by CPUExit::id
+const EXIT_NAMES: [&str; 8] = ["none", "timer", "halt", "syscall", "illegal instruction", "illegal access", "unknown action", "interrupt"];
+
+#[derive(Debug)]
+pub struct TraceError {
+    pub message: String,
+}
+
+// which instructions get recorded. everything left as None matches
+pub struct TraceFilter {
+    pub range: Option<RangeInclusive<u16>>, // pc
+    pub task: Option<u8>,
+    pub mode: Option<CPUMode>,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        Self {
+            range: None,
+            task: None,
+            mode: None,
+        }
+    }
+
+    // first-last, both inclusive
+    pub fn parse_range(text: &str) -> Option<RangeInclusive<u16>> {
+        let (first, last) = text.split_once('-')?;
+        let first = parse_number(first).filter(|n| *n <= 0xFFFF)? as u16;
+        let last = parse_number(last).filter(|n| *n <= 0xFFFF)? as u16;
+        Some(first..=last)
+    }
+
+    fn matches(&self, pc: u16, task: u8, mode: CPUMode) -> bool {
+        self.range.as_ref().is_none_or(|r| r.contains(&pc))
+            && self.task.is_none_or(|t| t == task)
+            && self.mode.is_none_or(|m| m == mode)
+    }
+}
+
+// the cpu just before an instruction, to diff against afterwards
+pub struct Before {
+    pc: u16,
+    sp: u16,
+    regs: [u8; 8],
+    flags: u8,
+    mode: CPUMode,
+    task: u8,
+    wanted: bool,
+}
+
+pub struct Tracer {
+    out: BufWriter<File>,
+    filter: TraceFilter,
+    last_cycle: u64,
+    pub records: u64,
+}
+
+impl Tracer {
+    pub fn create(path: &str, filter: TraceFilter) -> Result<Self, TraceError> {
+        let mut out = File::create(path)
+            .map(BufWriter::new)
+            .map_err(|e| TraceError { message: format!("couldn't create {}: {}", path, e) })?;
+        out.write_all(MAGIC)
+            .and_then(|()| out.write_all(&[VERSION]))
+            .map_err(|e| TraceError { message: format!("couldn't write {}: {}", path, e) })?;
+
+        Ok(Self {
+            out,
+            filter,
+            last_cycle: 0,
+            records: 0,
+        })
+    }
+
+    pub fn before(&self, cpu: &Cpu, mem: &Bus) -> Before {
+        let task = mem.ram()[CURRENT_TASK as usize];
+        Before {
+            pc: cpu.pc,
+            sp: cpu.sp,
+            regs: cpu.regs,
+            flags: cpu.flags.bits(),
+            mode: cpu.mode,
+            task,
+            wanted: self.filter.matches(cpu.pc, task, cpu.mode),
+        }
+    }
+
+    // writes the record for the instruction that just ran. the bus's access log is
+    // emptied either way
+    pub fn record(&mut self, before: &Before, cpu: &Cpu, mem: &mut Bus) -> io::Result<()> {
+        let mut accesses = mem.take_accesses();
+        if !before.wanted {
+            return Ok(());
+        }
+        let ram = mem.ram();
+        let len = disasm::length(ram, before.pc);
+
+        // operands are fetched as reads too, they're already in the instruction bytes
+        let fetched = before.pc as u32..before.pc as u32 + len as u32;
+        accesses.retain(|(addr, access, _)| *access != Access::R || !fetched.contains(&(*addr as u32)));
+
+        let mut kinds = 0;
+        let changed: Vec<usize> = (0..8).filter(|r| cpu.regs[*r] != before.regs[*r]).collect();
+        if !changed.is_empty() { kinds |= TRACE_REGS; }
+        if cpu.flags.bits() != before.flags { kinds |= TRACE_FLAGS; }
+        if cpu.sp != before.sp { kinds |= TRACE_SP; }
+        if cpu.mode != before.mode { kinds |= TRACE_MODE; }
+        if cpu.last_exit.is_some() { kinds |= TRACE_EXIT; }
+        if !accesses.is_empty() { kinds |= TRACE_MEM; }
+
+        let mut rec = vec![kinds];
+        push_varint(&mut rec, cpu.cycles - self.last_cycle);
+        self.last_cycle = cpu.cycles;
+        rec.extend_from_slice(&before.pc.to_be_bytes());
+        rec.push(before.task & !USER_BIT | if before.mode == CPUMode::U { USER_BIT } else { 0 });
+
+        // the bytes as they were run, unless the instruction rewrote itself
+        rec.push(len as u8);
+        rec.extend((0..len).map(|i| ram[before.pc.wrapping_add(i) as usize]));
+
+        if kinds & TRACE_REGS != 0 {
+            rec.push(changed.iter().fold(0, |mask, r| mask | 1 << r));
+            rec.extend(changed.iter().map(|r| cpu.regs[*r]));
+        }
+        if kinds & TRACE_FLAGS != 0 {
+            rec.push(cpu.flags.bits());
+        }
+        if kinds & TRACE_SP != 0 {
+            rec.extend_from_slice(&cpu.sp.to_be_bytes());
+        }
+        if let Some(exit) = &cpu.last_exit {
+            rec.push(exit.id());
+        }
+        if kinds & TRACE_MEM != 0 {
+            rec.push(accesses.len().min(MAX_ACCESSES) as u8);
+            for (addr, access, value) in accesses.iter().take(MAX_ACCESSES) {
+                // writes through a destination operand land after the log entry, read them back
+                let (kind, value) = if *access == Access::R { (0, *value) } else { (1, ram[*addr as usize]) };
+                rec.push(kind);
+                rec.extend_from_slice(&addr.to_be_bytes());
+                rec.push(value);
+            }
+        }
+
+        self.records += 1;
+        self.out.write_all(&rec)
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn push_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+// reads a trace back and writes it out one instruction per line
+pub fn render(path: &Path, symbols: &Symbols, out: &mut dyn Write) -> Result<u64, TraceError> {
+    let err = |what: String| TraceError { message: format!("{}: {}", path.display(), what) };
+    let data = fs::read(path).map_err(|e| err(e.to_string()))?;
+    if data.len() < MAGIC.len() + 1 || &data[..MAGIC.len()] != MAGIC {
+        return Err(err("not a trace file".to_string()));
+    }
+    if data[MAGIC.len()] != VERSION {
+        return Err(err(format!("trace version {}, this build reads {}", data[MAGIC.len()], VERSION)));
+    }
+
+    let mut reader = Reader { data: &data, at: MAGIC.len() + 1 };
+    let mut scratch = vec![0u8; 0x10000]; // disassemble works on the whole address space
+    let mut cycle = 0u64;
+    let mut records = 0;
+
+    while reader.at < data.len() {
+        let truncated = || err(format!("truncated after {} records", records));
+        let kinds = reader.byte().ok_or_else(truncated)?;
+        cycle += reader.varint().ok_or_else(truncated)?;
+        let pc = reader.word().ok_or_else(truncated)?;
+        let task = reader.byte().ok_or_else(truncated)?;
+        let len = reader.byte().ok_or_else(truncated)?;
+        for i in 0..len {
+            scratch[pc.wrapping_add(i as u16) as usize] = reader.byte().ok_or_else(truncated)?;
+        }
+        let inst = disasm::disassemble(&scratch, pc, symbols);
+
+        let mut changes: Vec<String> = Vec::new();
+        if kinds & TRACE_REGS != 0 {
+            let mask = reader.byte().ok_or_else(truncated)?;
+            for r in (0..8).filter(|r| mask & 1 << r != 0) {
+                changes.push(format!("r{}={:02X}", r, reader.byte().ok_or_else(truncated)?));
+            }
+        }
+        if kinds & TRACE_FLAGS != 0 {
+            let bits = reader.byte().ok_or_else(truncated)?;
+            let flag = |bit: u8, c: char| if bits & bit != 0 { c.to_ascii_uppercase() } else { c };
+            changes.push(format!("flags={}{}{}{}", flag(4, 'z'), flag(1, 'c'), flag(2, 's'), flag(8, 'o')));
+        }
+        if kinds & TRACE_SP != 0 {
+            changes.push(format!("sp={:04X}", reader.word().ok_or_else(truncated)?));
+        }
+        let user = task & USER_BIT != 0;
+        let exit = if kinds & TRACE_EXIT != 0 { Some(reader.byte().ok_or_else(truncated)?) } else { None };
+        if kinds & TRACE_MEM != 0 {
+            let count = reader.byte().ok_or_else(truncated)?;
+            for _ in 0..count {
+                let kind = reader.byte().ok_or_else(truncated)?;
+                let addr = reader.word().ok_or_else(truncated)?;
+                let value = reader.byte().ok_or_else(truncated)?;
+                changes.push(format!("{}[{:04X}]={:02X}", if kind == 0 { "R" } else { "W" }, addr, value));
+            }
+        }
+        match exit {
+            Some(id) => changes.push(format!("-> kernel ({})", EXIT_NAMES.get(id as usize).unwrap_or(&"?"))),
+            None if kinds & TRACE_MODE != 0 => changes.push(format!("-> {}", if user { "kernel" } else { "user" })),
+            None => (),
+        }
+
+        let _ = writeln!(out, "{:>10}  {}{}  {:<32} {:<28} {}",
+            cycle, if user { 'U' } else { 'K' }, task & !USER_BIT, symbols.format(pc), inst.text, changes.join(" "));
+        records += 1;
+    }
+    Ok(records)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.data.get(self.at)?;
+        self.at += 1;
+        Some(b)
+    }
+
+    fn word(&mut self) -> Option<u16> {
+        Some((self.byte()? as u16) << 8 | self.byte()? as u16)
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            n |= ((b & 0x7F) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+}
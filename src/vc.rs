@@ -1,27 +1,571 @@
 
-/**
- * for 128x128 black/white pixels,
- * 2048 bytes -> 2^11 - 2^12 (2048-4096)
+use crate::charset;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+/*
+ * video registers, mapped at mmio + VIDEO_OFFSET
+ *
+ * 0x0: mode
+ *      0: 128x128, 2bpp (default)
+ *      1: 128x64, 4bpp
+ *      2: 64x64, 4bpp
+ *      3: 64x64, 8bpp
+ *      4: 16x16 text, 8x8 glyphs (128x128)
+ *      anything else shows as mode 0
+ * 0x1: cursor column (text mode)
+ * 0x2: cursor row (text mode)
+ * 0x3: cursor control, bit 0: show (drawn by swapping the cell's colours)
+ * 0x4: layers, bit 0: tile layer on, bit 1: sprites on
+ * 0x5-0x6: pattern table address, big-endian
+ * 0x7-0x8: tile map address, big-endian
+ * 0x9: tile layer scroll x
+ * 0xA: tile layer scroll y
+ * 0xB: tile layer palette bank (0-3)
+ * 0xC: sprite status for the last frame drawn (read only)
+ *      bit 0: collision, two sprites had solid pixels on the same spot
+ *      bit 1: overflow, more than SPRITES_PER_LINE sprites on a line (the extras are dropped)
+ * 0xD: beam status (read only), bit 0: in vblank
+ * 0xE: frame counter (read only), goes up by one at the start of every vblank
+ * 0xF: display page. vram is split into pages the size of one frame in the current mode
+ *      (4 KB in mode 0, 2 KB in mode 2, 512 bytes in text mode...) and this picks which
+ *      one is shown, wrapping if it's past the last page. draw into one, flip to it in vblank
+ *
+ * sprite attribute table, mapped at mmio + SPRITE_OFFSET
+ *      SPRITES entries of 4 bytes: y, x, pattern, flags. sprite 0 is drawn on top
+ *      flags bit 0: flip horizontally
+ *            bit 1: flip vertically
+ *            bit 2: behind the tile layer
+ *            bit 3: enable
+ *            bits 4-5: palette bank
+ *      x/y from 248 up count as -8..-1 so a sprite can slide off the top/left edge
+ *
+ * patterns are 8x8 at 2bpp (16 bytes, 2 per row, msb first), pixel value 0 is transparent.
+ * a palette bank picks entries bank * 4 .. bank * 4 + 3 for values 0-3.
+ * the tile map is 16x16 pattern indices (a 128x128 layer) that wraps when scrolled.
+ *
+ * window table, mapped at mmio + WINDOW_OFFSET
+ *      one 8 byte entry per user task, entry n shows task n's own vram (128x128, 2bpp,
+ *      same packing as mode 0). the kernel fills this in, it's the window manager
+ *      0: flags, bit 0: visible, bits 4-5: palette bank
+ *      1: x, 2: y (top left corner on screen)
+ *      3: width, 4: height (0 means 128)
+ *      5: z, higher is in front (ties go to the higher task)
+ *      6: source x, 7: source y (which part of the task's vram is in the window, wraps)
+ *
+ * layers go framebuffer, then windows, then tiles, then sprites, on top of any mode
+ *
+ * palette ram, mapped at mmio + PALETTE_OFFSET
+ *      256 entries of r, g, b (one byte each). a pixel's value is its palette index,
+ *      so 2bpp uses entries 0-3, 4bpp 0-15 and 8bpp all of them
+ *
+ * pixels are packed msb first, left to right, rows top to bottom from the start of vram
+ *
+ * text mode cells are 2 bytes, char code then attribute, row by row from the start of vram.
+ * the attribute's low nibble is the foreground palette index, high nibble the background.
+ * char codes follow charset.rs, glyphs come from the font (built-in, or loaded from a file)
  */
 
+pub const VIDEO_OFFSET: u16 = 0x60;
+pub const VIDEO_SIZE: u16 = 0x20;
+pub const PALETTE_OFFSET: u16 = 0x100;
+pub const PALETTE_SIZE: u16 = 0x300;
+
+pub const SPRITE_OFFSET: u16 = 0x80;
+pub const SPRITE_SIZE: u16 = 0x40;
+
+pub const WINDOW_OFFSET: u16 = 0xC0;
+pub const WINDOW_SIZE: u16 = 0x20;
+
+pub const VRAM_SIZE: usize = 0x1000;
+
+pub const SPRITES: usize = 16;
+pub const SPRITES_PER_LINE: usize = 8;
+const PATTERN_SIZE: usize = 8;
+const PATTERN_BYTES: usize = 16;
+const TILE_MAP_SIZE: usize = 16;
+const WINDOW_ENTRY: usize = 8;
+pub const WINDOWS: usize = WINDOW_SIZE as usize / WINDOW_ENTRY; // one per task
+const TASK_VRAM_SIZE: usize = 128;
+
+pub const TEXT_COLS: usize = 16;
+pub const TEXT_ROWS: usize = 16;
+const GLYPH_SIZE: usize = 8;
+
+const REG_MODE: usize = 0x0;
+const REG_CURSOR_X: usize = 0x1;
+const REG_CURSOR_Y: usize = 0x2;
+const REG_CURSOR_CTRL: usize = 0x3;
+const REG_LAYERS: usize = 0x4;
+const REG_PATTERN_BASE: usize = 0x5;
+const REG_MAP_BASE: usize = 0x7;
+const REG_SCROLL_X: usize = 0x9;
+const REG_SCROLL_Y: usize = 0xA;
+const REG_TILE_BANK: usize = 0xB;
+pub const REG_SPRITE_STATUS: u16 = 0xC;
+pub const REG_BEAM_STATUS: u16 = 0xD;
+pub const REG_FRAME_COUNT: u16 = 0xE;
+const REG_PAGE: usize = 0xF;
+
+const CURSOR_SHOW: u8 = 0b0000_0001;
+
+const LAYER_TILES: u8 = 0b0000_0001;
+const LAYER_SPRITES: u8 = 0b0000_0010;
+
+const SPRITE_HFLIP: u8 = 0b0000_0001;
+const SPRITE_VFLIP: u8 = 0b0000_0010;
+const SPRITE_BEHIND: u8 = 0b0000_0100;
+const SPRITE_ENABLE: u8 = 0b0000_1000;
+
+const STATUS_COLLISION: u8 = 0b0000_0001;
+const STATUS_OVERFLOW: u8 = 0b0000_0010;
+
+const WINDOW_VISIBLE: u8 = 0b0000_0001;
+
+pub const BEAM_VBLANK: u8 = 0b0000_0001;
+
+// everything in the mmio video block that's plain memory the controller reads each frame
+pub fn is_video_register(offset: u16) -> bool {
+    (VIDEO_OFFSET..VIDEO_OFFSET + VIDEO_SIZE).contains(&offset)
+        || (SPRITE_OFFSET..SPRITE_OFFSET + SPRITE_SIZE).contains(&offset)
+        || (WINDOW_OFFSET..WINDOW_OFFSET + WINDOW_SIZE).contains(&offset)
+        || (PALETTE_OFFSET..PALETTE_OFFSET + PALETTE_SIZE).contains(&offset)
+}
+
+#[derive(Debug)]
+pub struct FontError {
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum VideoMode {
+    Gray128,  // 128x128 2bpp
+    Color128, // 128x64 4bpp
+    Color64,  // 64x64 4bpp
+    Full64,   // 64x64 8bpp
+    Text,     // 16x16 cells
+}
+
+impl VideoMode {
+    pub fn from_reg(val: u8) -> Self {
+        match val {
+            1 => VideoMode::Color128,
+            2 => VideoMode::Color64,
+            3 => VideoMode::Full64,
+            4 => VideoMode::Text,
+            _ => VideoMode::Gray128,
+        }
+    }
+
+    pub fn to_reg(self) -> u8 {
+        match self {
+            VideoMode::Gray128 => 0,
+            VideoMode::Color128 => 1,
+            VideoMode::Color64 => 2,
+            VideoMode::Full64 => 3,
+            VideoMode::Text => 4,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        match self {
+            VideoMode::Gray128 | VideoMode::Color128 | VideoMode::Text => 128,
+            VideoMode::Color64 | VideoMode::Full64 => 64,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            VideoMode::Gray128 | VideoMode::Text => 128,
+            VideoMode::Color128 | VideoMode::Color64 | VideoMode::Full64 => 64,
+        }
+    }
+
+    // bits per pixel for the bitmap modes
+    pub fn bpp(&self) -> usize {
+        match self {
+            VideoMode::Gray128 => 2,
+            VideoMode::Color128 | VideoMode::Color64 => 4,
+            VideoMode::Full64 => 8,
+            VideoMode::Text => 0,
+        }
+    }
+
+    // bytes of vram one frame takes up
+    pub fn frame_size(&self) -> usize {
+        match self {
+            VideoMode::Text => TEXT_COLS * TEXT_ROWS * 2,
+            _ => self.width() * self.height() * self.bpp() / 8,
+        }
+    }
+}
+
+// 0-3 is the old grey ramp so 2bpp programs look the same as before,
+// 4-15 round out a 16 colour set and 16-255 are rgb332 of the index
+pub fn default_palette() -> [u8; PALETTE_SIZE as usize] {
+    const SIXTEEN: [[u8; 3]; 16] = [
+        [0x00, 0x00, 0x00], [0x55, 0x55, 0x55], [0xAA, 0xAA, 0xAA], [0xFF, 0xFF, 0xFF],
+        [0xAA, 0x00, 0x00], [0x00, 0xAA, 0x00], [0x00, 0x00, 0xAA], [0xAA, 0x55, 0x00],
+        [0xFF, 0x55, 0x55], [0x55, 0xFF, 0x55], [0x55, 0x55, 0xFF], [0xFF, 0xFF, 0x55],
+        [0x00, 0xAA, 0xAA], [0xAA, 0x00, 0xAA], [0x55, 0xFF, 0xFF], [0xFF, 0x55, 0xFF],
+    ];
+
+    let mut palette = [0; PALETTE_SIZE as usize];
+    for idx in 0..256 {
+        let rgb = match SIXTEEN.get(idx) {
+            Some(rgb) => *rgb,
+            None => {
+                let i = idx as u32;
+                [((i >> 5) * 255 / 7) as u8, ((i >> 2 & 0b111) * 255 / 7) as u8, ((i & 0b11) * 255 / 3) as u8]
+            },
+        };
+        palette[idx * 3..idx * 3 + 3].copy_from_slice(&rgb);
+    }
+    palette
+}
 
 pub struct VideoController {
     pub framebuffer: Vec<u8>,
     pub vram_base: u16,
+    pub mmio_base: u16,
+    task_vram: Vec<u16>, // base of each task's vram, indexed like the window table
+    pub mode: VideoMode,
+    palette: [u32; 256],
+    font: Vec<[u8; GLYPH_SIZE]>, // 256 glyphs
+    cursor: (usize, usize),
+    cursor_shown: bool,
+    layers: u8,
+    pattern_base: u16,
+    map_base: u16,
+    scroll: (usize, usize),
+    tile_bank: u8,
+    page: usize,
+    pub sprite_status: u8,
+    pub pixels: Vec<u32>, // rendered frame, 0RGB, mode.width() * mode.height()
 }
 
 
 impl VideoController {
-    pub fn new(width: usize, height: usize, vram_base: u16) -> Self {
+    pub fn new(vram_base: u16, mmio_base: u16, task_vram: Vec<u16>) -> Self {
+        let mode = VideoMode::Gray128;
         Self {
-            framebuffer: vec![0; (width * height) / 4],
+            framebuffer: vec![0; VRAM_SIZE],
             vram_base,
+            mmio_base,
+            task_vram,
+            mode,
+            palette: [0; 256],
+            font: default_font(),
+            cursor: (0, 0),
+            cursor_shown: false,
+            layers: 0,
+            pattern_base: 0,
+            map_base: 0,
+            scroll: (0, 0),
+            tile_bank: 0,
+            page: 0,
+            sprite_status: 0,
+            pixels: vec![0; mode.width() * mode.height()],
         }
     }
 
     pub fn update_framebuffer(&mut self, mem: &[u8]) {
-        for i in 0..self.framebuffer.len() {
-            self.framebuffer[i] = mem[i];
+        let len = self.framebuffer.len().min(mem.len());
+        self.framebuffer[..len].copy_from_slice(&mem[..len]);
+    }
+
+    // where the displayed page starts, as an offset into vram
+    pub fn page_start(&self) -> usize {
+        let size = self.mode.frame_size();
+        (self.page % (VRAM_SIZE / size)) * size
+    }
+
+    pub fn update_registers(&mut self, regs: &[u8]) {
+        let mode = VideoMode::from_reg(regs[REG_MODE]);
+        if mode != self.mode {
+            self.mode = mode;
+            self.pixels = vec![0; mode.width() * mode.height()];
+        }
+        self.cursor = (regs[REG_CURSOR_X] as usize, regs[REG_CURSOR_Y] as usize);
+        self.cursor_shown = regs[REG_CURSOR_CTRL] & CURSOR_SHOW != 0;
+        self.layers = regs[REG_LAYERS];
+        self.pattern_base = u16::from_be_bytes([regs[REG_PATTERN_BASE], regs[REG_PATTERN_BASE + 1]]);
+        self.map_base = u16::from_be_bytes([regs[REG_MAP_BASE], regs[REG_MAP_BASE + 1]]);
+        self.scroll = (regs[REG_SCROLL_X] as usize, regs[REG_SCROLL_Y] as usize);
+        self.tile_bank = regs[REG_TILE_BANK] & 0b11;
+        self.page = regs[REG_PAGE] as usize;
+    }
+
+    // font rom image: 8 bytes per glyph starting at code 0, up to 256 glyphs.
+    // glyphs past the end of the file keep the built-in ones
+    pub fn load_font(&mut self, rom: &[u8]) -> Result<(), FontError> {
+        if !rom.len().is_multiple_of(GLYPH_SIZE) || rom.len() > self.font.len() * GLYPH_SIZE {
+            return Err(FontError { message: format!("font rom is {} bytes, expected up to 256 glyphs of {} bytes", rom.len(), GLYPH_SIZE) });
+        }
+        for (glyph, rows) in self.font.iter_mut().zip(rom.chunks(GLYPH_SIZE)) {
+            glyph.copy_from_slice(rows);
+        }
+        Ok(())
+    }
+
+    pub fn update_palette(&mut self, palette_ram: &[u8]) {
+        for (entry, rgb) in self.palette.iter_mut().zip(palette_ram.chunks(3)) {
+            *entry = (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32;
+        }
+    }
+
+    // everything pulled off the bus for the last frame, and the frame itself, so a
+    // restored machine shows what it was showing. the bases come from the memory map
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.u8(self.mode.to_reg());
+        for colour in &self.palette {
+            out.u32(*colour);
         }
+        out.bytes(&self.font.concat());
+        out.u8(self.cursor.0 as u8);
+        out.u8(self.cursor.1 as u8);
+        out.bool(self.cursor_shown);
+        out.u8(self.layers);
+        out.u16(self.pattern_base);
+        out.u16(self.map_base);
+        out.u8(self.scroll.0 as u8);
+        out.u8(self.scroll.1 as u8);
+        out.u8(self.tile_bank);
+        out.u8(self.page as u8);
+        out.u8(self.sprite_status);
+        out.bytes(&self.framebuffer);
+        for pixel in &self.pixels {
+            out.u32(*pixel);
+        }
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), SnapshotError> {
+        self.mode = VideoMode::from_reg(input.u8()?);
+        for colour in self.palette.iter_mut() {
+            *colour = input.u32()?;
+        }
+        for glyph in self.font.iter_mut() {
+            *glyph = input.array()?;
+        }
+        self.cursor = (input.u8()? as usize, input.u8()? as usize);
+        self.cursor_shown = input.bool()?;
+        self.layers = input.u8()?;
+        self.pattern_base = input.u16()?;
+        self.map_base = input.u16()?;
+        self.scroll = (input.u8()? as usize, input.u8()? as usize);
+        self.tile_bank = input.u8()?;
+        self.page = input.u8()? as usize;
+        self.sprite_status = input.u8()?;
+        self.framebuffer.copy_from_slice(input.bytes(VRAM_SIZE)?);
+        self.pixels = vec![0; self.mode.width() * self.mode.height()];
+        for pixel in self.pixels.iter_mut() {
+            *pixel = input.u32()?;
+        }
+        Ok(())
+    }
+
+    // the last rendered frame as rgba bytes, row by row, for screenshots
+    pub fn rgba(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            out.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8, 0xFF]);
+        }
+        out
+    }
+
+    // decodes the framebuffer into pixels for the current mode, then draws the
+    // tile and sprite layers over it. ram is all of memory, patterns and the tile map live there
+    pub fn render(&mut self, ram: &[u8]) {
+        match self.mode {
+            VideoMode::Text => self.render_text(),
+            _ => self.render_bitmap(),
+        }
+        self.render_layers(ram);
+    }
+
+    fn render_bitmap(&mut self) {
+        let bpp = self.mode.bpp();
+        let per_byte = 8 / bpp;
+        let mask = ((1u16 << bpp) - 1) as u8;
+
+        for (idx, pixel) in self.pixels.iter_mut().enumerate() {
+            let byte = self.framebuffer[idx / per_byte];
+            let shift = 8 - bpp * (idx % per_byte + 1);
+            let value = (byte >> shift) & mask;
+            *pixel = self.palette[value as usize];
+        }
+    }
+
+    fn render_text(&mut self) {
+        let width = self.mode.width();
+
+        for row in 0..TEXT_ROWS {
+            for col in 0..TEXT_COLS {
+                let cell = (row * TEXT_COLS + col) * 2;
+                let glyph = &self.font[self.framebuffer[cell] as usize];
+                let attr = self.framebuffer[cell + 1];
+
+                let mut fg = self.palette[(attr & 0xF) as usize];
+                let mut bg = self.palette[(attr >> 4) as usize];
+                if self.cursor_shown && self.cursor == (col, row) {
+                    std::mem::swap(&mut fg, &mut bg);
+                }
+
+                for (y, bits) in glyph.iter().enumerate() {
+                    let line = (row * GLYPH_SIZE + y) * width + col * GLYPH_SIZE;
+                    for x in 0..GLYPH_SIZE {
+                        self.pixels[line + x] = if bits & (0x80 >> x) != 0 { fg } else { bg };
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl VideoController {
+    // 2-bit value of pixel (x, y) of a pattern
+    fn pattern_pixel(&self, ram: &[u8], pattern: u8, x: usize, y: usize) -> u8 {
+        let row = self.pattern_base as usize + pattern as usize * PATTERN_BYTES + y * 2;
+        let bits = u16::from_be_bytes([ram[row & 0xFFFF], ram[(row + 1) & 0xFFFF]]);
+        (bits >> (14 - x * 2)) as u8 & 0b11
+    }
+
+    fn bank_colour(&self, bank: u8, value: u8) -> u32 {
+        self.palette[(bank * 4 + value) as usize]
     }
-}
\ No newline at end of file
+
+    fn render_layers(&mut self, ram: &[u8]) {
+        let width = self.mode.width();
+        let height = self.mode.height();
+        let mut tile_solid = vec![false; width * height];
+
+        self.render_windows(ram);
+
+        if self.layers & LAYER_TILES != 0 {
+            let layer_size = TILE_MAP_SIZE * PATTERN_SIZE;
+            for y in 0..height {
+                for x in 0..width {
+                    let lx = (x + self.scroll.0) % layer_size;
+                    let ly = (y + self.scroll.1) % layer_size;
+                    let entry = self.map_base as usize + (ly / PATTERN_SIZE) * TILE_MAP_SIZE + lx / PATTERN_SIZE;
+                    let value = self.pattern_pixel(ram, ram[entry & 0xFFFF], lx % PATTERN_SIZE, ly % PATTERN_SIZE);
+                    if value != 0 {
+                        self.pixels[y * width + x] = self.bank_colour(self.tile_bank, value);
+                        tile_solid[y * width + x] = true;
+                    }
+                }
+            }
+        }
+
+        self.sprite_status = 0;
+        if self.layers & LAYER_SPRITES == 0 {
+            return;
+        }
+
+        let table = self.mmio_base as usize + SPRITE_OFFSET as usize;
+        let sprites: Vec<&[u8]> = ram[table..table + SPRITES * 4].chunks(4).collect();
+
+        // hand out line slots in priority order, so it's the low-priority sprites that drop out
+        let mut line_counts = vec![0; height];
+        let mut rows_shown = [[false; PATTERN_SIZE]; SPRITES];
+        for (idx, attr) in sprites.iter().enumerate() {
+            if attr[3] & SPRITE_ENABLE == 0 {
+                continue;
+            }
+            for (row, shown) in rows_shown[idx].iter_mut().enumerate() {
+                let Some(y) = sprite_coord(attr[0], row, height) else { continue };
+                if line_counts[y] < SPRITES_PER_LINE {
+                    line_counts[y] += 1;
+                    *shown = true;
+                }
+                else {
+                    self.sprite_status |= STATUS_OVERFLOW;
+                }
+            }
+        }
+
+        // back to front so sprite 0 ends up on top
+        let mut sprite_solid = vec![false; width * height];
+        for idx in (0..SPRITES).rev() {
+            let attr = sprites[idx];
+            let flags = attr[3];
+            let bank = (flags >> 4) & 0b11;
+
+            for (row, shown) in rows_shown[idx].iter().enumerate() {
+                if !shown {
+                    continue;
+                }
+                let Some(y) = sprite_coord(attr[0], row, height) else { continue };
+                let py = if flags & SPRITE_VFLIP != 0 { PATTERN_SIZE - 1 - row } else { row };
+
+                for col in 0..PATTERN_SIZE {
+                    let Some(x) = sprite_coord(attr[1], col, width) else { continue };
+                    let px = if flags & SPRITE_HFLIP != 0 { PATTERN_SIZE - 1 - col } else { col };
+                    let value = self.pattern_pixel(ram, attr[2], px, py);
+                    if value == 0 {
+                        continue;
+                    }
+
+                    let at = y * width + x;
+                    if sprite_solid[at] {
+                        self.sprite_status |= STATUS_COLLISION;
+                    }
+                    sprite_solid[at] = true;
+                    if flags & SPRITE_BEHIND != 0 && tile_solid[at] {
+                        continue;
+                    }
+                    self.pixels[at] = self.bank_colour(bank, value);
+                }
+            }
+        }
+    }
+}
+
+impl VideoController {
+    fn render_windows(&mut self, ram: &[u8]) {
+        let width = self.mode.width();
+        let height = self.mode.height();
+
+        let table = self.mmio_base as usize + WINDOW_OFFSET as usize;
+        // tasks past the end of the table run without a window
+        let entries = self.task_vram.len().min(WINDOWS);
+        let mut windows: Vec<(usize, &[u8])> = ram[table..table + entries * WINDOW_ENTRY]
+            .chunks(WINDOW_ENTRY)
+            .enumerate()
+            .filter(|(_, entry)| entry[0] & WINDOW_VISIBLE != 0)
+            .collect();
+        // back to front
+        windows.sort_by_key(|(task, entry)| (entry[5], *task));
+
+        for (task, entry) in windows {
+            let base = self.task_vram[task] as usize;
+            let bank = (entry[0] >> 4) & 0b11;
+            let (left, top) = (entry[1] as usize, entry[2] as usize);
+            let win_width = if entry[3] == 0 { TASK_VRAM_SIZE } else { entry[3] as usize };
+            let win_height = if entry[4] == 0 { TASK_VRAM_SIZE } else { entry[4] as usize };
+            let (src_x, src_y) = (entry[6] as usize, entry[7] as usize);
+
+            for wy in 0..win_height.min(height.saturating_sub(top)) {
+                let ty = (src_y + wy) % TASK_VRAM_SIZE;
+                for wx in 0..win_width.min(width.saturating_sub(left)) {
+                    let tx = (src_x + wx) % TASK_VRAM_SIZE;
+                    let byte = ram[base + (ty * TASK_VRAM_SIZE + tx) / 4];
+                    let value = (byte >> (6 - (tx % 4) * 2)) & 0b11;
+                    self.pixels[(top + wy) * width + left + wx] = self.bank_colour(bank, value);
+                }
+            }
+        }
+    }
+}
+
+// screen coordinate of pixel `offset` of a sprite at `pos`, None if it's off screen
+fn sprite_coord(pos: u8, offset: usize, limit: usize) -> Option<usize> {
+    let start = if pos >= 248 { pos as isize - 256 } else { pos as isize };
+    let coord = start + offset as isize;
+    if coord >= 0 && (coord as usize) < limit { Some(coord as usize) } else { None }
+}
+
+fn default_font() -> Vec<[u8; GLYPH_SIZE]> {
+    let mut font = vec![[0; GLYPH_SIZE]; 256];
+    font[..charset::GLYPHS].copy_from_slice(&charset::FONT);
+    font
+}
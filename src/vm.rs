@@ -1,25 +1,80 @@
+use std::collections::VecDeque;
+
 use crate::cpu::Cpu;
 use crate::bus::Bus;
-use crate::vc::VideoController;
+use crate::vc::{self, VideoController};
+use crate::device::IRQ_VBLANK;
+use crate::history::{History, HistoryError};
+use crate::profile::Profiler;
+use crate::replay::{self, Recording};
+use crate::rtc::ClockSample;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+use crate::symbols::Symbols;
+use crate::trace::Tracer;
+
 
+// default virtual clock rate (the machine config can change it); one instruction is one cycle
+pub const CLOCK_HZ: u64 = 1_000_000;
 
+// frames come out at a fixed rate of virtual time, whatever speed the host runs at.
+// the last tenth of every frame is vblank; the frame is captured as it starts
+pub const FRAME_HZ: u64 = 60;
+const VBLANK_FRACTION: u64 = 10;
 
 
 pub struct Vm {
     pub mem: Bus,
     pub cpu: Cpu,
     pub video: VideoController,
+    pub frames: u64,
+    pub symbols: Symbols, // labels of the loaded programs, for the debugger
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    recording: Option<Recording>,
+    recording_since: u64,
+    replaying: VecDeque<(u64, u8)>, // recorded keys still to come
+    replay_clock: Option<Vec<(u64, ClockSample)>>, // all of a replayed host clock, for going back over it
+    history: Option<History>,
+    refeed: VecDeque<(u64, u8)>, // logged keys coming round again after going back
+    cycles_per_frame: u64,
+    in_vblank: bool,
+    frame_ready: bool,
 }
 
 impl Vm {
-    pub fn new(mem: Bus, video: VideoController, cpu: Cpu) -> Self {
+    pub fn new(mut mem: Bus, video: VideoController, cpu: Cpu) -> Self {
+        // palette ram starts out with the default colours
+        let palette_base = video.mmio_base + vc::PALETTE_OFFSET;
+        for (i, byte) in vc::default_palette().iter().enumerate() {
+            mem.force_set(palette_base + i as u16, *byte);
+        }
+
         Self {
             mem: mem,
             cpu: cpu,
             video: video,
+            frames: 0,
+            symbols: Symbols::new(),
+            tracer: None,
+            profiler: None,
+            recording: None,
+            recording_since: 0,
+            replaying: VecDeque::new(),
+            replay_clock: None,
+            history: None,
+            refeed: VecDeque::new(),
+            cycles_per_frame: CLOCK_HZ / FRAME_HZ,
+            in_vblank: false,
+            frame_ready: false,
         }
     }
 
+    // the rtc and audio count virtual time in cycles, so they follow the clock too
+    pub fn set_clock_hz(&mut self, hz: u64) {
+        self.cycles_per_frame = hz / FRAME_HZ;
+        self.mem.set_clock_hz(hz);
+    }
+
     pub fn step(&mut self) {
 
         // if self.cpu.pc >= 0x800 && self.cpu.pc <= 0x0FFF {
@@ -28,22 +83,362 @@ impl Vm {
         // }
 
         if !self.cpu.halted {
+            while let Some(&(cycle, code)) = self.refeed.front() && cycle <= self.cpu.cycles {
+                self.mem.key_inject(code);
+                self.refeed.pop_front();
+            }
+            while let Some(&(cycle, code)) = self.replaying.front() && cycle <= self.cpu.cycles {
+                self.log_key(code);
+                self.mem.key_inject(code);
+                self.replaying.pop_front();
+            }
+
+            let before = self.tracer.as_ref().map(|t| t.before(&self.cpu, &self.mem));
+            let profiled = self.profiler.as_ref().map(|p| p.before(&self.cpu, &self.mem));
+            self.cpu.last_exit = None;
+
             self.cpu.step(&mut self.mem);
+
+            if let (Some(profiler), Some(before)) = (&mut self.profiler, profiled) {
+                profiler.record(&before, &self.cpu);
+            }
+
+            if let (Some(tracer), Some(before)) = (&mut self.tracer, before)
+                && let Err(e) = tracer.record(&before, &self.cpu, &mut self.mem) {
+                eprintln!("Trace stopped: {}", e);
+                self.stop_trace();
+            }
+            self.mem.tick(self.cpu.cycles);
             // self.cpu.status();
 
-            self.video.update_framebuffer(self.mem.get_range(self.video.vram_base, self.video.vram_base + self.video.framebuffer.len() as u16));
+            // vram is only read when a frame is rendered, see render_frame
+            self.update_beam();
+
+            if self.history.is_some() {
+                self.update_history();
+            }
         }
         else {
             // println!("CPU halted at {}", self.cpu.pc);
         }
     }
 
-    pub fn step_many(&mut self, n: i32) {
-        for _ in 0..n {
+    // records every instruction from here on, see trace.rs
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.mem.log_accesses(true);
+        self.tracer = Some(tracer);
+    }
+
+    // flushes the trace out and returns how many instructions it recorded
+    pub fn stop_trace(&mut self) -> Option<u64> {
+        self.mem.log_accesses(false);
+        let mut tracer = self.tracer.take()?;
+        if let Err(e) = tracer.finish() {
+            eprintln!("Trace stopped: {}", e);
+        }
+        Some(tracer.records)
+    }
+
+    // counts every instruction from here on, see profile.rs
+    pub fn start_profile(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    // writes the report and/or stacks out and returns how many cycles it covered
+    pub fn stop_profile(&mut self) -> Option<u64> {
+        let profiler = self.profiler.take()?;
+        if let Err(e) = profiler.finish(&self.symbols, self.mem.ram()) {
+            eprintln!("{}", e.message);
+        }
+        Some(profiler.total_cycles())
+    }
+
+    // keys from the front-ends and scenarios come in through here, so they get recorded.
+    // one typed while back in history makes a new future
+    pub fn inject_key(&mut self, code: u8) {
+        self.forget_future();
+        if let Some(recording) = &mut self.recording {
+            recording.keys.push((self.cpu.cycles, code));
+        }
+        self.log_key(code);
+        self.mem.key_inject(code);
+    }
+
+    fn log_key(&mut self, code: u8) {
+        if let Some(history) = &mut self.history {
+            history.keys.push((self.cpu.cycles, code));
+        }
+    }
+
+    // logs every outside input from here on, see replay.rs
+    pub fn start_recording(&mut self) {
+        self.forget_future();
+        self.recording = Some(Recording::new(self.snapshot(), self.mem.host_clock()));
+        self.recording_since = self.cpu.cycles;
+        self.mem.record_clock();
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        let mut recording = self.recording.take()?;
+        recording.clock = self.mem.clock_log().iter().filter(|(c, _)| *c >= self.recording_since).copied().collect();
+        if self.history.is_none() {
+            self.mem.stop_clock_log();
+        }
+        recording.end_cycle = self.cpu.cycles;
+        recording.ram_hash = self.ram_hash();
+        Some(recording)
+    }
+
+    // goes back to where the recording started and feeds its inputs in again as
+    // the cycles come round. run to recording.end_cycle and compare ram_hash
+    pub fn start_replay(&mut self, recording: &Recording) -> Result<(), SnapshotError> {
+        self.restore(&recording.start)?;
+        self.replay_clock = recording.host_clock.then(|| recording.clock.clone());
+        self.mem.replay_clock(self.replay_clock.clone());
+        self.replaying = recording.keys.iter().copied().collect();
+        Ok(())
+    }
+
+    // keeps checkpoints and logs input from here on, so the machine can go back, see history.rs
+    pub fn enable_history(&mut self) {
+        self.history = Some(History::new(self.cpu.cycles, self.snapshot()));
+        self.refeed.clear();
+        self.mem.record_clock();
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // called after every instruction while history is on
+    fn update_history(&mut self) {
+        let Some(history) = &self.history else {
+            return;
+        };
+        if self.cpu.cycles < history.present {
+            return; // running logged history again
+        }
+        let due = history.checkpoint_due(self.cpu.cycles);
+        let snapshot = due.then(|| self.snapshot());
+
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        history.present = self.cpu.cycles;
+        if let Some(snapshot) = snapshot {
+            history.add_checkpoint(self.cpu.cycles, snapshot);
+            let oldest = history.oldest();
+            self.mem.trim_clock_log(oldest);
+        }
+    }
+
+    // anything changed by hand while back in history (registers, memory, a key) means
+    // the logged future won't happen any more. does nothing at the present
+    pub fn forget_future(&mut self) {
+        if let Some(history) = &mut self.history && self.cpu.cycles < history.present {
+            history.forget_after(self.cpu.cycles);
+            self.refeed.clear();
+            self.mem.forget_clock_after(self.cpu.cycles);
+        }
+    }
+
+    // back to the state at an earlier cycle, one the machine has stopped at before
+    // (an instruction boundary)
+    pub fn rewind_to(&mut self, cycle: u64) -> Result<(), HistoryError> {
+        let idx = self.checkpoint_at(cycle)?;
+        self.rerun(idx, cycle, 0, |_, _| false);
+        Ok(())
+    }
+
+    // goes back to just before the last instruction that hit says yes to, looking one
+    // checkpoint at a time from the newest. hit sees the machine right after each
+    // instruction is run again, and the pc it ran from. false if nothing did, which
+    // leaves the machine at the oldest checkpoint
+    pub fn reverse_until(&mut self, hit: impl FnMut(&mut Vm, u16) -> bool) -> Result<bool, HistoryError> {
+        self.reverse_hits(1, hit)
+    }
+
+    // back count instructions. false if history ran out first
+    pub fn reverse_step(&mut self, count: u64) -> Result<bool, HistoryError> {
+        self.reverse_hits(count as usize, |_, _| true)
+    }
+
+    // reverse_until, to the count-th last hit
+    fn reverse_hits(&mut self, mut count: usize, mut hit: impl FnMut(&mut Vm, u16) -> bool) -> Result<bool, HistoryError> {
+        let now = self.cpu.cycles;
+        if count == 0 || self.history.as_ref().is_some_and(|history| now <= history.oldest()) {
+            return Ok(count == 0);
+        }
+        let mut idx = self.checkpoint_at(now - 1)?;
+        let mut end = now;
+        loop {
+            let (hits, last) = self.rerun(idx, end, count, &mut hit);
+            if hits >= count {
+                self.rewind_to(last.front().copied().unwrap())?;
+                return Ok(true);
+            }
+            count -= hits;
+            let history = self.history.as_ref().unwrap();
+            if idx == 0 {
+                let oldest = history.oldest();
+                self.rewind_to(oldest)?;
+                return Ok(false);
+            }
+            end = history.checkpoints[idx].0;
+            idx -= 1;
+        }
+    }
+
+    fn checkpoint_at(&self, cycle: u64) -> Result<usize, HistoryError> {
+        if self.recording.is_some() {
+            return Err(HistoryError { message: "can't go back while recording".to_string() });
+        }
+        let Some(history) = &self.history else {
+            return Err(HistoryError { message: "history is off".to_string() });
+        };
+        history.checkpoint_at(cycle)
+    }
+
+    // restores checkpoint idx and runs forward to cycle with the logged input. how many
+    // instructions hit said yes to, and the cycles of the last keep of them
+    fn rerun(&mut self, idx: usize, until: u64, keep: usize, mut hit: impl FnMut(&mut Vm, u16) -> bool) -> (usize, VecDeque<u64>) {
+        let history = self.history.as_ref().unwrap();
+        let (from, snapshot) = history.checkpoints[idx].clone();
+        let present = history.present;
+        self.refeed = history.keys_from(from);
+
+        self.load(&snapshot).expect("checkpoint taken by this vm");
+        if self.mem.host_clock() {
+            match &self.replay_clock {
+                Some(samples) => self.mem.rewind_clock(samples, from, None),
+                None => {
+                    let samples = self.mem.clock_log().to_vec();
+                    self.mem.rewind_clock(&samples, from, Some(present));
+                },
+            }
+        }
+
+        // running it again isn't new execution, the trace and profile already have it
+        let tracer = self.tracer.take();
+        let profiler = self.profiler.take();
+        let mut hits = 0;
+        let mut last = VecDeque::new();
+        while self.cpu.cycles < until && !self.cpu.halted {
+            let (cycle, pc) = (self.cpu.cycles, self.cpu.pc);
             self.step();
-            if self.cpu.halted {
-                break;
+            if hit(self, pc) {
+                hits += 1;
+                last.push_back(cycle);
+                if last.len() > keep {
+                    last.pop_front();
+                }
             }
         }
+        self.tracer = tracer;
+        self.profiler = profiler;
+        self.mem.take_watch_hit();
+        self.mem.drain_audio(&mut Vec::new()); // already played
+        (hits, last)
     }
-}
\ No newline at end of file
+
+    pub fn ram_hash(&self) -> u64 {
+        replay::ram_hash(self.mem.ram())
+    }
+
+    // the whole machine as a save state, see snapshot.rs
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = StateWriter::new();
+        self.cpu.save_state(&mut out);
+        self.mem.save_state(&mut out);
+        self.video.save_state(&mut out);
+        out.u64(self.frames);
+        out.bool(self.in_vblank);
+        out.bool(self.frame_ready);
+        out.bytes
+    }
+
+    // puts the machine back the way snapshot found it. it has to have been booted
+    // with the same memory map; symbols, the debugger and any trace are left alone.
+    // history starts over from here
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        self.load(snapshot)?;
+        self.replaying.clear();
+        self.replay_clock = None;
+        if self.history.is_some() {
+            self.enable_history();
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut input = StateReader::new(snapshot)?;
+        self.cpu.load_state(&mut input)?;
+        self.mem.load_state(&mut input)?;
+        self.video.load_state(&mut input)?;
+        self.frames = input.u64()?;
+        self.in_vblank = input.bool()?;
+        self.frame_ready = input.bool()?;
+        input.finish()
+    }
+
+    // enters/leaves vblank as the cycle count crosses frame boundaries
+    fn update_beam(&mut self) {
+        let vblank = self.cpu.cycles % self.cycles_per_frame >= self.cycles_per_frame - self.cycles_per_frame / VBLANK_FRACTION;
+        if vblank == self.in_vblank {
+            return;
+        }
+        self.in_vblank = vblank;
+
+        let video_regs = self.video.mmio_base + vc::VIDEO_OFFSET;
+        if vblank {
+            self.render_frame();
+            self.frames += 1;
+            self.frame_ready = true;
+            self.mem.force_set(video_regs + vc::REG_FRAME_COUNT, self.frames as u8);
+            self.mem.raise_irq(IRQ_VBLANK);
+        }
+        self.mem.force_set(video_regs + vc::REG_BEAM_STATUS, if vblank { vc::BEAM_VBLANK } else { 0 });
+    }
+
+    // runs until the next frame is out (or the cpu halts)
+    pub fn run_frame(&mut self) {
+        self.run_frame_until(|_| false);
+    }
+
+    // same, but stop gets a look after every instruction and can end the frame early.
+    // true if it did
+    pub fn run_frame_until(&mut self, mut stop: impl FnMut(&mut Self) -> bool) -> bool {
+        self.frame_ready = false;
+        while !self.frame_ready && !self.cpu.halted {
+            self.step();
+            if stop(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // pulls video registers, palette and the displayed vram page off the bus and renders a frame
+    pub fn render_frame(&mut self) -> &[u32] {
+        // in usize, a region can end right at 0x10000
+        let mmio_base = self.video.mmio_base as usize;
+        let vram_base = self.video.vram_base as usize;
+        let ram = self.mem.ram();
+
+        self.video.update_registers(&ram[mmio_base + vc::VIDEO_OFFSET as usize..][..vc::VIDEO_SIZE as usize]);
+        self.video.update_palette(&ram[mmio_base + vc::PALETTE_OFFSET as usize..][..vc::PALETTE_SIZE as usize]);
+        let page = vram_base + self.video.page_start();
+        self.video.update_framebuffer(&ram[page..page + self.video.mode.frame_size()]);
+        self.video.render(ram);
+
+        // sprite status is read-only to the guest, rewrite it for every frame
+        let status = self.video.sprite_status;
+        self.mem.force_set(self.video.mmio_base + vc::VIDEO_OFFSET + vc::REG_SPRITE_STATUS, status);
+
+        &self.video.pixels
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+
+// a minimal rsp client, over a real socket to os --gdb
+struct Client {
+    os: Child,
+    stream: TcpStream,
+}
+
+fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Vec<u8> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+}
+
+impl Client {
+    // boots the os waiting on an ephemeral port and connects to it
+    fn start() -> Self {
+        let mut os = Command::new(env!("CARGO_BIN_EXE_os"))
+            .args(["run", "--gdb", "0"])
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // "Waiting for gdb on 127.0.0.1:PORT"
+        let mut stdout = BufReader::new(os.stdout.take().unwrap());
+        let addr = loop {
+            let mut line = String::new();
+            assert!(stdout.read_line(&mut line).unwrap() > 0, "os exited before listening");
+            if let Some(addr) = line.trim().strip_prefix("Waiting for gdb on ") {
+                break addr.to_string();
+            }
+        };
+        // the rest is only read so os never writes into a closed pipe
+        std::thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+
+        let stream = TcpStream::connect(&addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(60))).unwrap();
+        Self { os, stream }
+    }
+
+    fn byte(&mut self) -> u8 {
+        let mut b = [0u8];
+        self.stream.read_exact(&mut b).unwrap();
+        b[0]
+    }
+
+    fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    // one packet with a correct checksum, then its acked reply
+    fn send(&mut self, body: &str) -> String {
+        self.send_raw(format!("${}#{:02x}", body, checksum(body.as_bytes())).as_bytes());
+        assert_eq!(self.byte(), b'+', "{} wasn't acked", body);
+        self.reply()
+    }
+
+    fn reply(&mut self) -> String {
+        assert_eq!(self.byte(), b'$');
+        let mut body = Vec::new();
+        loop {
+            match self.byte() {
+                b'#' => break,
+                b => body.push(b),
+            }
+        }
+        let sent = String::from_utf8(vec![self.byte(), self.byte()]).unwrap();
+        assert_eq!(u8::from_str_radix(&sent, 16).unwrap(), checksum(&body), "bad checksum on {:?}", String::from_utf8_lossy(&body));
+        self.send_raw(b"+");
+        String::from_utf8(body).unwrap()
+    }
+
+    fn monitor(&mut self, command: &str) -> String {
+        let reply = self.send(&format!("qRcmd,{}", hex(command.as_bytes())));
+        String::from_utf8(unhex(&reply)).unwrap()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.os.kill();
+        let _ = self.os.wait();
+    }
+}
+
+#[test]
+fn checksums_and_acks() {
+    let mut gdb = Client::start();
+
+    // a bad checksum gets a nak and no reply, the same packet again goes through
+    gdb.send_raw(b"$?#00");
+    assert_eq!(gdb.byte(), b'-');
+    assert_eq!(gdb.send("?"), "S05");
+
+    let supported = gdb.send("qSupported:swbreak+;hwbreak+");
+    assert!(supported.contains("swbreak+"), "{}", supported);
+    assert!(supported.contains("qXfer:features:read+"), "{}", supported);
+    assert!(gdb.send("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+}
+
+#[test]
+fn registers_and_memory() {
+    let mut gdb = Client::start();
+
+    // r0-r7, pc, sp, flags, mode: 8 + 2 + 2 + 1 + 1 bytes
+    let regs = gdb.send("g");
+    assert_eq!(regs.len(), 14 * 2, "{}", regs);
+    assert_eq!(gdb.send("p8"), regs[16..20]);
+    assert_eq!(gdb.send("p9"), regs[20..24]);
+    assert_eq!(gdb.send("pc"), "E01");
+
+    assert_eq!(gdb.send("P0=5a"), "OK");
+    assert_eq!(gdb.send("p0"), "5a");
+
+    assert_eq!(gdb.send("M6800,3:abcdef"), "OK");
+    assert_eq!(gdb.send("m6800,3"), "abcdef");
+    assert_eq!(gdb.send("m0,10").len(), 0x10 * 2);
+}
+
+#[test]
+fn breakpoint_and_continue() {
+    let mut gdb = Client::start();
+    // traps into the kernel would stop the continue before the breakpoint
+    assert!(!gdb.monitor("exits off").is_empty());
+
+    let sym = gdb.monitor("sym on_sys");
+    let addr = sym.trim().rsplit("0x").next().unwrap();
+    let addr = u16::from_str_radix(addr, 16).unwrap_or_else(|_| panic!("{}", sym));
+
+    assert_eq!(gdb.send(&format!("Z0,{:x},1", addr)), "OK");
+    let stop = gdb.send("c");
+    let pc = hex(&addr.to_le_bytes());
+    assert!(stop.starts_with("T05"), "{}", stop);
+    assert!(stop.contains(&format!("08:{};", pc)), "{}", stop);
+    assert!(stop.contains("swbreak:;"), "{}", stop);
+    assert_eq!(gdb.send("p8"), pc);
+    assert_eq!(gdb.send("?"), stop);
+
+    // once it's cleared a step moves on past it
+    assert_eq!(gdb.send(&format!("z0,{:x},1", addr)), "OK");
+    let stop = gdb.send("s");
+    assert!(stop.starts_with("T05"), "{}", stop);
+    assert_ne!(gdb.send("p8"), pc);
+
+    gdb.send_raw(b"$D#44");
+    assert_eq!(gdb.byte(), b'+');
+    assert_eq!(gdb.reply(), "OK");
+}
@@ -0,0 +1,42 @@
+use std::env;
+use std::fs;
+use std::process::{Command, Output};
+
+
+fn os(args: &[&str]) -> Output {
+    let out = Command::new(env!("CARGO_BIN_EXE_os"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "os {}:\n{}{}", args.join(" "),
+        String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+    out
+}
+
+// "ram hash XXXXXXXXXXXXXXXX at cycle N" out of a run's output
+fn hash_and_cycle(out: &Output) -> (String, u64) {
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let rest = stdout.split("ram hash ").nth(1).unwrap_or_else(|| panic!("no ram hash in:\n{}", stdout));
+    let mut words = rest.split_whitespace();
+    let hash = words.next().unwrap().to_string();
+    assert_eq!(words.next(), Some("at"));
+    assert_eq!(words.next(), Some("cycle"));
+    let cycle = words.next().unwrap().trim_end_matches(|c: char| !c.is_ascii_digit()).parse().unwrap();
+    (hash, cycle)
+}
+
+#[test]
+fn replay_matches_the_recording() {
+    let path = env::temp_dir().join(format!("os_replay_test_{}.rec", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let recorded = os(&["run", "--headless", "--cycles", "1500000", "--record", path]);
+    let replayed = os(&["run", "--headless", "--replay", path]);
+    let _ = fs::remove_file(path);
+
+    let stdout = String::from_utf8_lossy(&replayed.stdout);
+    assert!(stdout.contains("Replay matched"), "{}", stdout);
+    assert_eq!(hash_and_cycle(&recorded), hash_and_cycle(&replayed));
+    assert_eq!(hash_and_cycle(&recorded).1, 1500000);
+}
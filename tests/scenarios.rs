@@ -0,0 +1,29 @@
+use std::fs;
+use std::process::Command;
+
+
+// every scenario in scenarios/, through os test, one at a time so a failure names its file
+#[test]
+fn scenarios_pass() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/scenarios");
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "scenario"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scenarios in {}", dir);
+
+    let mut failed = Vec::new();
+    for path in &paths {
+        let out = Command::new(env!("CARGO_BIN_EXE_os"))
+            .arg("test")
+            .arg(path)
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .output()
+            .unwrap();
+        if !out.status.success() {
+            failed.push(format!("{}:\n{}{}", path.display(), String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr)));
+        }
+    }
+    assert!(failed.is_empty(), "{} of {} scenario(s) failed\n{}", failed.len(), paths.len(), failed.join("\n"));
+}